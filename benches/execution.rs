@@ -0,0 +1,101 @@
+//! Instruction throughput of the processor, with the decode cache and with the plain fetch and
+//! decode path of `Processor::step`. Run with `cargo bench`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use jade::octo;
+use jade::processor::{InstructionSettings, Platform, Processor};
+
+/// Number of instructions executed per iteration.
+const STEPS: u64 = 10_000;
+
+/// Register arithmetic and memory access in a tight loop.
+const ARITHMETIC: &str = "
+: main
+	loop
+		i := scratch
+		v0 += 1
+		v1 ^= v0
+		v2 := v1
+		v2 <<= v2
+		v3 += v2
+		v4 := random 0xff
+		bcd v3
+		save v2
+		load v2
+		if v0 == 0 then v5 += 1
+	again
+
+: scratch 0 0 0 0
+";
+
+/// Sprite drawing and scrolling in high resolution mode.
+const SPRITES: &str = "
+: main
+	hires
+	loop
+		i := bighex v0
+		sprite v1 v2 10
+		v0 += 1
+		v1 += 9
+		v2 += 3
+		i := square
+		sprite v2 v1 0
+		scroll-left
+	again
+
+: square
+	0xff 0xff 0xff 0xff 0xff 0xff 0xff 0xff 0xff 0xff 0xff 0xff 0xff 0xff 0xff 0xff
+	0xff 0xff 0xff 0xff 0xff 0xff 0xff 0xff 0xff 0xff 0xff 0xff 0xff 0xff 0xff 0xff
+";
+
+/// Code which rewrites the instruction it executes next, "v2 += 1" to "v2 += <counter>".
+const SELF_MODIFYING: &str = "
+: main
+	loop
+		i := patch
+		v1 += 1
+		v0 := 0x72
+		save v1
+: patch
+		v2 += 1
+	again
+";
+
+fn processor(source: &str, cache: bool) -> Processor {
+    let rom = octo::compile(source)
+        .expect("benchmark program is valid")
+        .rom;
+    let mut processor = Processor::new();
+    processor.load_settings(InstructionSettings::from_platform(Platform::XoChip));
+    processor.set_seed(0);
+    processor.set_decode_cache(cache);
+    processor.load_program(rom).unwrap();
+    processor
+}
+
+fn step(c: &mut Criterion) {
+    let mut group = c.benchmark_group("step");
+    group.throughput(Throughput::Elements(STEPS));
+    let programs = [
+        ("arithmetic", ARITHMETIC),
+        ("sprites", SPRITES),
+        ("self-modifying", SELF_MODIFYING),
+    ];
+    for (name, source) in programs {
+        for (path, cache) in [("decode cache", true), ("fetch and decode", false)] {
+            let mut processor = processor(source, cache);
+            group.bench_function(BenchmarkId::new(path, name), |b| {
+                b.iter(|| {
+                    for _ in 0..STEPS {
+                        processor.step().unwrap();
+                    }
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, step);
+criterion_main!(benches);
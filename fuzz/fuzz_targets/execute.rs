@@ -0,0 +1,78 @@
+//! Execute random programs from random processor states. Any panic is a bug: problems of the
+//! program must be reported as `ExecutionError`.
+//!
+//! The input consists of a header with the platform, the keys and the registers, followed by the
+//! code which is written at the program counter (and also loaded as ROM).
+
+#![no_main]
+
+use std::collections::HashSet;
+
+use libfuzzer_sys::fuzz_target;
+
+use jade::processor::{InstructionSettings, Key, Platform, Processor, Registers};
+
+/// Size of the header: platform (1), keys (2), V0 to VF (16), I (2), PC (2), delay and sound
+/// timer (2), stack depth (1)
+const HEADER_SIZE: usize = 26;
+
+/// Maximum number of instructions executed per input.
+const STEPS: usize = 1000;
+
+/// Execute a timer tick after this many instructions, so that programs waiting for the vertical
+/// blank continue.
+const STEPS_PER_FRAME: usize = 10;
+
+const PLATFORMS: [Platform; 5] = [
+    Platform::CosmacVip,
+    Platform::Chip48,
+    Platform::SchipLegacy,
+    Platform::SchipModern,
+    Platform::XoChip,
+];
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < HEADER_SIZE {
+        return;
+    }
+    let (header, code) = data.split_at(HEADER_SIZE);
+
+    let platform = PLATFORMS[header[0] as usize % PLATFORMS.len()];
+    let key_bits = u16::from_be_bytes([header[1], header[2]]);
+    let keys: HashSet<Key> = (0..16u8)
+        .filter(|key| key_bits & (1 << key) != 0)
+        .filter_map(|key| Key::try_from(key).ok())
+        .collect();
+    let program_counter = u16::from_be_bytes([header[21], header[22]]);
+    let registers = Registers {
+        variable_registers: header[3..19].try_into().expect("16 bytes"),
+        index_register: u16::from_be_bytes([header[19], header[20]]),
+        program_counter,
+        // Return addresses are taken from the code
+        stack: code
+            .chunks_exact(2)
+            .take(header[25] as usize % 17)
+            .map(|address| u16::from_be_bytes([address[0], address[1]]))
+            .collect(),
+        delay_timer: header[23],
+        sound_timer: header[24],
+    };
+
+    let mut processor = Processor::new();
+    processor.load_settings(InstructionSettings::from_platform(platform));
+    processor.set_seed(0);
+    if processor.load_program(code.to_vec()).is_err() {
+        return;
+    }
+    processor.load_registers(&registers);
+    processor.write_memory(program_counter, code);
+    processor.handle_keys(keys);
+
+    for step in 1..=STEPS {
+        // Errors are fine, panics are not
+        let _ = processor.step();
+        if step % STEPS_PER_FRAME == 0 {
+            processor.handle_timer_tick();
+        }
+    }
+});
@@ -1,48 +1,186 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io;
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use eframe::egui::{self, Color32, Pos2, Rect, Rounding, Sense, Vec2, ViewportCommand};
-use log::warn;
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use crate::emulator::{self, Display, Emulator, InstructionSettings, Response, Speed};
+use crate::assembler::{AsmArgs, Assembly};
+use crate::breakpoint::Breakpoint;
+use crate::disassembler::DisasmArgs;
+use crate::disassembler::Syntax;
+use crate::emulator::{
+    self, DebugState, Display, Emulator, InstructionOverrides, InstructionSettings, Platform,
+    ProgramState, Response, RewindSettings, Speed, Stats,
+};
+use crate::gdb::GdbArgs;
+use crate::headless::HeadlessArgs;
+use crate::memory_viewer::MemoryViewer;
+use crate::movie::{Movie, MovieError};
+use crate::octo::{self, OctoError};
+use crate::opcode::Opcode;
+use crate::symbols::{SymbolMap, SymbolMapError};
+use crate::trace::TraceArgs;
+use crate::trace_diff::TraceDiffArgs;
 
 /// Command line arguments for Jade, the CHIP-8 emulator
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, subcommand_negates_reqs = true)]
 pub struct Args {
     /// Path to a Jade settings file (default: jade.toml)
     #[arg(short, long)]
     settings_file: Option<PathBuf>,
 
-    /// ROM file (*.ch8)
-    #[arg(value_name = "ROM_FILE")]
-    program_file: PathBuf,
+    /// Seed for the random number generator (default: from the settings file or random)
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// ROM file (*.ch8) or Octo source file (*.8o)
+    #[arg(value_name = "ROM_FILE", required = true)]
+    program_file: Option<PathBuf>,
+
+    /// Record the keys pressed in each frame to this movie file
+    #[arg(long, value_name = "FILE", conflicts_with = "play_movie")]
+    record_movie: Option<PathBuf>,
+
+    /// Replay the keys of a movie file (recorded with --record-movie)
+    #[arg(long, value_name = "FILE", conflicts_with = "keys")]
+    play_movie: Option<PathBuf>,
+
+    /// Symbol file with labels and source lines for error messages, traces and the debugger
+    /// (default: the labels of an Octo source file)
+    #[arg(long, value_name = "FILE")]
+    symbols: Option<PathBuf>,
+
+    #[command(flatten)]
+    headless: HeadlessArgs,
+
+    #[command(flatten)]
+    trace: TraceArgs,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// Tools which run instead of the emulator
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Print an annotated disassembly of a ROM
+    Disasm(DisasmArgs),
+    /// Assemble a source file into a ROM
+    Asm(AsmArgs),
+    /// Wait for GDB to connect and debug a ROM with it
+    Gdb(GdbArgs),
+    /// Compare a headless run of a ROM step by step with a reference trace (CSV)
+    TraceDiff(TraceDiffArgs),
 }
 
 impl Args {
     pub fn settings_file_path(&self) -> Option<&Path> {
         self.settings_file.as_deref()
     }
+
+    /// The seed for the random number generator. Taken from the command line if given, from the
+    /// settings otherwise. If neither specifies a seed, a random seed is chosen.
+    pub fn seed(&self, settings: &Settings) -> u64 {
+        let seed = self.seed.or(settings.seed).unwrap_or_else(rand::random);
+        info!("random seed: {}", seed);
+        seed
+    }
+
+    /// The ROM file. Only optional if a subcommand is given.
+    pub fn program_file(&self) -> &Path {
+        self.program_file
+            .as_deref()
+            .expect("ROM file is required without subcommand")
+    }
+
+    pub fn record_movie_path(&self) -> Option<&Path> {
+        self.record_movie.as_deref()
+    }
+
+    pub fn play_movie_path(&self) -> Option<&Path> {
+        self.play_movie.as_deref()
+    }
+
+    pub fn symbols_path(&self) -> Option<&Path> {
+        self.symbols.as_deref()
+    }
+
+    pub fn command(&self) -> Option<&Command> {
+        self.command.as_ref()
+    }
+
+    /// Return true if Jade should run without a window.
+    pub fn is_headless(&self) -> bool {
+        self.headless.headless
+    }
+
+    pub fn headless_args(&self) -> &HeadlessArgs {
+        &self.headless
+    }
+
+    pub fn trace_args(&self) -> &TraceArgs {
+        &self.trace
+    }
 }
 
+/// Number of save state slots per ROM.
+const NUM_SAVE_SLOTS: u8 = 10;
+
+/// Hold this key to rewind.
+const REWIND_KEY: egui::Key = egui::Key::Backspace;
+
+/// Press this key to show or hide the debugger panel.
+const DEBUGGER_KEY: egui::Key = egui::Key::F12;
+
+/// Press this key to show or hide the memory viewer.
+const MEMORY_VIEWER_KEY: egui::Key = egui::Key::F11;
+
+/// How long to wait for the movie being recorded to be written on exit.
+const MOVIE_SAVE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Size of the emulator screen in the window.
+const SCREEN_SIZE: Vec2 = Vec2::new(640.0, 320.0);
+
+/// Width of the debugger panel.
+const DEBUGGER_WIDTH: f32 = 300.0;
+
+/// Window height with the debugger panel.
+const DEBUGGER_HEIGHT: f32 = 640.0;
+
 /// The main application.
 pub struct Application {
     emulator: Emulator,
     display: Display,
     key_map: KeyMap,
+    program_file: PathBuf,
+    save_slot: u8,
+    speed: Speed,
+    debugger_open: bool,
+    debug_state: Option<DebugState>,
+    stats: Option<Stats>,
+    breakpoints: Vec<Breakpoint>,
+    breakpoint_input: String,
+    breakpoint_message: String,
+    memory_viewer: MemoryViewer,
+    recording_movie: bool,
+    symbols: SymbolMap,
+    /// Names of the addresses for the disassembly, from the symbols
+    labels: BTreeMap<u16, String>,
 }
 
 impl Application {
     pub fn new(args: &Args, cc: &eframe::CreationContext<'_>) -> Result<Self, ApplicationError> {
         let settings = load_settings(args.settings_file_path())?;
-        let program_data: Vec<u8> = std::fs::read(&args.program_file)?;
+        let program = load_program_with_symbols(args.program_file(), args.symbols_path())?;
+        let program_data = program.rom;
 
-        let file_name = args.program_file.file_name().and_then(|s| s.to_str());
+        let file_name = args.program_file().file_name().and_then(|s| s.to_str());
         let title = if let Some(file_name) = file_name {
             "Jade".to_string() + " - " + file_name
         } else {
@@ -52,14 +190,46 @@ impl Application {
         cc.egui_ctx.send_viewport_cmd(ViewportCommand::Title(title));
 
         let emulator = Emulator::new();
-        emulator.load_settings(settings.instructions);
-        emulator.load_program(program_data);
-        emulator.run_program(Speed::new(settings.instructions_per_second));
+        emulator.load_settings(settings.instruction_settings());
+        emulator.set_seed(args.seed(&settings));
+        emulator.configure_rewind(settings.rewind);
+        if let Some(tracer) = args.trace_args().tracer()? {
+            emulator.set_tracer(tracer);
+        }
+        let movie = match args.play_movie_path() {
+            Some(path) => Some(Movie::load(path)?),
+            None => None,
+        };
+        if let Some(movie) = &movie {
+            movie.check_rom(&program_data)?;
+        }
+        emulator.load_program(program_data, Some(program.symbols.clone()));
+        if let Some(movie) = movie {
+            emulator.play_movie(movie);
+        }
+        if let Some(path) = args.record_movie_path() {
+            emulator.record_movie(path.to_path_buf());
+        }
+        let speed = Speed::new(settings.instructions_per_second);
+        emulator.run_program(speed);
 
         Ok(Application {
             emulator,
             display: Display::default(),
             key_map: KeyMap::from_type(settings.key_map),
+            program_file: args.program_file().to_path_buf(),
+            save_slot: 0,
+            speed,
+            debugger_open: false,
+            debug_state: None,
+            stats: None,
+            breakpoints: Vec::new(),
+            breakpoint_input: String::new(),
+            breakpoint_message: String::new(),
+            memory_viewer: MemoryViewer::new(),
+            recording_movie: args.record_movie_path().is_some(),
+            labels: program.symbols.labels(),
+            symbols: program.symbols,
         })
     }
 }
@@ -78,12 +248,45 @@ impl eframe::App for Application {
             self.emulator.stop();
         }
 
+        // Save states: F5 saves, F9 loads, F6/F7 select the previous/next slot.
+        self.handle_save_state_keys(ctx);
+
+        // Go back in time while the rewind key is held.
+        if ctx.input(|i| i.key_down(REWIND_KEY)) {
+            self.emulator.rewind(1);
+        }
+
         // Send the keys pressed in this frame to the emulator.
         let keys = ctx.input(|i| self.map_keys(&i.keys_down));
         self.emulator.send_keys(&keys);
 
-        // Query for the latest screen display.
+        // Show or hide the debugger, resizing the window accordingly.
+        if ctx.input(|i| i.key_pressed(DEBUGGER_KEY)) {
+            self.debugger_open = !self.debugger_open;
+            let size = if self.debugger_open {
+                Vec2::new(SCREEN_SIZE.x + DEBUGGER_WIDTH, DEBUGGER_HEIGHT)
+            } else {
+                SCREEN_SIZE
+            };
+            ctx.send_viewport_cmd(ViewportCommand::InnerSize(size));
+        }
+
+        if ctx.input(|i| i.key_pressed(MEMORY_VIEWER_KEY)) {
+            self.memory_viewer.toggle();
+        }
+
+        // Query for the latest screen display, and the debug state and memory if the debugger
+        // or the memory viewer are shown.
         self.emulator.query_display();
+        if self.debugger_open || self.memory_viewer.is_open() {
+            self.emulator.query_debug_state();
+        }
+        if self.debugger_open {
+            self.emulator.query_stats();
+        }
+        if self.memory_viewer.is_open() {
+            self.emulator.query_memory();
+        }
 
         // Get the current content of the display from the responses.
         let responses = self.emulator.responses();
@@ -93,6 +296,23 @@ impl eframe::App for Application {
         {
             self.display = d.clone();
         }
+        if let Some(Response::DebugState(state)) = responses
+            .iter()
+            .rfind(|&r| matches!(r, Response::DebugState(_)))
+        {
+            self.debug_state = Some(state.clone());
+        }
+        if let Some(Response::Stats(stats)) =
+            responses.iter().rfind(|&r| matches!(r, Response::Stats(_)))
+        {
+            self.stats = Some(*stats);
+        }
+        if let Some(Response::Memory(memory)) = responses
+            .iter()
+            .rfind(|&r| matches!(r, Response::Memory(_)))
+        {
+            self.memory_viewer.update_memory(memory.clone());
+        }
 
         // Log error messages, if there are any.
         for e in responses.iter().filter_map(|response| match response {
@@ -103,8 +323,32 @@ impl eframe::App for Application {
         }) {
             warn!("emulator error: {}", e);
         }
+        for response in &responses {
+            match response {
+                Response::SaveState(Err(e)) => warn!("cannot save state: {}", e),
+                Response::RecordMovie(Err(e)) => warn!("cannot save movie: {}", e),
+                Response::LoadState(Err(e)) => warn!("cannot load state: {}", e),
+                Response::BreakpointHit(breakpoint, registers) => {
+                    info!(
+                        "breakpoint hit at {:#05x}: {}",
+                        registers.program_counter, breakpoint
+                    );
+                    self.breakpoint_message =
+                        format!("Hit at {:04X}: {}", registers.program_counter, breakpoint);
+                }
+                _ => {}
+            }
+        }
 
         // Show the GUI
+        if self.debugger_open {
+            egui::SidePanel::right("debugger")
+                .exact_width(DEBUGGER_WIDTH)
+                .resizable(false)
+                .show(ctx, |ui| {
+                    self.show_debugger(ui);
+                });
+        }
         egui::CentralPanel::default()
             .frame(egui::Frame::none())
             .show(ctx, |ui| {
@@ -112,39 +356,235 @@ impl eframe::App for Application {
                     self.show_emulator_screen(ui);
                 });
             });
+        self.memory_viewer
+            .show(ctx, &self.emulator, self.debug_state.as_ref());
+    }
+
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        if !self.recording_movie {
+            return;
+        }
+        // Wait for the movie to be written before the emulator thread is torn down
+        self.emulator.stop_recording();
+        while let Some(response) = self.emulator.wait_response(MOVIE_SAVE_TIMEOUT) {
+            match response {
+                Response::RecordMovie(Ok(())) => info!("movie saved"),
+                Response::RecordMovie(Err(e)) => warn!("cannot save movie: {}", e),
+                _ => continue,
+            }
+            break;
+        }
     }
 }
 
 impl Application {
-    /// Draw the 64x32 CHIP-8 display with blocks of 10x10 pixels.
+    /// Draw the CHIP-8 display with blocks of 10x10 pixels in low resolution mode (64x32) and
+    /// blocks of 5x5 pixels in high resolution mode (128x64). The colour of a pixel depends on
+    /// which of the XO-CHIP drawing planes are set.
     fn show_emulator_screen(&self, ui: &mut egui::Ui) {
-        const BLOCK_SIZE: f32 = 10.0;
-
+        const PALETTE: [Color32; 4] = [
+            Color32::BLACK,                      // no plane (background)
+            Color32::from_gray(128),             // plane 1
+            Color32::from_rgb(0x3c, 0x8d, 0x6e), // plane 2
+            Color32::from_rgb(0xc8, 0xe6, 0xd8), // both planes
+        ];
+
+        let block_size = SCREEN_SIZE.x / self.display.width as f32;
         let screen_dim = Vec2::new(
-            emulator::DISPLAY_WIDTH as f32 * BLOCK_SIZE,
-            emulator::DISPLAY_HEIGHT as f32 * BLOCK_SIZE,
+            self.display.width as f32 * block_size,
+            self.display.height as f32 * block_size,
         );
 
         let (response, painter) = ui.allocate_painter(screen_dim, Sense::hover());
-        let color = Color32::from_gray(128);
 
-        for y in 0..emulator::DISPLAY_HEIGHT {
-            for x in 0..emulator::DISPLAY_WIDTH {
-                if !self.display.get(x, y) {
+        for y in 0..self.display.height {
+            for x in 0..self.display.width {
+                let planes = self.display.get(x, y);
+                if planes == 0 {
                     continue;
                 }
+                let color = PALETTE[planes as usize & 0b11];
                 let rect = Rect::from_min_size(
                     Pos2::new(
-                        response.rect.left() + x as f32 * BLOCK_SIZE,
-                        response.rect.top() + y as f32 * BLOCK_SIZE,
+                        response.rect.left() + x as f32 * block_size,
+                        response.rect.top() + y as f32 * block_size,
                     ),
-                    Vec2::splat(BLOCK_SIZE),
+                    Vec2::splat(block_size),
                 );
                 painter.rect_filled(rect, Rounding::ZERO, color);
             }
         }
     }
 
+    /// Draw the debugger: execution controls, registers, stack, timers and the code around the
+    /// program counter.
+    fn show_debugger(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            if ui.button("Run").clicked() {
+                self.emulator.run_program(self.speed);
+            }
+            if ui.button("Pause").clicked() {
+                self.emulator.stop();
+            }
+            if ui.button("Step").clicked() {
+                self.emulator.step();
+            }
+            if ui.button("Step over").clicked() {
+                self.emulator.step_over();
+            }
+            if ui.button("Step out").clicked() {
+                self.emulator.step_out();
+            }
+        });
+        if ui.button("Memory").clicked() {
+            self.memory_viewer.toggle();
+        }
+
+        self.show_breakpoints(ui);
+
+        let Some(debug_state) = &self.debug_state else {
+            return;
+        };
+        let registers = &debug_state.registers;
+        ui.label(match debug_state.state {
+            ProgramState::Running => "Running",
+            ProgramState::Stopped => "Paused",
+        });
+        if let Some(location) = self.symbols.describe(registers.program_counter) {
+            ui.monospace(location);
+        }
+        if let Some(stats) = &self.stats {
+            ui.monospace(stats.to_string());
+        }
+        ui.separator();
+
+        egui::Grid::new("registers").striped(true).show(ui, |ui| {
+            for (index, value) in registers.variable_registers.iter().enumerate() {
+                ui.monospace(format!("V{:X}", index));
+                ui.monospace(format!("{:02X}", value));
+                if index % 4 == 3 {
+                    ui.end_row();
+                }
+            }
+            ui.monospace("I");
+            ui.monospace(format!("{:04X}", registers.index_register));
+            ui.monospace("PC");
+            ui.monospace(format!("{:04X}", registers.program_counter));
+            ui.monospace("SP");
+            ui.monospace(format!("{:02X}", registers.stack.len()));
+            ui.end_row();
+            ui.monospace("DT");
+            ui.monospace(format!("{:02X}", registers.delay_timer));
+            ui.monospace("ST");
+            ui.monospace(format!("{:02X}", registers.sound_timer));
+            ui.end_row();
+        });
+        let stack: Vec<_> = registers
+            .stack
+            .iter()
+            .rev()
+            .map(|address| format!("{:04X}", address))
+            .collect();
+        ui.monospace(format!("Stack: {}", stack.join(" ")));
+        ui.separator();
+
+        // Disassemble the code window. The window may start in the middle of an instruction,
+        // which is fine for a quick overview.
+        let labels = &self.labels;
+        let mut offset = 0;
+        while offset + 1 < debug_state.code.len() {
+            let address = debug_state.code_address.wrapping_add(offset as u16);
+            let bytes = &debug_state.code[offset..];
+            let (text, size) = match Opcode::decode_bytes(bytes) {
+                Ok(instruction) => (
+                    instruction.format(Syntax::Octo, labels),
+                    instruction.size() as usize,
+                ),
+                Err(_) => (format!("{:#04x} {:#04x}", bytes[0], bytes[1]), 2),
+            };
+            let line = format!("{:04X}  {}", address, text);
+            if address == registers.program_counter {
+                ui.label(egui::RichText::new(line).monospace().strong().underline());
+            } else {
+                ui.monospace(line);
+            }
+            offset += size;
+        }
+    }
+
+    /// Draw the list of breakpoints with a text field for adding breakpoints.
+    fn show_breakpoints(&mut self, ui: &mut egui::Ui) {
+        ui.separator();
+        ui.horizontal(|ui| {
+            let input = ui.add(
+                egui::TextEdit::singleline(&mut self.breakpoint_input)
+                    .hint_text("0x2a4 if V3 == 0x10")
+                    .desired_width(DEBUGGER_WIDTH - 60.0),
+            );
+            let submitted = input.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+            if ui.button("Add").clicked() || submitted {
+                match Breakpoint::parse(&self.breakpoint_input) {
+                    Ok(breakpoint) => {
+                        self.emulator.set_breakpoint(breakpoint.clone());
+                        self.breakpoints.push(breakpoint);
+                        self.breakpoint_input.clear();
+                        self.breakpoint_message.clear();
+                    }
+                    Err(e) => self.breakpoint_message = e.to_string(),
+                }
+            }
+        });
+
+        let mut removed = None;
+        for (index, breakpoint) in self.breakpoints.iter().enumerate() {
+            ui.horizontal(|ui| {
+                if ui.small_button("x").clicked() {
+                    removed = Some(index);
+                }
+                ui.monospace(breakpoint.to_string());
+            });
+        }
+        if let Some(index) = removed {
+            let breakpoint = self.breakpoints.remove(index);
+            self.emulator.clear_breakpoint(breakpoint);
+        }
+        if !self.breakpoint_message.is_empty() {
+            ui.label(&self.breakpoint_message);
+        }
+        ui.separator();
+    }
+
+    /// Handle the hotkeys for saving and loading states.
+    fn handle_save_state_keys(&mut self, ctx: &egui::Context) {
+        let (save, load, previous, next) = ctx.input(|i| {
+            (
+                i.key_pressed(egui::Key::F5),
+                i.key_pressed(egui::Key::F9),
+                i.key_pressed(egui::Key::F6),
+                i.key_pressed(egui::Key::F7),
+            )
+        });
+
+        if previous {
+            self.save_slot = (self.save_slot + NUM_SAVE_SLOTS - 1) % NUM_SAVE_SLOTS;
+            info!("selected save slot {}", self.save_slot);
+        }
+        if next {
+            self.save_slot = (self.save_slot + 1) % NUM_SAVE_SLOTS;
+            info!("selected save slot {}", self.save_slot);
+        }
+        if save {
+            let path = save_state_path(&self.program_file, self.save_slot);
+            info!("saving state to {}", path.display());
+            self.emulator.save_state(path);
+        }
+        if load {
+            let path = save_state_path(&self.program_file, self.save_slot);
+            info!("loading state from {}", path.display());
+            self.emulator.load_state(path);
+        }
+    }
+
     /// Apply the keymap.
     fn map_keys(&self, keys: &HashSet<egui::Key>) -> HashSet<emulator::Key> {
         keys.iter()
@@ -153,13 +593,25 @@ impl Application {
     }
 }
 
+/// Path of the save state file for the given slot. Save states are stored next to the ROM file,
+/// e.g. `pong.state3` for slot 3 of `pong.ch8`.
+fn save_state_path(program_file: &Path, slot: u8) -> PathBuf {
+    program_file.with_extension(format!("state{}", slot))
+}
+
 #[derive(Error, Debug)]
 pub enum ApplicationError {
     #[error(transparent)]
     SettingsFileError(#[from] SettingsFileError),
 
-    #[error("Cannot read program data: {0}")]
-    ReadProgramData(#[from] io::Error),
+    #[error(transparent)]
+    ProgramFile(#[from] ProgramFileError),
+
+    #[error("cannot create trace file: {0}")]
+    Trace(#[from] io::Error),
+
+    #[error(transparent)]
+    Movie(#[from] MovieError),
 }
 
 #[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
@@ -246,7 +698,67 @@ impl KeyMap {
 pub struct Settings {
     key_map: KeyMapType,
     instructions_per_second: usize,
-    instructions: InstructionSettings,
+    platform: Option<Platform>,
+    seed: Option<u64>,
+    #[serde(default)]
+    instructions: InstructionOverrides,
+    #[serde(default)]
+    rewind: RewindSettings,
+}
+
+impl Settings {
+    pub fn instructions_per_second(&self) -> usize {
+        self.instructions_per_second
+    }
+
+    /// The quirks of the configured platform, with the individually configured quirks applied on
+    /// top. Without a platform, the quirks start from the default instruction settings.
+    pub fn instruction_settings(&self) -> InstructionSettings {
+        let settings = match self.platform {
+            Some(platform) => InstructionSettings::from_platform(platform),
+            None => InstructionSettings::default(),
+        };
+        settings.with_overrides(self.instructions)
+    }
+}
+
+/// Load the program file. Octo source files (*.8o) are compiled, all other files are read as ROM.
+pub fn load_program(path: &Path) -> Result<Assembly, ProgramFileError> {
+    let is_octo_source = path
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("8o"));
+    if is_octo_source {
+        return Ok(octo::compile_file(path)?);
+    }
+    Ok(Assembly {
+        rom: std::fs::read(path)?,
+        symbols: SymbolMap::new(),
+    })
+}
+
+/// Load the program file together with its symbols: those of `symbols_file` if given, otherwise
+/// the labels and source lines of a compiled Octo source.
+pub fn load_program_with_symbols(
+    path: &Path,
+    symbols_file: Option<&Path>,
+) -> Result<Assembly, ProgramFileError> {
+    let mut assembly = load_program(path)?;
+    if let Some(symbols_file) = symbols_file {
+        assembly.symbols = SymbolMap::load(symbols_file)?;
+    }
+    Ok(assembly)
+}
+
+#[derive(Error, Debug)]
+pub enum ProgramFileError {
+    #[error("cannot read program data: {0}")]
+    Read(#[from] io::Error),
+
+    #[error("cannot compile program: {0}")]
+    Compile(#[from] OctoError),
+
+    #[error(transparent)]
+    Symbols(#[from] SymbolMapError),
 }
 
 pub fn load_settings(settings_file: Option<&Path>) -> Result<Settings, SettingsFileError> {
@@ -312,4 +824,35 @@ mod tests {
         assert_eq!(qwerty.apply(&egui::Key::B), None);
         assert_eq!(literal.apply(&egui::Key::G), None);
     }
+
+    #[test]
+    fn default_settings_file() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("jade.toml");
+        let settings = load_settings(Some(&path)).unwrap();
+        assert_eq!(settings.platform, Some(Platform::SchipModern));
+        assert_eq!(settings.rewind, RewindSettings::default());
+    }
+
+    #[test]
+    fn platform_with_overrides() {
+        let settings: Settings = toml::from_str(
+            r#"
+            key_map = "CommonQWERTY"
+            instructions_per_second = 700
+            platform = "cosmac-vip"
+
+            [instructions]
+            use_bxnn_instead_bnnn = true
+            "#,
+        )
+        .unwrap();
+        let expected = InstructionSettings::from_platform(Platform::CosmacVip).with_overrides(
+            InstructionOverrides {
+                use_bxnn_instead_bnnn: Some(true),
+                ..Default::default()
+            },
+        );
+        assert_eq!(settings.platform, Some(Platform::CosmacVip));
+        assert_eq!(settings.instruction_settings(), expected);
+    }
 }
@@ -0,0 +1,582 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+use crate::opcode::Opcode;
+use crate::processor::ROM_START_ADDR;
+use crate::symbols::SymbolMap;
+
+/// Maximum nesting depth of includes, guards against include cycles.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Maximum nesting depth of constants defined by other constants.
+const MAX_CONSTANT_DEPTH: usize = 32;
+
+/// Command line arguments for the assembler
+#[derive(clap::Args, Debug)]
+pub struct AsmArgs {
+    /// Assembler source file
+    #[arg(value_name = "SOURCE_FILE")]
+    pub source_file: PathBuf,
+
+    /// ROM file to write (default: the source file with extension .ch8)
+    #[arg(short, long, value_name = "FILE")]
+    pub output: Option<PathBuf>,
+
+    /// Write the symbol map (labels and their addresses) to this file
+    #[arg(long, value_name = "FILE")]
+    pub symbols: Option<PathBuf>,
+}
+
+/// Assemble the source file given on the command line.
+pub fn run(args: &AsmArgs) -> Result<(), AssemblerError> {
+    let assembly = assemble_file(&args.source_file)?;
+    let output = args
+        .output
+        .clone()
+        .unwrap_or_else(|| args.source_file.with_extension("ch8"));
+    std::fs::write(&output, &assembly.rom).map_err(AssemblerError::Write)?;
+    if let Some(path) = &args.symbols {
+        std::fs::write(path, assembly.symbols.to_string()).map_err(AssemblerError::Write)?;
+    }
+    Ok(())
+}
+
+/// An assembled program.
+#[derive(Clone, Debug, Default)]
+pub struct Assembly {
+    /// The binary, to be loaded at [`ROM_START_ADDR`]
+    pub rom: Vec<u8>,
+    /// The labels and their addresses
+    pub symbols: SymbolMap,
+}
+
+/// Assemble a source file. Includes are resolved relative to the including file.
+pub fn assemble_file(path: &Path) -> Result<Assembly, AssemblerError> {
+    let mut lines = Vec::new();
+    read_source(path, &mut lines, 0)?;
+    Assembler::default().assemble(&lines)
+}
+
+/// Assemble source text. Includes are resolved relative to the working directory.
+///
+/// The source consists of one statement per line, `;` starts a comment. A statement is either
+///  * an instruction in classic syntax, e.g. `LD V1, 0x12` or `DRW V0, V1, 5`,
+///  * a data directive, `DB 1, 2, 3` for bytes or `DW 0x1234` for (big endian) words,
+///  * a constant definition, `NAME EQU 42`,
+///  * or an include, `INCLUDE "sprites.asm"`.
+///
+/// Statements can be preceded by a label, `name:`. Numbers are decimal, hexadecimal (`0x` or `$`)
+/// or binary (`0b` or `%`). Wherever a number is expected, labels, constants and sums or
+/// differences of them can be used as well.
+pub fn assemble(source: &str) -> Result<Assembly, AssemblerError> {
+    let mut lines = Vec::new();
+    split_source(source, "<input>", Path::new("."), &mut lines, 0)?;
+    Assembler::default().assemble(&lines)
+}
+
+/// A line of source, after resolving includes.
+#[derive(Clone, Debug)]
+struct SourceLine {
+    file: String,
+    number: usize,
+    text: String,
+}
+
+impl SourceLine {
+    fn error(&self, message: impl Into<String>) -> AssemblerError {
+        AssemblerError::Source {
+            file: self.file.clone(),
+            line: self.number,
+            message: message.into(),
+        }
+    }
+}
+
+fn read_source(
+    path: &Path,
+    lines: &mut Vec<SourceLine>,
+    depth: usize,
+) -> Result<(), AssemblerError> {
+    let source = std::fs::read_to_string(path).map_err(|e| AssemblerError::Read {
+        path: path.to_path_buf(),
+        source: e,
+    })?;
+    let directory = path.parent().unwrap_or(Path::new("."));
+    split_source(
+        &source,
+        &path.display().to_string(),
+        directory,
+        lines,
+        depth,
+    )
+}
+
+fn split_source(
+    source: &str,
+    file: &str,
+    directory: &Path,
+    lines: &mut Vec<SourceLine>,
+    depth: usize,
+) -> Result<(), AssemblerError> {
+    for (index, text) in source.lines().enumerate() {
+        let line = SourceLine {
+            file: file.to_string(),
+            number: index + 1,
+            text: text.to_string(),
+        };
+        let code = strip_comment(text).trim();
+        let include = code
+            .split_once(char::is_whitespace)
+            .filter(|(directive, _)| directive.eq_ignore_ascii_case("include"));
+        let Some((_, argument)) = include else {
+            lines.push(line);
+            continue;
+        };
+
+        if depth >= MAX_INCLUDE_DEPTH {
+            return Err(line.error("includes are nested too deeply"));
+        }
+        let name = argument
+            .trim()
+            .strip_prefix('"')
+            .and_then(|a| a.strip_suffix('"'))
+            .ok_or_else(|| line.error("expected a quoted file name after INCLUDE"))?;
+        read_source(&directory.join(name), lines, depth + 1).map_err(|e| match e {
+            AssemblerError::Read { source, .. } => {
+                line.error(format!("cannot include '{}': {}", name, source))
+            }
+            e => e,
+        })?;
+    }
+    Ok(())
+}
+
+fn strip_comment(text: &str) -> &str {
+    text.split(';').next().unwrap_or_default()
+}
+
+/// An operand of an instruction. Expressions are kept as text and evaluated in the second pass,
+/// when all labels are known.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Operand {
+    Register(u8),
+    Index,
+    IndexIndirect,
+    DelayTimer,
+    SoundTimer,
+    Key,
+    FontSprite,
+    BigFontSprite,
+    BcdDigits,
+    Flags,
+    Long(String),
+    Value(String),
+}
+
+impl Operand {
+    fn parse(text: &str) -> Operand {
+        let text = text.trim();
+        let upper = text.to_ascii_uppercase();
+        match upper.as_str() {
+            "I" => return Operand::Index,
+            "[I]" => return Operand::IndexIndirect,
+            "DT" => return Operand::DelayTimer,
+            "ST" => return Operand::SoundTimer,
+            "K" => return Operand::Key,
+            "F" => return Operand::FontSprite,
+            "HF" => return Operand::BigFontSprite,
+            "B" => return Operand::BcdDigits,
+            "R" => return Operand::Flags,
+            _ => {}
+        }
+        if let Some(register) = upper
+            .strip_prefix('V')
+            .filter(|r| r.len() == 1)
+            .and_then(|r| u8::from_str_radix(r, 16).ok())
+        {
+            return Operand::Register(register);
+        }
+        if upper.starts_with("LONG ") {
+            return Operand::Long(text[5..].trim().to_string());
+        }
+        Operand::Value(text.to_string())
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Statement {
+    Instruction {
+        mnemonic: String,
+        operands: Vec<Operand>,
+    },
+    Bytes(Vec<String>),
+    Words(Vec<String>),
+}
+
+impl Statement {
+    fn size(&self) -> u16 {
+        match self {
+            Statement::Instruction { operands, .. } => {
+                if operands.iter().any(|o| matches!(o, Operand::Long(_))) {
+                    4
+                } else {
+                    2
+                }
+            }
+            Statement::Bytes(values) => values.len() as u16,
+            Statement::Words(values) => 2 * values.len() as u16,
+        }
+    }
+}
+
+/// A two pass assembler. The first pass determines the addresses of the labels, the second pass
+/// encodes the statements.
+#[derive(Default)]
+struct Assembler<'a> {
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, (String, &'a SourceLine)>,
+}
+
+impl<'a> Assembler<'a> {
+    fn assemble(mut self, lines: &'a [SourceLine]) -> Result<Assembly, AssemblerError> {
+        let mut statements = Vec::new();
+        let mut address = ROM_START_ADDR;
+        for line in lines {
+            let mut code = strip_comment(&line.text).trim();
+            if let Some((label, rest)) = code.split_once(':') {
+                let label = label.trim();
+                if !is_identifier(label) {
+                    return Err(line.error(format!("invalid label '{}'", label)));
+                }
+                self.define(label, line)?;
+                self.labels.insert(label.to_string(), address);
+                code = rest.trim();
+            }
+            if code.is_empty() {
+                continue;
+            }
+
+            let (mnemonic, arguments) = code
+                .split_once(char::is_whitespace)
+                .map_or((code, ""), |(m, a)| (m, a.trim()));
+            if let Some((_, value)) = arguments
+                .split_once(char::is_whitespace)
+                .filter(|(directive, _)| directive.eq_ignore_ascii_case("equ"))
+            {
+                if !is_identifier(mnemonic) {
+                    return Err(line.error(format!("invalid constant name '{}'", mnemonic)));
+                }
+                self.define(mnemonic, line)?;
+                self.constants
+                    .insert(mnemonic.to_string(), (value.trim().to_string(), line));
+                continue;
+            }
+
+            let arguments: Vec<&str> = match arguments {
+                "" => Vec::new(),
+                arguments => arguments.split(',').map(str::trim).collect(),
+            };
+            let statement = match mnemonic.to_ascii_uppercase().as_str() {
+                "DB" => Statement::Bytes(arguments.iter().map(|a| a.to_string()).collect()),
+                "DW" => Statement::Words(arguments.iter().map(|a| a.to_string()).collect()),
+                mnemonic => Statement::Instruction {
+                    mnemonic: mnemonic.to_string(),
+                    operands: arguments.iter().map(|a| Operand::parse(a)).collect(),
+                },
+            };
+            address = address
+                .checked_add(statement.size())
+                .ok_or_else(|| line.error("program does not fit into memory"))?;
+            statements.push((line, statement));
+        }
+
+        let mut rom = Vec::new();
+        let mut symbols = SymbolMap::new();
+        for (line, statement) in &statements {
+            match statement {
+                Statement::Instruction { mnemonic, operands } => {
+                    let instruction = self
+                        .instruction(mnemonic, operands)
+                        .map_err(|message| line.error(message))?;
+                    let address = ROM_START_ADDR + rom.len() as u16;
+                    symbols.insert_source_line(address, line.number, strip_comment(&line.text));
+                    rom.extend(instruction.encode());
+                }
+                Statement::Bytes(values) => {
+                    for value in values {
+                        let value = self
+                            .evaluate(value, -0x80, 0xff)
+                            .map_err(|message| line.error(message))?;
+                        rom.push(value as u8);
+                    }
+                }
+                Statement::Words(values) => {
+                    for value in values {
+                        let value = self
+                            .evaluate(value, -0x8000, 0xffff)
+                            .map_err(|message| line.error(message))?;
+                        rom.extend((value as u16).to_be_bytes());
+                    }
+                }
+            }
+        }
+
+        for (name, address) in self.labels {
+            symbols.insert(name, address);
+        }
+        Ok(Assembly { rom, symbols })
+    }
+
+    fn define(&self, name: &str, line: &SourceLine) -> Result<(), AssemblerError> {
+        if self.labels.contains_key(name) || self.constants.contains_key(name) {
+            return Err(line.error(format!("'{}' is already defined", name)));
+        }
+        Ok(())
+    }
+
+    fn instruction(&self, mnemonic: &str, operands: &[Operand]) -> Result<Opcode, String> {
+        use Opcode::*;
+        use Operand::*;
+
+        let address = |expression: &str| self.evaluate(expression, 0, 0xfff).map(|v| v as u16);
+        let long = |expression: &str| self.evaluate(expression, 0, 0xffff).map(|v| v as u16);
+        let byte = |expression: &str| self.evaluate(expression, -0x80, 0xff).map(|v| v as u8);
+        let nibble = |expression: &str| self.evaluate(expression, 0, 0xf).map(|v| v as u8);
+
+        let instruction = match (mnemonic, operands) {
+            ("CLS", []) => ClearScreen,
+            ("RET", []) => Return,
+            ("SCD", [Value(n)]) => ScrollDown(nibble(n)?),
+            ("SCU", [Value(n)]) => ScrollUp(nibble(n)?),
+            ("SCR", []) => ScrollRight,
+            ("SCL", []) => ScrollLeft,
+            ("EXIT", []) => Exit,
+            ("LOW", []) => LowResolution,
+            ("HIGH", []) => HighResolution,
+            ("JP", [Value(a)]) => Jump(address(a)?),
+            ("JP", [Register(0), Value(a)]) => JumpWithOffset(address(a)?),
+            ("CALL", [Value(a)]) => Call(address(a)?),
+            ("SE", [Register(x), Value(v)]) => SkipIfEqual {
+                x: *x,
+                value: byte(v)?,
+            },
+            ("SE", [Register(x), Register(y)]) => SkipIfRegistersEqual { x: *x, y: *y },
+            ("SNE", [Register(x), Value(v)]) => SkipIfNotEqual {
+                x: *x,
+                value: byte(v)?,
+            },
+            ("SNE", [Register(x), Register(y)]) => SkipIfRegistersNotEqual { x: *x, y: *y },
+            ("SAVE", [Register(x), Register(y)]) => SaveRange { x: *x, y: *y },
+            ("LOAD", [Register(x), Register(y)]) => LoadRange { x: *x, y: *y },
+            ("LD", [Register(x), Value(v)]) => SetRegister {
+                x: *x,
+                value: byte(v)?,
+            },
+            ("LD", [Register(x), Register(y)]) => Assign { x: *x, y: *y },
+            ("LD", [Index, Value(a)]) => SetIndex(address(a)?),
+            ("LD", [Index, Long(a)]) => SetIndexLong(long(a)?),
+            ("LD", [Register(x), DelayTimer]) => GetDelay(*x),
+            ("LD", [Register(x), Key]) => WaitKey(*x),
+            ("LD", [DelayTimer, Register(x)]) => SetDelay(*x),
+            ("LD", [SoundTimer, Register(x)]) => SetSound(*x),
+            ("LD", [FontSprite, Register(x)]) => Font(*x),
+            ("LD", [BigFontSprite, Register(x)]) => BigFont(*x),
+            ("LD", [BcdDigits, Register(x)]) => Bcd(*x),
+            ("LD", [IndexIndirect, Register(x)]) => Store(*x),
+            ("LD", [Register(x), IndexIndirect]) => Load(*x),
+            ("LD", [Flags, Register(x)]) => StoreFlags(*x),
+            ("LD", [Register(x), Flags]) => LoadFlags(*x),
+            ("ADD", [Register(x), Value(v)]) => AddToRegister {
+                x: *x,
+                value: byte(v)?,
+            },
+            ("ADD", [Register(x), Register(y)]) => Add { x: *x, y: *y },
+            ("ADD", [Index, Register(x)]) => AddToIndex(*x),
+            ("OR", [Register(x), Register(y)]) => Or { x: *x, y: *y },
+            ("AND", [Register(x), Register(y)]) => And { x: *x, y: *y },
+            ("XOR", [Register(x), Register(y)]) => Xor { x: *x, y: *y },
+            ("SUB", [Register(x), Register(y)]) => Subtract { x: *x, y: *y },
+            ("SUBN", [Register(x), Register(y)]) => SubtractReverse { x: *x, y: *y },
+            ("SHR", [Register(x)]) => ShiftRight { x: *x, y: *x },
+            ("SHR", [Register(x), Register(y)]) => ShiftRight { x: *x, y: *y },
+            ("SHL", [Register(x)]) => ShiftLeft { x: *x, y: *x },
+            ("SHL", [Register(x), Register(y)]) => ShiftLeft { x: *x, y: *y },
+            ("RND", [Register(x), Value(v)]) => Random {
+                x: *x,
+                mask: byte(v)?,
+            },
+            ("DRW", [Register(x), Register(y), Value(n)]) => Draw {
+                x: *x,
+                y: *y,
+                rows: nibble(n)?,
+            },
+            ("SKP", [Register(x)]) => SkipIfKey(*x),
+            ("SKNP", [Register(x)]) => SkipIfNotKey(*x),
+            ("AUDIO", []) => LoadAudio,
+            ("PLANE", [Value(n)]) => SelectPlanes(nibble(n)?),
+            ("PITCH", [Register(x)]) => SetPitch(*x),
+            _ => {
+                return Err(format!(
+                    "invalid instruction or operands for '{}'",
+                    mnemonic
+                ))
+            }
+        };
+        Ok(instruction)
+    }
+
+    /// Evaluate an expression and check that its value is within `min..=max`.
+    fn evaluate(&self, expression: &str, min: i64, max: i64) -> Result<i64, String> {
+        let value = self.evaluate_expression(expression, 0)?;
+        if !(min..=max).contains(&value) {
+            return Err(format!(
+                "value {} of '{}' is out of range ({} to {})",
+                value, expression, min, max
+            ));
+        }
+        Ok(value)
+    }
+
+    fn evaluate_expression(&self, expression: &str, depth: usize) -> Result<i64, String> {
+        let expression = expression.replace('-', "+-");
+        let mut total: i64 = 0;
+        for (index, term) in expression.split('+').map(str::trim).enumerate() {
+            if term.is_empty() && index == 0 {
+                continue;
+            }
+            let (negative, term) = match term.strip_prefix('-') {
+                Some(term) => (true, term.trim()),
+                None => (false, term),
+            };
+            let value = self.evaluate_term(term, depth)?;
+            total = if negative {
+                total.checked_sub(value)
+            } else {
+                total.checked_add(value)
+            }
+            .ok_or_else(|| "arithmetic overflow".to_string())?;
+        }
+        Ok(total)
+    }
+
+    fn evaluate_term(&self, term: &str, depth: usize) -> Result<i64, String> {
+        let number = |digits: &str, radix| {
+            i64::from_str_radix(&digits.replace('_', ""), radix)
+                .map_err(|e| format!("invalid number '{}': {}", term, e))
+        };
+        if let Some(digits) = term
+            .strip_prefix("0x")
+            .or_else(|| term.strip_prefix("0X"))
+            .or_else(|| term.strip_prefix('$'))
+        {
+            return number(digits, 16);
+        }
+        if let Some(digits) = term
+            .strip_prefix("0b")
+            .or_else(|| term.strip_prefix("0B"))
+            .or_else(|| term.strip_prefix('%'))
+        {
+            return number(digits, 2);
+        }
+        if term.starts_with(|c: char| c.is_ascii_digit()) {
+            return number(term, 10);
+        }
+        if term.is_empty() {
+            return Err("missing value".to_string());
+        }
+
+        if let Some(&address) = self.labels.get(term) {
+            return Ok(address as i64);
+        }
+        if let Some((expression, line)) = self.constants.get(term) {
+            if depth >= MAX_CONSTANT_DEPTH {
+                return Err(format!("constant '{}' is defined recursively", term));
+            }
+            return self
+                .evaluate_expression(expression, depth + 1)
+                .map_err(|message| {
+                    format!("in constant '{}' (line {}): {}", term, line.number, message)
+                });
+        }
+        Err(format!("unknown symbol '{}'", term))
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[derive(Error, Debug)]
+pub enum AssemblerError {
+    #[error("cannot read {path}: {source}")]
+    Read { path: PathBuf, source: io::Error },
+
+    #[error("{file}:{line}: {message}")]
+    Source {
+        file: String,
+        line: usize,
+        message: String,
+    },
+
+    #[error("cannot write output: {0}")]
+    Write(io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disassembler::{disassemble, Syntax};
+
+    #[test]
+    fn assemble_program() {
+        let source = "
+            SPEED equ 2           ; a constant
+            start:
+                LD I, sprite
+                LD V0, SPEED + 1
+                drw v0, v1, sprite_end - sprite
+                CALL sub
+            loop: JP loop
+            sub: RET
+            sprite:
+                DB %11110000, $90
+            sprite_end:
+                DW 0x1234
+        ";
+        let assembly = assemble(source).unwrap();
+        assert_eq!(
+            assembly.rom,
+            [
+                0xa2, 0x0c, 0x60, 0x03, 0xd0, 0x12, 0x22, 0x0a, 0x12, 0x08, 0x00, 0xee, 0xf0, 0x90,
+                0x12, 0x34
+            ]
+        );
+        assert_eq!(assembly.symbols.get("loop"), Some(0x208));
+        assert_eq!(assembly.symbols.get("sprite_end"), Some(0x20e));
+        assert_eq!(assembly.symbols.get("SPEED"), None);
+    }
+
+    #[test]
+    fn errors_have_line_numbers() {
+        let error = assemble("CLS\n\nLD V0, 256\n").unwrap_err();
+        assert!(matches!(error, AssemblerError::Source { line: 3, .. }));
+        let error = assemble("JP nowhere").unwrap_err();
+        assert_eq!(error.to_string(), "<input>:1: unknown symbol 'nowhere'");
+        let error = assemble("a: CLS\na: RET").unwrap_err();
+        assert!(matches!(error, AssemblerError::Source { line: 2, .. }));
+    }
+
+    #[test]
+    fn reassemble_disassembly() {
+        let rom = [
+            0x00, 0xff, 0xf0, 0x00, 0x02, 0x10, 0x22, 0x0e, 0x3a, 0x01, 0x8a, 0xb6, 0x12, 0x04,
+            0x00, 0xee, 0xff, 0x81,
+        ];
+        let source = disassemble(&rom, ROM_START_ADDR).format(Syntax::Classic);
+        assert_eq!(assemble(&source).unwrap().rom, rom);
+    }
+}
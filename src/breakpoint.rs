@@ -0,0 +1,415 @@
+use std::fmt;
+
+use thiserror::Error;
+
+use crate::opcode::Opcode;
+use crate::processor::{Registers, NUM_VARIABLE_REGISTERS};
+
+/// A breakpoint or watchpoint, optionally with a condition. Breakpoints on addresses and opcodes
+/// stop execution before the instruction is executed, watchpoints on memory and registers after
+/// the instruction which changed them.
+///
+/// As text, a breakpoint is written as one of
+///  * `0x2a4` (or `pc 0x2a4`): the program counter reaches the address,
+///  * `write 0x300-0x30f` (or `write 0x300`): memory in the range is written,
+///  * `change V3` (or `change I`): the register changes,
+///  * `opcode DXYN`: the opcode matches the pattern, letters other than hex digits are wildcards,
+///
+/// followed by an optional condition, e.g. `if V3 == 0x10 && I > 0x300`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub kind: BreakpointKind,
+    pub condition: Option<Condition>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BreakpointKind {
+    Address(u16),
+    MemoryWrite { start: u16, end: u16 },
+    RegisterChange(Register),
+    Opcode { pattern: u16, mask: u16 },
+}
+
+impl Breakpoint {
+    pub fn new(kind: BreakpointKind) -> Self {
+        Breakpoint {
+            kind,
+            condition: None,
+        }
+    }
+
+    pub fn with_condition(self, condition: Condition) -> Self {
+        Breakpoint {
+            condition: Some(condition),
+            ..self
+        }
+    }
+
+    /// Return true if the breakpoint is hit before executing `opcode` with the given registers.
+    pub fn is_hit_before(&self, registers: &Registers, opcode: u16) -> bool {
+        let hit = match self.kind {
+            BreakpointKind::Address(address) => registers.program_counter == address,
+            BreakpointKind::Opcode { pattern, mask } => opcode & mask == pattern,
+            _ => false,
+        };
+        hit && self.condition_holds(registers)
+    }
+
+    /// Return true if the watchpoint is hit by an instruction which changed the registers from
+    /// `before` to `after` and wrote the memory range `written`.
+    pub fn is_hit_after(
+        &self,
+        before: &Registers,
+        after: &Registers,
+        written: Option<(u16, u16)>,
+    ) -> bool {
+        let hit = match self.kind {
+            BreakpointKind::MemoryWrite { start, end } => {
+                written.is_some_and(|(first, last)| first <= end && start <= last)
+            }
+            BreakpointKind::RegisterChange(register) => {
+                register.value(before) != register.value(after)
+            }
+            _ => false,
+        };
+        hit && self.condition_holds(after)
+    }
+
+    fn condition_holds(&self, registers: &Registers) -> bool {
+        self.condition
+            .as_ref()
+            .is_none_or(|condition| condition.evaluate(registers))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, BreakpointError> {
+        let (kind, condition) = match text.split_once(" if ") {
+            Some((kind, condition)) => (kind, Some(Condition::parse(condition)?)),
+            None => (text, None),
+        };
+        let words: Vec<&str> = kind.split_whitespace().collect();
+        let kind = match words.as_slice() {
+            [address] | ["pc", address] => BreakpointKind::Address(parse_value(address)?),
+            ["write", range] => {
+                let (start, end) = match range.split_once('-') {
+                    Some((start, end)) => (parse_value(start)?, parse_value(end)?),
+                    None => (parse_value(range)?, parse_value(range)?),
+                };
+                if start > end {
+                    return Err(BreakpointError(format!("invalid range '{}'", range)));
+                }
+                BreakpointKind::MemoryWrite { start, end }
+            }
+            ["change", register] => BreakpointKind::RegisterChange(Register::parse(register)?),
+            ["opcode", pattern] => parse_opcode_pattern(pattern)?,
+            _ => return Err(BreakpointError(format!("invalid breakpoint '{}'", kind))),
+        };
+        Ok(Breakpoint { kind, condition })
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            BreakpointKind::Address(address) => write!(f, "pc {:#05x}", address)?,
+            BreakpointKind::MemoryWrite { start, end } if start == end => {
+                write!(f, "write {:#05x}", start)?
+            }
+            BreakpointKind::MemoryWrite { start, end } => {
+                write!(f, "write {:#05x}-{:#05x}", start, end)?
+            }
+            BreakpointKind::RegisterChange(register) => write!(f, "change {}", register)?,
+            BreakpointKind::Opcode { pattern, mask } => {
+                write!(f, "opcode ")?;
+                for shift in [12, 8, 4, 0] {
+                    match mask >> shift & 0xf {
+                        0 => write!(f, "_")?,
+                        _ => write!(f, "{:X}", pattern >> shift & 0xf)?,
+                    }
+                }
+            }
+        }
+        if let Some(condition) = &self.condition {
+            write!(f, " if {}", condition)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse a pattern like `DXYN` or `F_33`. Hex digits must match, other characters match
+/// anything.
+fn parse_opcode_pattern(text: &str) -> Result<BreakpointKind, BreakpointError> {
+    let text = text.trim_start_matches("0x");
+    if text.chars().count() != 4 {
+        return Err(BreakpointError(format!(
+            "invalid opcode pattern '{}'",
+            text
+        )));
+    }
+    let (mut pattern, mut mask) = (0, 0);
+    for c in text.chars() {
+        pattern <<= 4;
+        mask <<= 4;
+        if let Some(digit) = c.to_digit(16) {
+            pattern |= digit as u16;
+            mask |= 0xf;
+        }
+    }
+    Ok(BreakpointKind::Opcode { pattern, mask })
+}
+
+fn parse_value(text: &str) -> Result<u16, BreakpointError> {
+    let result = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(digits) => u16::from_str_radix(digits, 16),
+        None => text.parse(),
+    };
+    result.map_err(|_| BreakpointError(format!("invalid value '{}'", text)))
+}
+
+/// A register which can be watched or used in conditions.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Register {
+    V(u8),
+    I,
+    PC,
+    DT,
+    ST,
+}
+
+impl Register {
+    pub fn value(&self, registers: &Registers) -> u16 {
+        match *self {
+            Register::V(x) => registers.variable_registers[x as usize] as u16,
+            Register::I => registers.index_register,
+            Register::PC => registers.program_counter,
+            Register::DT => registers.delay_timer as u16,
+            Register::ST => registers.sound_timer as u16,
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Self, BreakpointError> {
+        let register = match text.to_ascii_uppercase().as_str() {
+            "I" => Register::I,
+            "PC" => Register::PC,
+            "DT" => Register::DT,
+            "ST" => Register::ST,
+            name => name
+                .strip_prefix('V')
+                .filter(|x| x.len() == 1)
+                .and_then(|x| u8::from_str_radix(x, 16).ok())
+                .filter(|&x| x < NUM_VARIABLE_REGISTERS)
+                .map(Register::V)
+                .ok_or_else(|| BreakpointError(format!("invalid register '{}'", text)))?,
+        };
+        Ok(register)
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Register::V(x) => write!(f, "V{:X}", x),
+            Register::I => write!(f, "I"),
+            Register::PC => write!(f, "PC"),
+            Register::DT => write!(f, "DT"),
+            Register::ST => write!(f, "ST"),
+        }
+    }
+}
+
+/// A condition of a breakpoint, one or more comparisons joined by `&&`, e.g. `V3 == 0x10`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Condition {
+    comparisons: Vec<Comparison>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Comparison {
+    lhs: Operand,
+    operator: Operator,
+    rhs: Operand,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Operand {
+    Register(Register),
+    Value(u16),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Operator {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+const OPERATORS: [(&str, Operator); 6] = [
+    ("==", Operator::Equal),
+    ("!=", Operator::NotEqual),
+    ("<=", Operator::LessOrEqual),
+    (">=", Operator::GreaterOrEqual),
+    ("<", Operator::Less),
+    (">", Operator::Greater),
+];
+
+impl Condition {
+    pub fn parse(text: &str) -> Result<Self, BreakpointError> {
+        let comparisons = text
+            .split("&&")
+            .map(|comparison| {
+                let (symbol, operator) = OPERATORS
+                    .iter()
+                    .find(|(symbol, _)| comparison.contains(symbol))
+                    .ok_or_else(|| {
+                        BreakpointError(format!("invalid comparison '{}'", comparison.trim()))
+                    })?;
+                let (lhs, rhs) = comparison
+                    .split_once(symbol)
+                    .expect("operator was found before");
+                Ok(Comparison {
+                    lhs: Operand::parse(lhs.trim())?,
+                    operator: *operator,
+                    rhs: Operand::parse(rhs.trim())?,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Condition { comparisons })
+    }
+
+    pub fn evaluate(&self, registers: &Registers) -> bool {
+        self.comparisons.iter().all(|comparison| {
+            let lhs = comparison.lhs.value(registers);
+            let rhs = comparison.rhs.value(registers);
+            match comparison.operator {
+                Operator::Equal => lhs == rhs,
+                Operator::NotEqual => lhs != rhs,
+                Operator::Less => lhs < rhs,
+                Operator::LessOrEqual => lhs <= rhs,
+                Operator::Greater => lhs > rhs,
+                Operator::GreaterOrEqual => lhs >= rhs,
+            }
+        })
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, comparison) in self.comparisons.iter().enumerate() {
+            if index > 0 {
+                write!(f, " && ")?;
+            }
+            let symbol = OPERATORS
+                .iter()
+                .find(|(_, operator)| *operator == comparison.operator)
+                .map_or("?", |(symbol, _)| symbol);
+            write!(f, "{} {} {}", comparison.lhs, symbol, comparison.rhs)?;
+        }
+        Ok(())
+    }
+}
+
+impl Operand {
+    fn parse(text: &str) -> Result<Self, BreakpointError> {
+        match parse_value(text) {
+            Ok(value) => Ok(Operand::Value(value)),
+            Err(_) => Ok(Operand::Register(Register::parse(text)?)),
+        }
+    }
+
+    fn value(&self, registers: &Registers) -> u16 {
+        match self {
+            Operand::Register(register) => register.value(registers),
+            Operand::Value(value) => *value,
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Register(register) => write!(f, "{}", register),
+            Operand::Value(value) => write!(f, "{:#x}", value),
+        }
+    }
+}
+
+/// The memory range (first and last address) which `instruction` writes, given the registers
+/// before its execution.
+pub fn written_memory(instruction: &Opcode, registers: &Registers) -> Option<(u16, u16)> {
+    let count = match *instruction {
+        Opcode::Store(x) => x as u16,
+        Opcode::Bcd(_) => 2,
+        Opcode::SaveRange { x, y } => x.abs_diff(y) as u16,
+        _ => return None,
+    };
+    let start = registers.index_register;
+    Some((start, start.saturating_add(count)))
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("{0}")]
+pub struct BreakpointError(String);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_display() {
+        for text in [
+            "pc 0x2a4",
+            "write 0x300-0x30f",
+            "write 0x300",
+            "change V3",
+            "opcode D___",
+            "pc 0x200 if V3 == 0x10 && I >= 0x300",
+        ] {
+            assert_eq!(Breakpoint::parse(text).unwrap().to_string(), text);
+        }
+        assert_eq!(
+            Breakpoint::parse("opcode DXYN").unwrap().kind,
+            BreakpointKind::Opcode {
+                pattern: 0xd000,
+                mask: 0xf000
+            }
+        );
+        assert_eq!(
+            Breakpoint::parse("opcode FX33").unwrap().kind,
+            BreakpointKind::Opcode {
+                pattern: 0xf033,
+                mask: 0xf0ff
+            }
+        );
+        assert!(Breakpoint::parse("change V3 if V3 = 1").is_err());
+        assert!(Breakpoint::parse("write 0x30f-0x300").is_err());
+    }
+
+    #[test]
+    fn hits() {
+        let mut registers = Registers {
+            program_counter: 0x2a4,
+            ..Default::default()
+        };
+        registers.variable_registers[3] = 0x10;
+
+        let breakpoint = Breakpoint::parse("0x2a4 if V3 == 16").unwrap();
+        assert!(breakpoint.is_hit_before(&registers, 0x1234));
+        let breakpoint = Breakpoint::parse("0x2a4 if V3 != 16").unwrap();
+        assert!(!breakpoint.is_hit_before(&registers, 0x1234));
+        let breakpoint = Breakpoint::parse("opcode DXYN").unwrap();
+        assert!(breakpoint.is_hit_before(&registers, 0xd125));
+
+        let mut after = registers.clone();
+        after.variable_registers[3] = 0x11;
+        let breakpoint = Breakpoint::parse("change V3").unwrap();
+        assert!(breakpoint.is_hit_after(&registers, &after, None));
+        let breakpoint = Breakpoint::parse("change V4").unwrap();
+        assert!(!breakpoint.is_hit_after(&registers, &after, None));
+
+        let breakpoint = Breakpoint::parse("write 0x300-0x30f").unwrap();
+        assert!(breakpoint.is_hit_after(&registers, &after, Some((0x30f, 0x311))));
+        assert!(!breakpoint.is_hit_after(&registers, &after, Some((0x310, 0x311))));
+    }
+}
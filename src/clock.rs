@@ -0,0 +1,427 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Rate of the timer ticks (delay and sound timer), which also mark the frames.
+pub const TICKS_PER_SECOND: u64 = 60;
+
+/// If the schedule falls behind by more than this, e.g. because the process was suspended, the
+/// missed time is dropped instead of being caught up in a burst.
+const MAX_LAG: Duration = Duration::from_millis(250);
+
+/// Interval over which the statistics are measured.
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+const NANOS_PER_SECOND: u128 = 1_000_000_000;
+
+/// A monotonic time source.
+pub trait Clock: Send {
+    /// Time elapsed since an arbitrary but fixed point in time.
+    fn now(&self) -> Duration;
+}
+
+/// The monotonic clock of the operating system.
+#[derive(Debug, Clone)]
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        SystemClock {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+/// A clock which only moves when it is advanced, for tests. Clones share the same time, so one
+/// clone can be handed to the emulator and the other one advanced by the test.
+#[derive(Debug, Clone, Default)]
+pub struct VirtualClock {
+    nanos: Arc<AtomicU64>,
+}
+
+impl VirtualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Duration {
+        Duration::from_nanos(self.nanos.load(Ordering::SeqCst))
+    }
+}
+
+/// What is due according to the schedule.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// A timer tick, i.e. the start of a new frame
+    Tick,
+    /// The given number of instructions
+    Instructions(usize),
+}
+
+/// When the timer ticks and the instructions are due. Ticks occur every 1/60 s. Instructions are
+/// spread evenly over time, independently of the ticks; the n-th instruction after starting is
+/// due at n / (instructions per second). All times are computed from the start, so rounding
+/// errors do not add up.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    tick_origin: Duration,
+    ticks: u64,
+    instructions: Option<InstructionSlots>,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct InstructionSlots {
+    per_second: u64,
+    origin: Duration,
+    /// Number of instructions handed out since `origin`
+    done: u64,
+}
+
+impl InstructionSlots {
+    /// Due time of the instruction with index `slot`.
+    fn time(&self, slot: u64) -> Duration {
+        self.origin + nanos(slot as u128 * NANOS_PER_SECOND / self.per_second as u128)
+    }
+
+    /// Number of instructions due up to and including `time`.
+    fn count_until(&self, time: Duration) -> u64 {
+        if time < self.origin {
+            return 0;
+        }
+        let elapsed = (time - self.origin).as_nanos() + 1;
+        (elapsed * self.per_second as u128).div_ceil(NANOS_PER_SECOND) as u64
+    }
+}
+
+impl Schedule {
+    /// Start a schedule at `now`, without instructions.
+    pub fn new(now: Duration) -> Self {
+        Schedule {
+            tick_origin: now,
+            ticks: 0,
+            instructions: None,
+        }
+    }
+
+    /// Start (or restart) scheduling instructions at `now`, with the first one due immediately.
+    pub fn start_instructions(&mut self, instructions_per_second: usize, now: Duration) {
+        self.instructions = (instructions_per_second > 0).then_some(InstructionSlots {
+            per_second: instructions_per_second as u64,
+            origin: now,
+            done: 0,
+        });
+    }
+
+    /// Stop scheduling instructions, only the timer keeps ticking.
+    pub fn stop_instructions(&mut self) {
+        self.instructions = None;
+    }
+
+    /// Drop the instructions due before the next tick, e.g. while the program waits for the
+    /// vertical blank.
+    pub fn skip_to_next_tick(&mut self) {
+        let tick = self.tick_time(self.ticks + 1);
+        if let Some(slots) = &mut self.instructions {
+            slots.done = slots
+                .done
+                .max(slots.count_until(tick.saturating_sub(Duration::from_nanos(1))));
+        }
+    }
+
+    /// Take the next event which is due at `now`, if any. Instructions due before a tick come
+    /// before it.
+    pub fn next_due(&mut self, now: Duration) -> Option<Event> {
+        let mut tick = self.tick_time(self.ticks + 1);
+        if now > tick + MAX_LAG {
+            self.resync(now);
+            tick = now;
+        }
+
+        if let Some(slots) = &mut self.instructions {
+            let limit = if now >= tick {
+                tick.saturating_sub(Duration::from_nanos(1))
+            } else {
+                now
+            };
+            let due = slots.count_until(limit).saturating_sub(slots.done);
+            if due > 0 {
+                slots.done += due;
+                return Some(Event::Instructions(due as usize));
+            }
+        }
+        if now >= tick {
+            self.ticks += 1;
+            return Some(Event::Tick);
+        }
+        None
+    }
+
+    /// Time from `now` until the next event.
+    pub fn time_until_next(&self, now: Duration) -> Duration {
+        let tick = self.tick_time(self.ticks + 1);
+        let next = match &self.instructions {
+            Some(slots) => tick.min(slots.time(slots.done)),
+            None => tick,
+        };
+        next.saturating_sub(now)
+    }
+
+    /// Time from `now` until the next tick.
+    pub fn time_until_next_tick(&self, now: Duration) -> Duration {
+        self.tick_time(self.ticks + 1).saturating_sub(now)
+    }
+
+    /// Time of the tick with index `tick`.
+    fn tick_time(&self, tick: u64) -> Duration {
+        self.tick_origin + nanos(tick as u128 * NANOS_PER_SECOND / TICKS_PER_SECOND as u128)
+    }
+
+    /// Continue the schedule with the next tick due at `now`, dropping the time missed. Ticks and
+    /// instructions are shifted alike, so that each frame keeps its instructions.
+    fn resync(&mut self, now: Duration) {
+        let lag = now - self.tick_time(self.ticks + 1);
+        self.tick_origin += lag;
+        if let Some(slots) = &mut self.instructions {
+            slots.origin += lag;
+        }
+    }
+}
+
+fn nanos(nanos: u128) -> Duration {
+    Duration::from_nanos(nanos as u64)
+}
+
+/// Measured execution speed and frame timing.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Stats {
+    pub instructions_per_second: f64,
+    /// Timer ticks per second
+    pub frames_per_second: f64,
+    /// Mean time between two timer ticks
+    pub mean_frame_time: Duration,
+    /// Longest time between two timer ticks
+    pub max_frame_time: Duration,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.0} IPS, {:.1} FPS, frame time {:.1} ms (max {:.1} ms)",
+            self.instructions_per_second,
+            self.frames_per_second,
+            self.mean_frame_time.as_secs_f64() * 1000.0,
+            self.max_frame_time.as_secs_f64() * 1000.0
+        )
+    }
+}
+
+/// Measures the statistics over intervals of one second. The statistics of the last complete
+/// interval are reported.
+#[derive(Debug, Clone)]
+pub struct StatsMeter {
+    interval_start: Duration,
+    instructions: u64,
+    ticks: u64,
+    last_tick: Duration,
+    frame_time_sum: Duration,
+    max_frame_time: Duration,
+    latest: Stats,
+}
+
+impl StatsMeter {
+    pub fn new(now: Duration) -> Self {
+        StatsMeter {
+            interval_start: now,
+            instructions: 0,
+            ticks: 0,
+            last_tick: now,
+            frame_time_sum: Duration::ZERO,
+            max_frame_time: Duration::ZERO,
+            latest: Stats::default(),
+        }
+    }
+
+    pub fn count_instructions(&mut self, count: usize) {
+        self.instructions += count as u64;
+    }
+
+    /// Count a timer tick handled at `now`. Completes the interval if it is over.
+    pub fn count_tick(&mut self, now: Duration) {
+        let frame_time = now.saturating_sub(self.last_tick);
+        self.frame_time_sum += frame_time;
+        self.max_frame_time = self.max_frame_time.max(frame_time);
+        self.ticks += 1;
+        self.last_tick = now;
+
+        let elapsed = now.saturating_sub(self.interval_start);
+        if elapsed >= STATS_INTERVAL {
+            let seconds = elapsed.as_secs_f64();
+            self.latest = Stats {
+                instructions_per_second: self.instructions as f64 / seconds,
+                frames_per_second: self.ticks as f64 / seconds,
+                mean_frame_time: self
+                    .frame_time_sum
+                    .checked_div(self.ticks as u32)
+                    .unwrap_or_default(),
+                max_frame_time: self.max_frame_time,
+            };
+            *self = StatsMeter {
+                latest: self.latest,
+                ..StatsMeter::new(now)
+            };
+        }
+    }
+
+    /// The statistics of the last complete interval.
+    pub fn stats(&self) -> Stats {
+        self.latest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MILLISECOND: Duration = Duration::from_millis(1);
+
+    /// Advance the schedule in steps of `step` until `end` and return the instructions executed
+    /// in each frame.
+    fn run(schedule: &mut Schedule, step: Duration, end: Duration) -> Vec<usize> {
+        let mut frames = vec![0];
+        let mut now = Duration::ZERO;
+        while now <= end {
+            while let Some(event) = schedule.next_due(now) {
+                match event {
+                    Event::Tick => frames.push(0),
+                    Event::Instructions(count) => *frames.last_mut().unwrap() += count,
+                }
+            }
+            now += step;
+        }
+        frames
+    }
+
+    #[test]
+    fn instructions_are_spread_evenly() {
+        let mut schedule = Schedule::new(Duration::ZERO);
+        schedule.start_instructions(700, Duration::ZERO);
+        let frames = run(&mut schedule, MILLISECOND, Duration::from_secs(1));
+        // 60 complete frames, the instruction at exactly 1 s belongs to the next one
+        assert_eq!(frames.len(), 61);
+        assert_eq!(frames.iter().sum::<usize>(), 701);
+        assert!(frames[..60].iter().all(|&count| count == 11 || count == 12));
+
+        // Within a frame, instructions are not executed in a burst
+        let mut schedule = Schedule::new(Duration::ZERO);
+        schedule.start_instructions(600, Duration::ZERO);
+        assert_eq!(
+            schedule.next_due(Duration::ZERO),
+            Some(Event::Instructions(1))
+        );
+        assert_eq!(
+            schedule.next_due(Duration::from_micros(8400)),
+            Some(Event::Instructions(5))
+        );
+        assert_eq!(
+            schedule.time_until_next(Duration::from_micros(8400)),
+            Duration::from_micros(1600)
+        );
+
+        // The result does not depend on how often the schedule is polled
+        let mut schedule = Schedule::new(Duration::ZERO);
+        schedule.start_instructions(700, Duration::ZERO);
+        let coarse = run(
+            &mut schedule,
+            Duration::from_millis(50),
+            Duration::from_secs(1),
+        );
+        assert_eq!(coarse[..20], frames[..20]);
+    }
+
+    #[test]
+    fn ticks_without_instructions() {
+        let mut schedule = Schedule::new(Duration::ZERO);
+        let frames = run(&mut schedule, MILLISECOND, Duration::from_secs(2));
+        assert_eq!(frames.len(), 121);
+        assert!(frames.iter().all(|&count| count == 0));
+        assert!(schedule.time_until_next(Duration::from_secs(2)) <= Duration::from_millis(17));
+    }
+
+    #[test]
+    fn skip_and_resync() {
+        let mut schedule = Schedule::new(Duration::ZERO);
+        schedule.start_instructions(600, Duration::ZERO);
+        assert_eq!(
+            schedule.next_due(Duration::ZERO),
+            Some(Event::Instructions(1))
+        );
+        schedule.skip_to_next_tick();
+        assert_eq!(schedule.next_due(Duration::from_millis(16)), None);
+        let tick = Duration::from_nanos(16_666_667);
+        assert_eq!(schedule.next_due(tick), Some(Event::Tick));
+        assert_eq!(schedule.next_due(tick), Some(Event::Instructions(1)));
+
+        // After a long pause, the current frame is completed and the missed time is dropped
+        let late = Duration::from_secs(10);
+        assert_eq!(schedule.next_due(late), Some(Event::Instructions(9)));
+        assert_eq!(schedule.next_due(late), Some(Event::Tick));
+        assert_eq!(schedule.next_due(late), Some(Event::Instructions(1)));
+        assert_eq!(schedule.next_due(late), None);
+        assert_eq!(
+            schedule.time_until_next_tick(late),
+            Duration::from_nanos(16_666_667)
+        );
+    }
+
+    #[test]
+    fn stats() {
+        let mut meter = StatsMeter::new(Duration::ZERO);
+        assert_eq!(meter.stats(), Stats::default());
+        let mut schedule = Schedule::new(Duration::ZERO);
+        schedule.start_instructions(1000, Duration::ZERO);
+        let mut now = Duration::ZERO;
+        while now < Duration::from_millis(2500) {
+            while let Some(event) = schedule.next_due(now) {
+                match event {
+                    Event::Tick => meter.count_tick(now),
+                    Event::Instructions(count) => meter.count_instructions(count),
+                }
+            }
+            now += MILLISECOND;
+        }
+        let stats = meter.stats();
+        assert!((stats.instructions_per_second - 1000.0).abs() < 20.0);
+        assert!((stats.frames_per_second - 60.0).abs() < 1.5);
+        assert!(stats.max_frame_time <= Duration::from_millis(17));
+        assert!(stats.mean_frame_time > Duration::from_millis(16));
+        assert!(stats.to_string().contains("IPS"));
+
+        let clock = VirtualClock::new();
+        let handle = clock.clone();
+        handle.advance(Duration::from_millis(3));
+        assert_eq!(clock.now(), Duration::from_millis(3));
+    }
+}
@@ -0,0 +1,469 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::io;
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+use crate::opcode::Opcode;
+use crate::processor::{MEMORY_SIZE, ROM_START_ADDR};
+use crate::symbols::{SourceLine, SymbolMap, SymbolMapError};
+
+/// Command line arguments for the disassembler
+#[derive(clap::Args, Debug)]
+pub struct DisasmArgs {
+    /// ROM file (*.ch8)
+    #[arg(value_name = "ROM_FILE")]
+    pub program_file: PathBuf,
+
+    /// Syntax of the listing
+    #[arg(long, value_enum, default_value_t = Syntax::Octo)]
+    pub syntax: Syntax,
+
+    /// Symbol file with labels and source lines, e.g. written by `jade asm --symbols`
+    #[arg(long, value_name = "FILE")]
+    pub symbols: Option<PathBuf>,
+}
+
+/// Print an annotated listing of the ROM given on the command line.
+pub fn run(args: &DisasmArgs) -> Result<(), DisasmError> {
+    let data = std::fs::read(&args.program_file)?;
+    let max = MEMORY_SIZE - ROM_START_ADDR as usize;
+    if data.len() > max {
+        return Err(DisasmError::TooLarge {
+            size: data.len(),
+            max,
+        });
+    }
+    let mut listing = disassemble(&data, ROM_START_ADDR);
+    if let Some(path) = &args.symbols {
+        listing.apply_symbols(&SymbolMap::load(path)?);
+    }
+    print!("{}", listing.format(args.syntax));
+    Ok(())
+}
+
+/// Mnemonic syntax of a listing.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Syntax {
+    /// Octo assembly language, e.g. `v1 := 0x12`
+    Octo,
+    /// Classic mnemonics as in Cowgod's technical reference, e.g. `LD V1, 0x12`
+    Classic,
+}
+
+impl Opcode {
+    /// Format the instruction in the given syntax. Addresses are replaced by their labels, if
+    /// there are any.
+    pub fn format(&self, syntax: Syntax, labels: &BTreeMap<u16, String>) -> String {
+        let address = |address: &u16| match labels.get(address) {
+            Some(label) => label.clone(),
+            None => format!("{:#05x}", address),
+        };
+        match syntax {
+            Syntax::Octo => self.format_octo(address),
+            Syntax::Classic => self.format_classic(address),
+        }
+    }
+
+    fn format_octo(&self, address: impl Fn(&u16) -> String) -> String {
+        use Opcode::*;
+        // Octo expresses skips as "if <condition> then <instruction>", where the instruction is
+        // executed if the condition is true, i.e. the skip condition is negated.
+        match self {
+            ClearScreen => "clear".to_string(),
+            Return => "return".to_string(),
+            ScrollDown(n) => format!("scroll-down {}", n),
+            ScrollUp(n) => format!("scroll-up {}", n),
+            ScrollRight => "scroll-right".to_string(),
+            ScrollLeft => "scroll-left".to_string(),
+            Exit => "exit".to_string(),
+            LowResolution => "lores".to_string(),
+            HighResolution => "hires".to_string(),
+            Jump(a) => format!("jump {}", address(a)),
+            Call(a) => format!(":call {}", address(a)),
+            SkipIfEqual { x, value } => format!("if v{:x} != {:#04x} then", x, value),
+            SkipIfNotEqual { x, value } => format!("if v{:x} == {:#04x} then", x, value),
+            SkipIfRegistersEqual { x, y } => format!("if v{:x} != v{:x} then", x, y),
+            SaveRange { x, y } => format!("save v{:x} - v{:x}", x, y),
+            LoadRange { x, y } => format!("load v{:x} - v{:x}", x, y),
+            SetRegister { x, value } => format!("v{:x} := {:#04x}", x, value),
+            AddToRegister { x, value } => format!("v{:x} += {:#04x}", x, value),
+            Assign { x, y } => format!("v{:x} := v{:x}", x, y),
+            Or { x, y } => format!("v{:x} |= v{:x}", x, y),
+            And { x, y } => format!("v{:x} &= v{:x}", x, y),
+            Xor { x, y } => format!("v{:x} ^= v{:x}", x, y),
+            Add { x, y } => format!("v{:x} += v{:x}", x, y),
+            Subtract { x, y } => format!("v{:x} -= v{:x}", x, y),
+            ShiftRight { x, y } => format!("v{:x} >>= v{:x}", x, y),
+            SubtractReverse { x, y } => format!("v{:x} =- v{:x}", x, y),
+            ShiftLeft { x, y } => format!("v{:x} <<= v{:x}", x, y),
+            SkipIfRegistersNotEqual { x, y } => format!("if v{:x} == v{:x} then", x, y),
+            SetIndex(a) => format!("i := {}", address(a)),
+            JumpWithOffset(a) => format!("jump0 {}", address(a)),
+            Random { x, mask } => format!("v{:x} := random {:#04x}", x, mask),
+            Draw { x, y, rows } => format!("sprite v{:x} v{:x} {}", x, y, rows),
+            SkipIfKey(x) => format!("if v{:x} -key then", x),
+            SkipIfNotKey(x) => format!("if v{:x} key then", x),
+            SetIndexLong(a) => format!("i := long {}", address(a)),
+            LoadAudio => "audio".to_string(),
+            SelectPlanes(n) => format!("plane {}", n),
+            GetDelay(x) => format!("v{:x} := delay", x),
+            WaitKey(x) => format!("v{:x} := key", x),
+            SetDelay(x) => format!("delay := v{:x}", x),
+            SetSound(x) => format!("buzzer := v{:x}", x),
+            AddToIndex(x) => format!("i += v{:x}", x),
+            Font(x) => format!("i := hex v{:x}", x),
+            BigFont(x) => format!("i := bighex v{:x}", x),
+            Bcd(x) => format!("bcd v{:x}", x),
+            SetPitch(x) => format!("pitch := v{:x}", x),
+            Store(x) => format!("save v{:x}", x),
+            Load(x) => format!("load v{:x}", x),
+            StoreFlags(x) => format!("saveflags v{:x}", x),
+            LoadFlags(x) => format!("loadflags v{:x}", x),
+        }
+    }
+
+    fn format_classic(&self, address: impl Fn(&u16) -> String) -> String {
+        use Opcode::*;
+        match self {
+            ClearScreen => "CLS".to_string(),
+            Return => "RET".to_string(),
+            ScrollDown(n) => format!("SCD {}", n),
+            ScrollUp(n) => format!("SCU {}", n),
+            ScrollRight => "SCR".to_string(),
+            ScrollLeft => "SCL".to_string(),
+            Exit => "EXIT".to_string(),
+            LowResolution => "LOW".to_string(),
+            HighResolution => "HIGH".to_string(),
+            Jump(a) => format!("JP {}", address(a)),
+            Call(a) => format!("CALL {}", address(a)),
+            SkipIfEqual { x, value } => format!("SE V{:X}, {:#04x}", x, value),
+            SkipIfNotEqual { x, value } => format!("SNE V{:X}, {:#04x}", x, value),
+            SkipIfRegistersEqual { x, y } => format!("SE V{:X}, V{:X}", x, y),
+            SaveRange { x, y } => format!("SAVE V{:X}, V{:X}", x, y),
+            LoadRange { x, y } => format!("LOAD V{:X}, V{:X}", x, y),
+            SetRegister { x, value } => format!("LD V{:X}, {:#04x}", x, value),
+            AddToRegister { x, value } => format!("ADD V{:X}, {:#04x}", x, value),
+            Assign { x, y } => format!("LD V{:X}, V{:X}", x, y),
+            Or { x, y } => format!("OR V{:X}, V{:X}", x, y),
+            And { x, y } => format!("AND V{:X}, V{:X}", x, y),
+            Xor { x, y } => format!("XOR V{:X}, V{:X}", x, y),
+            Add { x, y } => format!("ADD V{:X}, V{:X}", x, y),
+            Subtract { x, y } => format!("SUB V{:X}, V{:X}", x, y),
+            ShiftRight { x, y } => format!("SHR V{:X}, V{:X}", x, y),
+            SubtractReverse { x, y } => format!("SUBN V{:X}, V{:X}", x, y),
+            ShiftLeft { x, y } => format!("SHL V{:X}, V{:X}", x, y),
+            SkipIfRegistersNotEqual { x, y } => format!("SNE V{:X}, V{:X}", x, y),
+            SetIndex(a) => format!("LD I, {}", address(a)),
+            JumpWithOffset(a) => format!("JP V0, {}", address(a)),
+            Random { x, mask } => format!("RND V{:X}, {:#04x}", x, mask),
+            Draw { x, y, rows } => format!("DRW V{:X}, V{:X}, {}", x, y, rows),
+            SkipIfKey(x) => format!("SKP V{:X}", x),
+            SkipIfNotKey(x) => format!("SKNP V{:X}", x),
+            SetIndexLong(a) => format!("LD I, LONG {}", address(a)),
+            LoadAudio => "AUDIO".to_string(),
+            SelectPlanes(n) => format!("PLANE {}", n),
+            GetDelay(x) => format!("LD V{:X}, DT", x),
+            WaitKey(x) => format!("LD V{:X}, K", x),
+            SetDelay(x) => format!("LD DT, V{:X}", x),
+            SetSound(x) => format!("LD ST, V{:X}", x),
+            AddToIndex(x) => format!("ADD I, V{:X}", x),
+            Font(x) => format!("LD F, V{:X}", x),
+            BigFont(x) => format!("LD HF, V{:X}", x),
+            Bcd(x) => format!("LD B, V{:X}", x),
+            SetPitch(x) => format!("PITCH V{:X}", x),
+            Store(x) => format!("LD [I], V{:X}", x),
+            Load(x) => format!("LD V{:X}, [I]", x),
+            StoreFlags(x) => format!("LD R, V{:X}", x),
+            LoadFlags(x) => format!("LD V{:X}, R", x),
+        }
+    }
+}
+
+/// An item of a listing, either an instruction or a run of data bytes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Item {
+    Instruction {
+        address: u16,
+        bytes: Vec<u8>,
+        instruction: Opcode,
+    },
+    Data {
+        address: u16,
+        bytes: Vec<u8>,
+    },
+}
+
+impl Item {
+    pub fn address(&self) -> u16 {
+        match self {
+            Item::Instruction { address, .. } | Item::Data { address, .. } => *address,
+        }
+    }
+}
+
+/// The result of disassembling a ROM: instructions, data and labels, and optionally the source
+/// lines of the items.
+#[derive(Clone, Debug, Default)]
+pub struct Listing {
+    pub items: Vec<Item>,
+    pub labels: BTreeMap<u16, String>,
+    pub source: BTreeMap<u16, SourceLine>,
+}
+
+/// Maximum number of data bytes per listing line.
+const DATA_BYTES_PER_LINE: usize = 8;
+
+/// Disassemble `data`, which is loaded at `origin`. Code is found by following the control flow
+/// from the origin. Everything which cannot be reached is considered data. Jump and call
+/// targets as well as addresses loaded into the index register get labels. Bytes beyond the end
+/// of the address space are ignored.
+pub fn disassemble(data: &[u8], origin: u16) -> Listing {
+    let data = &data[..data.len().min(MEMORY_SIZE - origin as usize)];
+    let end = origin as usize + data.len();
+    let in_rom = |address: u16| (origin as usize..end).contains(&(address as usize));
+    let instruction_at = |address: u16| {
+        let offset = address.checked_sub(origin)? as usize;
+        Opcode::decode_bytes(data.get(offset..)?).ok()
+    };
+
+    // Follow the control flow from the origin to find the code
+    let mut code = BTreeMap::new();
+    let mut jump_targets = BTreeSet::new();
+    let mut call_targets = BTreeSet::new();
+    let mut data_targets = BTreeSet::new();
+    let mut pending = vec![origin];
+    while let Some(address) = pending.pop() {
+        if code.contains_key(&address) || !in_rom(address) {
+            continue;
+        }
+        let Some(instruction) = instruction_at(address) else {
+            continue;
+        };
+        code.insert(address, instruction);
+
+        let next = address.wrapping_add(instruction.size());
+        match instruction {
+            Opcode::Jump(target) => {
+                jump_targets.insert(target);
+                pending.push(target);
+            }
+            Opcode::Call(target) => {
+                call_targets.insert(target);
+                pending.push(target);
+                pending.push(next);
+            }
+            Opcode::SetIndex(target) | Opcode::SetIndexLong(target) => {
+                data_targets.insert(target);
+                pending.push(next);
+            }
+            // The target of BNNN depends on a register, and returning or exiting ends the flow
+            Opcode::JumpWithOffset(target) => {
+                jump_targets.insert(target);
+            }
+            Opcode::Return | Opcode::Exit => {}
+            instruction if instruction.is_skip() => {
+                pending.push(next);
+                let skipped = instruction_at(next).map_or(2, |i| i.size());
+                pending.push(next.wrapping_add(skipped));
+            }
+            _ => pending.push(next),
+        }
+    }
+
+    // Name the labels, calls take precedence over jumps over data
+    let mut labels = BTreeMap::new();
+    for &address in data_targets.iter().filter(|&&a| in_rom(a)) {
+        labels.insert(address, format!("data_{:03x}", address));
+    }
+    for &address in jump_targets.iter().filter(|&&a| in_rom(a)) {
+        labels.insert(address, format!("label_{:03x}", address));
+    }
+    for &address in call_targets.iter().filter(|&&a| in_rom(a)) {
+        labels.insert(address, format!("sub_{:03x}", address));
+    }
+
+    // Create the items
+    let mut items = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let address = origin.wrapping_add(offset as u16);
+        if let Some(instruction) = code.get(&address) {
+            let length = instruction.size() as usize;
+            items.push(Item::Instruction {
+                address,
+                bytes: data[offset..offset + length].to_vec(),
+                instruction: *instruction,
+            });
+            offset += length;
+            continue;
+        }
+
+        // Data up to the next instruction or label
+        let mut length = 1;
+        while length < DATA_BYTES_PER_LINE && offset + length < data.len() {
+            let next = address.wrapping_add(length as u16);
+            if code.contains_key(&next) || labels.contains_key(&next) {
+                break;
+            }
+            length += 1;
+        }
+        items.push(Item::Data {
+            address,
+            bytes: data[offset..offset + length].to_vec(),
+        });
+        offset += length;
+    }
+
+    // Targets in the middle of an instruction cannot be labeled
+    let item_addresses: BTreeSet<u16> = items.iter().map(Item::address).collect();
+    labels.retain(|address, _| item_addresses.contains(address));
+
+    Listing {
+        items,
+        labels,
+        source: BTreeMap::new(),
+    }
+}
+
+impl Listing {
+    /// Name the labels after `symbols` and add the source lines. Symbols replace generated labels
+    /// at the same address.
+    pub fn apply_symbols(&mut self, symbols: &SymbolMap) {
+        for (address, name) in symbols.labels() {
+            let is_item = self.items.iter().any(|item| item.address() == address);
+            if is_item || self.labels.contains_key(&address) {
+                self.labels.insert(address, name);
+            }
+        }
+        for item in &self.items {
+            if let Some(source) = symbols.source_line(item.address()) {
+                self.source.insert(item.address(), source.clone());
+            }
+        }
+    }
+
+    /// Format the listing in the given syntax. Each line is annotated with the address and the
+    /// raw bytes.
+    pub fn format(&self, syntax: Syntax) -> String {
+        let comment = match syntax {
+            Syntax::Octo => "#",
+            Syntax::Classic => ";",
+        };
+
+        let mut text = String::new();
+        for item in &self.items {
+            let address = item.address();
+            if let Some(label) = self.labels.get(&address) {
+                match syntax {
+                    Syntax::Octo => writeln!(text, ": {}", label),
+                    Syntax::Classic => writeln!(text, "{}:", label),
+                }
+                .expect("writing to a string cannot fail");
+            }
+
+            let (code, bytes) = match item {
+                Item::Instruction {
+                    bytes, instruction, ..
+                } => (instruction.format(syntax, &self.labels), bytes),
+                Item::Data { bytes, .. } => {
+                    let values: Vec<_> = bytes.iter().map(|b| format!("{:#04x}", b)).collect();
+                    let code = match syntax {
+                        Syntax::Octo => values.join(" "),
+                        Syntax::Classic => format!("DB {}", values.join(", ")),
+                    };
+                    (code, bytes)
+                }
+            };
+            let hex: String = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            let line = format!("    {:<32} {} {:#06x}  {}", code, comment, address, hex);
+            match self.source.get(&address) {
+                Some(source) => writeln!(text, "{:<56}  {}", line, source),
+                None => writeln!(text, "{}", line),
+            }
+            .expect("writing to a string cannot fail");
+        }
+        text
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum DisasmError {
+    #[error("cannot read ROM file: {0}")]
+    Read(#[from] io::Error),
+
+    #[error(transparent)]
+    Symbols(#[from] SymbolMapError),
+
+    #[error("ROM file too large ({size} bytes, at most {max} bytes fit into memory)")]
+    TooLarge { size: usize, max: usize },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listing_with_labels_and_data() {
+        let rom = [
+            0xa2, 0x0a, // 0x200: i := data_20a
+            0x22, 0x08, // 0x202: :call sub_208
+            0x12, 0x04, // 0x204: jump label_204
+            0xff, 0xff, // 0x206: unreachable
+            0xd0, 0x11, // 0x208: sprite v0 v1 1
+            0x00, 0xee, // 0x20a: return (also data)
+        ];
+        let listing = disassemble(&rom, 0x200);
+        assert_eq!(listing.labels.get(&0x204).unwrap(), "label_204");
+        assert_eq!(listing.labels.get(&0x208).unwrap(), "sub_208");
+        assert_eq!(listing.labels.get(&0x20a).unwrap(), "data_20a");
+        assert!(matches!(
+            listing.items[3],
+            Item::Data { address: 0x206, .. }
+        ));
+
+        let octo = listing.format(Syntax::Octo);
+        let lines: Vec<_> = octo
+            .lines()
+            .map(|l| l.split('#').next().unwrap().trim())
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                "i := data_20a",
+                ":call sub_208",
+                ": label_204",
+                "jump label_204",
+                "0xff 0xff",
+                ": sub_208",
+                "sprite v0 v1 1",
+                ": data_20a",
+                "return",
+            ]
+        );
+
+        let classic = listing.format(Syntax::Classic);
+        assert!(classic.contains("LD I, data_20a"));
+        assert!(classic.contains("DB 0xff, 0xff"));
+    }
+
+    #[test]
+    fn end_of_address_space() {
+        // Data up to the last byte of memory
+        let mut rom = vec![0x12, 0x00];
+        rom.resize(MEMORY_SIZE - 0x200 - 1, 0);
+        rom.push(0x12);
+        let listing = disassemble(&rom, 0x200);
+        let Some(Item::Data { address, bytes }) = listing.items.last() else {
+            panic!("no data at the end");
+        };
+        assert_eq!(*address as usize + bytes.len(), MEMORY_SIZE);
+        assert_eq!(bytes.last(), Some(&0x12));
+
+        // Bytes beyond the end of memory are dropped
+        let listing = disassemble(&[0x00, 0xe0, 0x12, 0x34, 0x56, 0x78], 0xfffc);
+        assert_eq!(listing.items.len(), 2);
+        assert_eq!(listing.items[1].address(), 0xfffe);
+    }
+}
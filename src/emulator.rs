@@ -1,45 +1,63 @@
 use std::collections::HashSet;
+use std::ops::ControlFlow;
+use std::path::PathBuf;
 use std::sync::mpsc;
+use std::time::Duration;
 
-use log::{error, trace, warn};
+use log::{error, info, trace, warn};
 
-pub use crate::processor::{Display, InstructionSettings, Key, DISPLAY_HEIGHT, DISPLAY_WIDTH};
-use crate::processor::{EmulatorError, Processor};
+use crate::breakpoint::{self, Breakpoint};
+use crate::clock::{Clock, Event, Schedule, StatsMeter, SystemClock};
+pub use crate::clock::{Stats, VirtualClock};
+use crate::movie::{Movie, MovieError};
+use crate::opcode::Opcode;
+pub use crate::processor::{
+    Display, InstructionOverrides, InstructionSettings, Key, Platform, Registers, DISPLAY_HEIGHT,
+    DISPLAY_WIDTH, HIRES_DISPLAY_HEIGHT, HIRES_DISPLAY_WIDTH,
+};
+use crate::processor::{EmulatorError, Processor, MEMORY_SIZE};
+use crate::rewind::RewindBuffer;
+pub use crate::rewind::RewindSettings;
+use crate::savestate::{self, SaveStateError};
 use crate::sound::Sound;
+use crate::symbols::SymbolMap;
+use crate::trace::{self, Tracer};
 
-const TIMER_INTERVAL: std::time::Duration = std::time::Duration::from_micros(16666);
-const DEFAULT_INSTRUCTIONS_PER_SECOND: usize = 700;
+pub const DEFAULT_INSTRUCTIONS_PER_SECOND: usize = 700;
+
+/// Shortest time the executor waits for requests, which limits how often it wakes up at high
+/// speeds. The instructions due in the meantime are executed together.
+const MIN_WAIT: Duration = Duration::from_millis(1);
+
+/// Number of bytes before the program counter included in the debug state.
+const DEBUG_CODE_BEFORE_PC: u16 = 16;
+/// Number of bytes of code included in the debug state.
+const DEBUG_CODE_LENGTH: usize = 48;
 
 /// The main part of the CHIP-8 emulator. Uses threading internally.
 pub struct Emulator {
     sender: mpsc::Sender<Request>,
     receiver: mpsc::Receiver<Response>,
-    #[allow(dead_code)]
-    timer: timer::Timer,
-    #[allow(dead_code)]
-    guard: timer::Guard,
 }
 
 impl Emulator {
-    /// Start a new emulator in a separate thread. This function also sets up the required
-    /// timers (delay and sound timer).
+    /// Start a new emulator in a separate thread, timed by the monotonic clock of the system.
     pub fn new() -> Self {
+        Self::with_clock(SystemClock::new())
+    }
+
+    /// Start a new emulator in a separate thread, timed by `clock`. The executor schedules the
+    /// timer ticks (delay and sound timer) and the instructions by it, e.g. a `VirtualClock` for
+    /// tests.
+    pub fn with_clock(clock: impl Clock + 'static) -> Self {
         // Channel from the emulator (handle) to the executor
         let (sender, executor_receiver) = mpsc::channel();
         // Channel from the executor back to the emulator
         let (executor_sender, receiver) = mpsc::channel();
 
-        let s = sender.clone();
-        let timer = timer::Timer::new();
-        let duration =
-            chrono::TimeDelta::from_std(TIMER_INTERVAL).expect("timer duration out of range");
-        let guard = timer.schedule_repeating(duration, move || {
-            let _ = s.send(Request::TimerTick);
-        });
-
         trace!("starting emulator");
         std::thread::spawn(move || {
-            let mut emulator = Executor::new(executor_receiver, executor_sender);
+            let mut emulator = Executor::new(executor_receiver, executor_sender, Box::new(clock));
             emulator.start()
         });
 
@@ -49,12 +67,7 @@ impl Emulator {
         // (i) Forgetting to call join explicitly. (ii) Implementing drop() and calling join there.
         // (iii) Doing the option dance in drop() so not to join an  already joined thread.
 
-        Self {
-            sender,
-            receiver,
-            timer,
-            guard,
-        }
+        Self { sender, receiver }
     }
 
     /// Get all responses currently available from previously posted requests.
@@ -76,6 +89,11 @@ impl Emulator {
         responses
     }
 
+    /// Wait up to `timeout` for the next response. Returns `None` if there is none in time.
+    pub fn wait_response(&mut self, timeout: std::time::Duration) -> Option<Response> {
+        self.receiver.recv_timeout(timeout).ok()
+    }
+
     /// Request to load settings regarding the instruction set into the emulator.
     pub fn load_settings(&self, settings: InstructionSettings) {
         let msg = Request::LoadSettings(settings);
@@ -84,9 +102,27 @@ impl Emulator {
             .expect("EmulatorHandle::load_settings failed to send. Emulator no longer running?");
     }
 
-    /// Request to load a program data (a 'ROM') into the emulator.
-    pub fn load_program(&self, data: Vec<u8>) {
-        let msg = Request::LoadProgram(data.to_vec());
+    /// Request to seed the random number generator. The seed is used for every program loaded
+    /// afterwards.
+    pub fn set_seed(&self, seed: u64) {
+        let msg = Request::SetSeed(seed);
+        self.sender
+            .send(msg)
+            .expect("EmulatorHandle::set_seed failed. Emulator no longer running?");
+    }
+
+    /// Request to configure the rewind buffer. Drops all snapshots taken so far.
+    pub fn configure_rewind(&self, settings: RewindSettings) {
+        let msg = Request::ConfigureRewind(settings);
+        self.sender
+            .send(msg)
+            .expect("EmulatorHandle::configure_rewind failed. Emulator no longer running?");
+    }
+
+    /// Request to load a program data (a 'ROM') into the emulator. With `symbols`, error messages
+    /// and traces show the locations of instructions relative to labels and their source lines.
+    pub fn load_program(&self, data: Vec<u8>, symbols: Option<SymbolMap>) {
+        let msg = Request::LoadProgram(data.to_vec(), symbols.unwrap_or_default());
         self.sender
             .send(msg)
             .expect("EmulatorHandle::load_program failed. Emulator no longer running?");
@@ -116,6 +152,89 @@ impl Emulator {
             .expect("EmulatorHandle::step failed. Emulator no longer running?");
     }
 
+    /// Request to step over the instruction at the program counter. For a subroutine call, the
+    /// program runs until the subroutine returns. Otherwise, this is the same as `step()`.
+    pub fn step_over(&self) {
+        let msg = Request::StepOver;
+        self.sender
+            .send(msg)
+            .expect("EmulatorHandle::step_over failed. Emulator no longer running?");
+    }
+
+    /// Request to run the program until the current subroutine returns.
+    pub fn step_out(&self) {
+        let msg = Request::StepOut;
+        self.sender
+            .send(msg)
+            .expect("EmulatorHandle::step_out failed. Emulator no longer running?");
+    }
+
+    /// Request the registers and the code around the program counter for debugging.
+    pub fn query_debug_state(&self) {
+        let msg = Request::DebugState;
+        self.sender
+            .send(msg)
+            .expect("EmulatorHandle::debug_state failed. Emulator no longer running?");
+    }
+
+    /// Request a copy of the complete memory.
+    pub fn query_memory(&self) {
+        let msg = Request::Memory;
+        self.sender
+            .send(msg)
+            .expect("EmulatorHandle::memory failed. Emulator no longer running?");
+    }
+
+    /// Request to overwrite memory starting at `address`. Only possible while the program is
+    /// stopped.
+    pub fn write_memory(&self, address: u16, data: Vec<u8>) {
+        let msg = Request::WriteMemory(address, data);
+        self.sender
+            .send(msg)
+            .expect("EmulatorHandle::write_memory failed. Emulator no longer running?");
+    }
+
+    /// Request the measured execution speed and frame timing.
+    pub fn query_stats(&self) {
+        let msg = Request::Stats;
+        self.sender
+            .send(msg)
+            .expect("EmulatorHandle::stats failed. Emulator no longer running?");
+    }
+
+    /// Request to record each executed instruction with `tracer`.
+    pub fn set_tracer(&self, tracer: Tracer) {
+        let msg = Request::SetTracer(tracer);
+        self.sender
+            .send(msg)
+            .expect("EmulatorHandle::set_tracer failed. Emulator no longer running?");
+    }
+
+    /// Request to overwrite the registers. Only possible while the program is stopped.
+    pub fn write_registers(&self, registers: Registers) {
+        let msg = Request::WriteRegisters(registers);
+        self.sender
+            .send(msg)
+            .expect("EmulatorHandle::write_registers failed. Emulator no longer running?");
+    }
+
+    /// Request to stop the program when the breakpoint is hit. A `Response::BreakpointHit` is
+    /// sent then.
+    pub fn set_breakpoint(&self, breakpoint: Breakpoint) {
+        let msg = Request::SetBreakpoint(breakpoint);
+        self.sender
+            .send(msg)
+            .expect("EmulatorHandle::set_breakpoint failed. Emulator no longer running?");
+    }
+
+    /// Request to remove a breakpoint set before.
+    pub fn clear_breakpoint(&self, breakpoint: Breakpoint) {
+        let msg = Request::ClearBreakpoint(breakpoint);
+        self.sender
+            .send(msg)
+            .expect("EmulatorHandle::clear_breakpoint failed. Emulator no longer running?");
+    }
+
     /// Request the display data.
     pub fn query_display(&self) {
         let msg = Request::Display;
@@ -132,6 +251,57 @@ impl Emulator {
             .expect("EmulatorHandle::state failed. Emulator no longer running?");
     }
 
+    /// Request to restart the program and record the keys pressed in each frame. The movie is
+    /// written to `path` when recording is stopped.
+    pub fn record_movie(&self, path: PathBuf) {
+        let msg = Request::RecordMovie(path);
+        self.sender
+            .send(msg)
+            .expect("EmulatorHandle::record_movie failed. Emulator no longer running?");
+    }
+
+    /// Request to stop recording and write the movie. A `Response::RecordMovie` is sent then.
+    pub fn stop_recording(&self) {
+        let msg = Request::StopRecording;
+        self.sender
+            .send(msg)
+            .expect("EmulatorHandle::stop_recording failed. Emulator no longer running?");
+    }
+
+    /// Request to restart the program with the seed of the movie and press the keys of the movie
+    /// instead of the keys sent. The program is paused at the end of the movie.
+    pub fn play_movie(&self, movie: Movie) {
+        let msg = Request::PlayMovie(movie);
+        self.sender
+            .send(msg)
+            .expect("EmulatorHandle::play_movie failed. Emulator no longer running?");
+    }
+
+    /// Request to write the complete emulator state to the save state file at `path`.
+    pub fn save_state(&self, path: PathBuf) {
+        let msg = Request::SaveState(path);
+        self.sender
+            .send(msg)
+            .expect("EmulatorHandle::save_state failed. Emulator no longer running?");
+    }
+
+    /// Request to restore the complete emulator state from the save state file at `path`.
+    pub fn load_state(&self, path: PathBuf) {
+        let msg = Request::LoadState(path);
+        self.sender
+            .send(msg)
+            .expect("EmulatorHandle::load_state failed. Emulator no longer running?");
+    }
+
+    /// Request to go back in time by approximately `frames` timer ticks, using the snapshots in the
+    /// rewind buffer.
+    pub fn rewind(&self, frames: usize) {
+        let msg = Request::Rewind(frames);
+        self.sender
+            .send(msg)
+            .expect("EmulatorHandle::rewind failed. Emulator no longer running?");
+    }
+
     /// Pass the currently pressed keys to the emulator.
     pub fn send_keys(&self, keys: &HashSet<Key>) {
         let msg = Request::SendKeys(keys.clone());
@@ -151,14 +321,31 @@ impl Default for Emulator {
 #[derive(Debug)]
 enum Request {
     LoadSettings(InstructionSettings),
-    LoadProgram(Vec<u8>),
+    SetSeed(u64),
+    ConfigureRewind(RewindSettings),
+    SetTracer(Tracer),
+    LoadProgram(Vec<u8>, SymbolMap),
     RunProgram(Speed),
     Stop,
     Step,
+    StepOver,
+    StepOut,
     Display,
     State,
+    DebugState,
+    Memory,
+    Stats,
+    WriteMemory(u16, Vec<u8>),
+    WriteRegisters(Registers),
+    SetBreakpoint(Breakpoint),
+    ClearBreakpoint(Breakpoint),
     SendKeys(HashSet<Key>),
-    TimerTick,
+    RecordMovie(PathBuf),
+    StopRecording,
+    PlayMovie(Movie),
+    SaveState(PathBuf),
+    LoadState(PathBuf),
+    Rewind(usize),
 }
 
 /// List of responses sent from emulator as an answer to a client request. Not all requests have
@@ -169,9 +356,22 @@ pub enum Response {
     Step(Result<(), EmulatorError>),
     Display(Display),
     State(ProgramState),
+    DebugState(DebugState),
+    Memory(Vec<u8>),
+    Stats(Stats),
+    BreakpointHit(Breakpoint, Registers),
+    RecordMovie(Result<(), MovieError>),
+    SaveState(Result<(), SaveStateError>),
+    LoadState(Result<(), SaveStateError>),
     RunError(EmulatorError),
 }
 
+/// Whether the executor records or plays a movie.
+enum MovieMode {
+    Recording { movie: Movie, path: PathBuf },
+    Playing { movie: Movie, frame: usize },
+}
+
 /// Executor part of the emulator. Receives client requests, contains the core loop and handles
 /// things like execution speed and sound.
 struct Executor {
@@ -181,18 +381,40 @@ struct Executor {
     state: ProgramState,
     sound: Option<Sound>,
     speed: Speed,
-    instruction_account_balance: usize,
+    clock: Box<dyn Clock>,
+    /// When the next timer tick and instructions are due
+    schedule: Schedule,
+    stats: StatsMeter,
+    rewind_buffer: RewindBuffer,
+    pause_condition: Option<PauseCondition>,
+    breakpoints: Vec<Breakpoint>,
+    /// Address at which execution was resumed, so that a breakpoint there is not hit again
+    resume_address: Option<u16>,
+    tracer: Option<Tracer>,
+    movie: Option<MovieMode>,
+    /// A single step requested while the program waits for the vertical blank, executed with the
+    /// next timer tick
+    step_after_vblank: bool,
+    /// Keys sent while recording a movie, pressed with the start of the next frame
+    latched_keys: Option<HashSet<Key>>,
+    /// Symbols of the loaded program
+    symbols: SymbolMap,
 }
 
 impl Executor {
     /// Create a new executor capable of handling requests and sending responses. This function
     /// also initialized the sound system.
-    fn new(receiver: mpsc::Receiver<Request>, sender: mpsc::Sender<Response>) -> Self {
+    fn new(
+        receiver: mpsc::Receiver<Request>,
+        sender: mpsc::Sender<Response>,
+        clock: Box<dyn Clock>,
+    ) -> Self {
         let sound = Sound::new();
         if let Err(e) = &sound {
             warn!("failed to initialize sound: {}", e);
         }
         let sound = sound.ok();
+        let now = clock.now();
 
         Executor {
             receiver,
@@ -201,38 +423,238 @@ impl Executor {
             state: ProgramState::Stopped,
             sound,
             speed: Speed(DEFAULT_INSTRUCTIONS_PER_SECOND),
-            instruction_account_balance: 0,
+            clock,
+            schedule: Schedule::new(now),
+            stats: StatsMeter::new(now),
+            rewind_buffer: RewindBuffer::new(RewindSettings::default()),
+            pause_condition: None,
+            breakpoints: Vec::new(),
+            resume_address: None,
+            tracer: None,
+            movie: None,
+            step_after_vblank: false,
+            latched_keys: None,
+            symbols: SymbolMap::new(),
         }
     }
 
     /// Start running the executor. This function contains the core loop which waits for
-    /// requests from the client.
+    /// requests from the client until the next timer tick or instruction is due.
     fn start(&mut self) {
         loop {
-            let request = if let Ok(request) = self.receiver.recv() {
-                request
-            } else {
-                trace!("emulator exiting because request channel was closed");
-                break;
-            };
+            let timeout = self.schedule.time_until_next(self.clock.now());
+            match self.receiver.recv_timeout(timeout.max(MIN_WAIT)) {
+                Ok(request) => self.handle(request),
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    trace!("emulator exiting because request channel was closed");
+                    break;
+                }
+            }
 
-            self.handle(request);
+            self.run_due();
+            self.handle_sound();
+        }
+        trace!("emulator finished running");
+    }
 
-            while self.instruction_account_balance > 0 {
-                match self.emulator.step() {
-                    Ok(_) => {
-                        self.instruction_account_balance -= 1;
+    /// Handle the timer ticks and execute the instructions which are due by now.
+    fn run_due(&mut self) {
+        let now = self.clock.now();
+        while let Some(event) = self.schedule.next_due(now) {
+            match event {
+                Event::Tick => self.timer_tick(now),
+                Event::Instructions(count) => self.run_instructions(count),
+            }
+        }
+    }
+
+    /// Handle a timer tick, which ends the current frame and starts a new one.
+    fn timer_tick(&mut self, now: Duration) {
+        self.emulator.handle_timer_tick();
+        if self.state == ProgramState::Running {
+            self.rewind_buffer.handle_timer_tick(&self.emulator);
+            self.handle_movie_frame();
+        }
+        if std::mem::take(&mut self.step_after_vblank) {
+            self.handle(Request::Step);
+        }
+        self.stats.count_tick(now);
+        if let Some(Err(e)) = self.tracer.as_mut().map(Tracer::flush) {
+            warn!("cannot write trace, stopped tracing: {}", e);
+            self.tracer = None;
+        }
+    }
+
+    /// Execute up to `count` instructions, fewer if the program stops or waits for the vertical
+    /// blank.
+    fn run_instructions(&mut self, count: usize) {
+        for _ in 0..count {
+            if self.state != ProgramState::Running {
+                break;
+            }
+            if self.emulator.is_waiting_for_vblank() {
+                // Drop the remaining instructions, execution resumes with the next timer tick
+                self.schedule.skip_to_next_tick();
+                break;
+            }
+            let watch = match self.check_breakpoints() {
+                ControlFlow::Continue(watch) => watch,
+                ControlFlow::Break(()) => break,
+            };
+            match trace::traced_step(&mut self.emulator, &mut self.tracer) {
+                Ok(_) if self.emulator.has_exited() => {
+                    trace!("program exited");
+                    self.state = ProgramState::Stopped;
+                    self.schedule.stop_instructions();
+                }
+                Ok(_) => {
+                    self.stats.count_instructions(1);
+                    if let Some(watch) = watch {
+                        self.check_watchpoints(watch);
                     }
-                    Err(e) => {
-                        self.state = ProgramState::Stopped;
-                        self.instruction_account_balance = 0;
-                        let _ = self.sender.send(Response::RunError(e));
+                    if self
+                        .pause_condition
+                        .is_some_and(|c| c.is_reached(&self.emulator.registers()))
+                    {
+                        trace!("pausing at {:?}", self.pause_condition);
+                        self.pause();
                     }
                 }
+                Err(e) => {
+                    self.pause();
+                    let _ = self
+                        .sender
+                        .send(Response::RunError(e.locate(&self.symbols)));
+                }
             }
-            self.handle_sound();
         }
-        trace!("emulator finished running");
+    }
+
+    /// Check the breakpoints before executing the next instruction. Breaks if one is hit,
+    /// otherwise returns what is needed to check the watchpoints afterwards (if there are any).
+    fn check_breakpoints(&mut self) -> ControlFlow<(), Option<Watch>> {
+        let resume_address = self.resume_address.take();
+        if self.breakpoints.is_empty() {
+            return ControlFlow::Continue(None);
+        }
+
+        let registers = self.emulator.registers();
+        let code = self.emulator.read_memory(registers.program_counter, 4);
+        let opcode = match code[..] {
+            [high, low, ..] => u16::from_be_bytes([high, low]),
+            _ => 0,
+        };
+        if resume_address != Some(registers.program_counter) {
+            if let Some(breakpoint) = self
+                .breakpoints
+                .iter()
+                .find(|b| b.is_hit_before(&registers, opcode))
+            {
+                self.hit_breakpoint(breakpoint.clone(), registers);
+                return ControlFlow::Break(());
+            }
+        }
+
+        let written = Opcode::decode_bytes(&code)
+            .ok()
+            .and_then(|instruction| breakpoint::written_memory(&instruction, &registers));
+        ControlFlow::Continue(Some(Watch { registers, written }))
+    }
+
+    /// Check the watchpoints after executing an instruction.
+    fn check_watchpoints(&mut self, watch: Watch) {
+        let registers = self.emulator.registers();
+        if let Some(breakpoint) = self
+            .breakpoints
+            .iter()
+            .find(|b| b.is_hit_after(&watch.registers, &registers, watch.written))
+        {
+            self.hit_breakpoint(breakpoint.clone(), registers);
+        }
+    }
+
+    fn hit_breakpoint(&mut self, breakpoint: Breakpoint, registers: Registers) {
+        trace!("breakpoint hit: {}", breakpoint);
+        self.pause();
+        let _ = self
+            .sender
+            .send(Response::BreakpointHit(breakpoint, registers));
+    }
+
+    /// Stop running the program and drop the instructions which are due.
+    fn pause(&mut self) {
+        self.state = ProgramState::Stopped;
+        self.schedule.stop_instructions();
+        self.pause_condition = None;
+    }
+
+    /// Run until `condition` is reached.
+    fn run_until(&mut self, condition: PauseCondition) {
+        self.pause_condition = Some(condition);
+        self.resume();
+    }
+
+    /// Continue running the program, with the next instruction due now.
+    fn resume(&mut self) {
+        self.state = ProgramState::Running;
+        self.resume_address = Some(self.emulator.registers().program_counter);
+        self.restart_schedule();
+    }
+
+    /// Start a new frame now, with the first instruction due immediately if running. This way
+    /// the instructions of each frame do not depend on when the frame was started.
+    fn restart_schedule(&mut self) {
+        let now = self.clock.now();
+        self.schedule = Schedule::new(now);
+        if self.state == ProgramState::Running {
+            let Speed(instructions_per_second) = self.speed;
+            self.schedule
+                .start_instructions(instructions_per_second, now);
+        }
+    }
+
+    /// Reload the current program, e.g. to record or play a movie from the start.
+    fn restart_program(&mut self) {
+        let data = self.emulator.program_data().to_vec();
+        self.rewind_buffer.clear();
+        self.emulator.handle_keys(HashSet::new());
+        if let Err(e) = self.emulator.load_program(data) {
+            error!("cannot restart program: {}", e);
+        }
+    }
+
+    /// Record the keys of the frame starting now, or press the keys of the movie. Keys sent while
+    /// recording are pressed here, so that they are recorded for the frame in which they take
+    /// effect.
+    fn handle_movie_frame(&mut self) {
+        match &mut self.movie {
+            Some(MovieMode::Recording { movie, .. }) => {
+                if let Some(keys) = self.latched_keys.take() {
+                    self.emulator.handle_keys(keys);
+                }
+                movie.push_frame(self.emulator.keys());
+            }
+            Some(MovieMode::Playing { movie, frame }) if *frame < movie.frames() => {
+                if let Some(keys) = movie.keys_at(*frame) {
+                    self.emulator.handle_keys(keys.clone());
+                }
+                *frame += 1;
+            }
+            Some(MovieMode::Playing { .. }) => {
+                info!("movie finished");
+                self.movie = None;
+                self.pause();
+            }
+            None => {}
+        }
+    }
+
+    /// Start the first frame of a movie now, like the headless runner does.
+    fn start_movie(&mut self) {
+        self.latched_keys = None;
+        self.restart_schedule();
+        self.handle_movie_frame();
     }
 
     /// Dispatch and handle client requests.
@@ -241,19 +663,65 @@ impl Executor {
             Request::LoadSettings(settings) => {
                 self.emulator.load_settings(settings);
             }
-            Request::LoadProgram(data) => {
+            Request::SetSeed(seed) => {
+                self.emulator.set_seed(seed);
+            }
+            Request::ConfigureRewind(settings) => {
+                self.rewind_buffer.configure(settings);
+            }
+            Request::SetTracer(mut tracer) => {
+                tracer.set_symbols(self.symbols.clone());
+                self.tracer = Some(tracer);
+            }
+            Request::LoadProgram(data, symbols) => {
+                self.rewind_buffer.clear();
+                if let Some(tracer) = &mut self.tracer {
+                    tracer.set_symbols(symbols.clone());
+                }
+                self.symbols = symbols;
                 let result = self.emulator.load_program(data);
                 let _ = self.sender.send(Response::LoadProgram(result));
             }
+            Request::Step if self.emulator.is_waiting_for_vblank() => {
+                // The step would do nothing before the next timer tick
+                trace!("stepping with the next timer tick");
+                self.step_after_vblank = true;
+            }
             Request::Step => {
-                let result = self.emulator.step();
+                let result = trace::traced_step(&mut self.emulator, &mut self.tracer)
+                    .map_err(|e| e.locate(&self.symbols));
                 self.handle_sound();
                 let _ = self.sender.send(Response::Step(result));
             }
+            Request::StepOver => {
+                let registers = self.emulator.registers();
+                let code = self.emulator.read_memory(registers.program_counter, 4);
+                match Opcode::decode_bytes(&code) {
+                    Ok(Opcode::Call(_)) => self.run_until(PauseCondition::ReturnTo {
+                        address: registers.program_counter.wrapping_add(2),
+                        stack_depth: registers.stack.len(),
+                    }),
+                    _ => self.handle(Request::Step),
+                }
+            }
+            Request::StepOut => {
+                let stack_depth = self.emulator.registers().stack.len();
+                if stack_depth == 0 {
+                    warn!("cannot step out, not in a subroutine");
+                } else {
+                    self.run_until(PauseCondition::StackBelow(stack_depth));
+                }
+            }
             Request::RunProgram(speed) => {
                 self.speed = speed;
-                if self.state != ProgramState::Running {
-                    self.state = ProgramState::Running;
+                self.pause_condition = None;
+                if self.state == ProgramState::Running {
+                    // Continue at the new speed
+                    let Speed(instructions_per_second) = speed;
+                    self.schedule
+                        .start_instructions(instructions_per_second, self.clock.now());
+                } else {
+                    self.resume();
                 }
             }
             Request::Display => {
@@ -263,20 +731,94 @@ impl Executor {
             Request::State => {
                 let _ = self.sender.send(Response::State(self.state));
             }
-            Request::Stop => {
-                self.state = ProgramState::Stopped;
+            Request::DebugState => {
+                let registers = self.emulator.registers();
+                let code_address = registers
+                    .program_counter
+                    .saturating_sub(DEBUG_CODE_BEFORE_PC);
+                let debug_state = DebugState {
+                    state: self.state,
+                    code: self.emulator.read_memory(code_address, DEBUG_CODE_LENGTH),
+                    code_address,
+                    registers,
+                };
+                let _ = self.sender.send(Response::DebugState(debug_state));
+            }
+            Request::Memory => {
+                let memory = self.emulator.read_memory(0, MEMORY_SIZE);
+                let _ = self.sender.send(Response::Memory(memory));
+            }
+            Request::Stats => {
+                let _ = self.sender.send(Response::Stats(self.stats.stats()));
             }
-            Request::TimerTick => {
+            Request::WriteMemory(address, data) => {
                 if self.state == ProgramState::Running {
-                    let Speed(instructions_per_second) = self.speed;
-                    let tick_interval = TIMER_INTERVAL.as_secs_f32();
-                    let instructions_per_tick = instructions_per_second as f32 * tick_interval;
-                    self.instruction_account_balance = instructions_per_tick.ceil() as usize;
+                    warn!("cannot write memory while the program is running");
+                } else {
+                    self.emulator.write_memory(address, &data);
                 }
-                self.emulator.handle_timer_tick();
             }
-            Request::SendKeys(keys) => {
-                self.emulator.handle_keys(keys);
+            Request::WriteRegisters(registers) => {
+                if self.state == ProgramState::Running {
+                    warn!("cannot write registers while the program is running");
+                } else {
+                    self.emulator.load_registers(&registers);
+                }
+            }
+            Request::SetBreakpoint(breakpoint) => {
+                if !self.breakpoints.contains(&breakpoint) {
+                    self.breakpoints.push(breakpoint);
+                }
+            }
+            Request::ClearBreakpoint(breakpoint) => {
+                self.breakpoints.retain(|b| *b != breakpoint);
+            }
+            Request::Stop => {
+                self.pause();
+            }
+            Request::SendKeys(keys) => match self.movie {
+                Some(MovieMode::Recording { .. }) => self.latched_keys = Some(keys),
+                Some(MovieMode::Playing { .. }) => {}
+                None => self.emulator.handle_keys(keys),
+            },
+            Request::RecordMovie(path) => {
+                let movie = Movie::new(self.emulator.program_data(), self.emulator.seed());
+                self.restart_program();
+                self.movie = Some(MovieMode::Recording { movie, path });
+                self.start_movie();
+            }
+            Request::StopRecording => {
+                if let Some(MovieMode::Recording { movie, path }) = self.movie.take() {
+                    let _ = self.sender.send(Response::RecordMovie(movie.save(&path)));
+                }
+                if let Some(keys) = self.latched_keys.take() {
+                    self.emulator.handle_keys(keys);
+                }
+            }
+            Request::PlayMovie(movie) => {
+                self.emulator.set_seed(movie.seed());
+                self.restart_program();
+                self.movie = Some(MovieMode::Playing { movie, frame: 0 });
+                self.start_movie();
+            }
+            Request::SaveState(path) => {
+                let result = savestate::save(&self.emulator, &path);
+                let _ = self.sender.send(Response::SaveState(result));
+            }
+            Request::LoadState(path) => {
+                let result = savestate::load(&path).map(|processor| {
+                    self.emulator.restore(processor);
+                    self.rewind_buffer.clear();
+                    self.handle_sound();
+                });
+                let _ = self.sender.send(Response::LoadState(result));
+            }
+            Request::Rewind(frames) => {
+                if let Some(processor) = self.rewind_buffer.rewind(frames) {
+                    self.emulator.restore(processor);
+                    self.schedule.skip_to_next_tick();
+                    self.handle_sound();
+                }
             }
         }
     }
@@ -285,7 +827,7 @@ impl Executor {
     fn handle_sound(&mut self) {
         if let Some(sound) = &mut self.sound {
             if self.emulator.playing_sound() {
-                sound.play();
+                sound.play(self.emulator.audio());
             } else {
                 sound.pause();
             }
@@ -293,6 +835,45 @@ impl Executor {
     }
 }
 
+/// Condition for pausing a running program, used for stepping over and out of subroutines.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum PauseCondition {
+    /// Pause when the program counter reaches `address` with the given stack depth.
+    ReturnTo { address: u16, stack_depth: usize },
+    /// Pause when the stack depth drops below the given depth.
+    StackBelow(usize),
+}
+
+impl PauseCondition {
+    fn is_reached(&self, registers: &Registers) -> bool {
+        match *self {
+            PauseCondition::ReturnTo {
+                address,
+                stack_depth,
+            } => registers.program_counter == address && registers.stack.len() == stack_depth,
+            PauseCondition::StackBelow(stack_depth) => registers.stack.len() < stack_depth,
+        }
+    }
+}
+
+/// The registers before executing an instruction and the memory it writes, for checking the
+/// watchpoints afterwards.
+struct Watch {
+    registers: Registers,
+    written: Option<(u16, u16)>,
+}
+
+/// Snapshot of the processor for the debugger.
+#[derive(Debug, Clone)]
+pub struct DebugState {
+    pub state: ProgramState,
+    pub registers: Registers,
+    /// Address of the first byte in `code`
+    pub code_address: u16,
+    /// Memory around the program counter
+    pub code: Vec<u8>,
+}
+
 /// Program execution speed. Instructions per second.
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub struct Speed(usize);
@@ -309,3 +890,307 @@ pub enum ProgramState {
     Running,
     Stopped,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::headless::Runner;
+
+    /// Duration of a frame, rounded up so that one timer tick is due after each one.
+    const FRAME: Duration = Duration::from_nanos(16_666_667);
+
+    fn executor(sender: mpsc::Sender<Response>) -> (Executor, VirtualClock) {
+        let (_sender, receiver) = mpsc::channel();
+        let clock = VirtualClock::new();
+        let executor = Executor::new(receiver, sender, Box::new(clock.clone()));
+        (executor, clock)
+    }
+
+    /// Frames of the movie in the movie tests, the last one does not end with an instruction.
+    const MOVIE_FRAMES: usize = 40;
+    const MOVIE_SPEED: usize = 700;
+
+    /// Reads keys, random numbers and the delay timer, and waits for the vertical blank.
+    const MOVIE_PROGRAM: &str = "
+        : main
+          v1 := 5
+          loop
+            if v1 key then v2 += 1
+            v3 := random 0x3f
+            v5 += v3
+            i := hex v2
+            sprite v3 v4 5
+            vf := delay
+            if vf == 0 then delay := v3
+            v4 += 1
+          again
+    ";
+
+    /// Number of frames the movie has recorded or played.
+    fn movie_frame(executor: &Executor) -> usize {
+        match &executor.movie {
+            Some(MovieMode::Recording { movie, .. }) => movie.frames(),
+            Some(MovieMode::Playing { frame, .. }) => *frame,
+            None => 0,
+        }
+    }
+
+    /// Run event by event until `frames` frames of the movie are complete, sending the keys at
+    /// the given times after the start.
+    fn run_movie(
+        executor: &mut Executor,
+        clock: &VirtualClock,
+        frames: usize,
+        mut key_presses: &[(Duration, &[Key])],
+    ) {
+        let start = clock.now();
+        while movie_frame(executor) <= frames {
+            let wait = executor.schedule.time_until_next(clock.now());
+            if let Some(((time, keys), rest)) = key_presses.split_first() {
+                let until_press = (start + *time).saturating_sub(clock.now());
+                if until_press < wait {
+                    clock.advance(until_press);
+                    executor.handle(Request::SendKeys(keys.iter().copied().collect()));
+                    key_presses = rest;
+                    continue;
+                }
+            }
+            clock.advance(wait);
+            executor.run_due();
+        }
+    }
+
+    #[test]
+    fn step_over_and_out() {
+        let (sender, _receiver) = mpsc::channel();
+        let (mut executor, clock) = executor(sender);
+        // 0x200: call 0x206, 0x202: V1 += 1, 0x204: jump 0x204
+        // 0x206: V0 += 1, 0x208: V0 += 1, 0x20a: return
+        let program = vec![
+            0x22, 0x06, 0x71, 0x01, 0x12, 0x04, 0x70, 0x01, 0x70, 0x01, 0x00, 0xee,
+        ];
+        executor.handle(Request::LoadProgram(program.clone(), SymbolMap::new()));
+        let tick = |executor: &mut Executor| {
+            clock.advance(FRAME);
+            executor.run_due();
+        };
+
+        // Step over the call
+        executor.handle(Request::StepOver);
+        tick(&mut executor);
+        let registers = executor.emulator.registers();
+        assert_eq!(executor.state, ProgramState::Stopped);
+        assert_eq!(registers.program_counter, 0x202);
+        assert_eq!(registers.variable_registers[0], 2);
+
+        // Step into the subroutine again, then out of it
+        executor.handle(Request::LoadProgram(program, SymbolMap::new()));
+        executor.handle(Request::Step);
+        executor.handle(Request::StepOver);
+        assert_eq!(executor.emulator.registers().program_counter, 0x208);
+        executor.handle(Request::StepOut);
+        tick(&mut executor);
+        assert_eq!(executor.state, ProgramState::Stopped);
+        assert_eq!(executor.emulator.registers().program_counter, 0x202);
+    }
+
+    #[test]
+    fn breakpoints() {
+        let (sender, responses) = mpsc::channel();
+        let (mut executor, clock) = executor(sender);
+        // 0x200: V0 += 1, 0x202: V1 := 5, 0x204: jump 0x200
+        executor.handle(Request::LoadProgram(
+            vec![0x70, 0x01, 0x61, 0x05, 0x12, 0x00],
+            SymbolMap::new(),
+        ));
+        let breakpoint = Breakpoint::parse("0x202 if V0 == 3").unwrap();
+        executor.handle(Request::SetBreakpoint(breakpoint.clone()));
+        executor.handle(Request::RunProgram(Speed::new(600)));
+        let tick = |executor: &mut Executor| {
+            clock.advance(FRAME);
+            executor.run_due();
+            responses.try_iter().find_map(|response| match response {
+                Response::BreakpointHit(breakpoint, registers) => Some((breakpoint, registers)),
+                _ => None,
+            })
+        };
+
+        let (hit, registers) = tick(&mut executor).unwrap();
+        assert_eq!(hit, breakpoint);
+        assert_eq!(registers.program_counter, 0x202);
+        assert_eq!(registers.variable_registers[0], 3);
+        assert_eq!(executor.state, ProgramState::Stopped);
+
+        // Resuming does not hit the same breakpoint again, but the watchpoint
+        executor.handle(Request::ClearBreakpoint(breakpoint));
+        let watchpoint = Breakpoint::parse("change V0").unwrap();
+        executor.handle(Request::SetBreakpoint(watchpoint.clone()));
+        executor.handle(Request::RunProgram(Speed::new(600)));
+        let (hit, registers) = tick(&mut executor).unwrap();
+        assert_eq!(hit, watchpoint);
+        assert_eq!(registers.program_counter, 0x202);
+        assert_eq!(registers.variable_registers[0], 4);
+    }
+
+    #[test]
+    fn timing() {
+        let (sender, responses) = mpsc::channel();
+        let (mut executor, clock) = executor(sender);
+        // V0 += 1, 100 times, then jump 0x200
+        let mut program = [0x70, 0x01].repeat(100);
+        program.extend([0x12, 0x00]);
+        executor.handle(Request::LoadProgram(program, SymbolMap::new()));
+        executor.handle(Request::RunProgram(Speed::new(600)));
+        let executed = |executor: &Executor| executor.emulator.registers().variable_registers[0];
+
+        // One instruction every 1/600 s, starting immediately
+        executor.run_due();
+        assert_eq!(executed(&executor), 1);
+        clock.advance(Duration::from_micros(1600));
+        executor.run_due();
+        assert_eq!(executed(&executor), 1);
+        clock.advance(Duration::from_micros(100));
+        executor.run_due();
+        assert_eq!(executed(&executor), 2);
+        clock.advance(Duration::from_micros(6700));
+        executor.run_due();
+        assert_eq!(executed(&executor), 6);
+
+        // The measured speed matches the requested one, without rounding up per frame
+        for _ in 0..(2000 - 9) {
+            clock.advance(Duration::from_millis(1));
+            executor.run_due();
+        }
+        executor.handle(Request::Stats);
+        let stats = responses
+            .try_iter()
+            .find_map(|response| match response {
+                Response::Stats(stats) => Some(stats),
+                _ => None,
+            })
+            .unwrap();
+        assert!((stats.instructions_per_second - 600.0).abs() < 1.0);
+        assert!((stats.frames_per_second - 60.0).abs() < 1.0);
+        // Ticks are handled up to 1 ms late, as the clock is advanced in steps of 1 ms
+        assert!(stats.max_frame_time < Duration::from_millis(18));
+
+        // Paused, only the timer keeps ticking
+        executor.handle(Request::Stop);
+        let registers = executor.emulator.registers();
+        clock.advance(FRAME * 10);
+        executor.run_due();
+        assert_eq!(executor.emulator.registers(), registers);
+    }
+
+    #[test]
+    fn step_waits_for_vblank() {
+        let (sender, responses) = mpsc::channel();
+        let (mut executor, clock) = executor(sender);
+        executor.handle(Request::LoadSettings(InstructionSettings::from_platform(
+            Platform::CosmacVip,
+        )));
+        // 0x200: draw, 0x202: V0 += 1
+        executor.handle(Request::LoadProgram(
+            vec![0xd0, 0x01, 0x70, 0x01],
+            SymbolMap::new(),
+        ));
+        let steps = || {
+            responses
+                .try_iter()
+                .filter(|response| matches!(response, Response::Step(Ok(()))))
+                .count()
+        };
+
+        executor.handle(Request::Step);
+        executor.handle(Request::Step);
+        assert_eq!(steps(), 1);
+        assert_eq!(executor.emulator.registers().variable_registers[0], 0);
+
+        clock.advance(FRAME);
+        executor.run_due();
+        assert_eq!(steps(), 1);
+        assert_eq!(executor.emulator.registers().variable_registers[0], 1);
+    }
+
+    /// Record a movie of `MOVIE_PROGRAM`, with keys sent in the middle of frames. Returns the
+    /// recorder, stopped at the end of the last frame, and the movie.
+    fn record_movie(platform: Platform) -> (Executor, Movie) {
+        let assembly = crate::octo::compile(MOVIE_PROGRAM).unwrap();
+        let (sender, _responses) = mpsc::channel();
+        let (mut recorder, clock) = executor(sender);
+        recorder.handle(Request::LoadSettings(InstructionSettings::from_platform(
+            platform,
+        )));
+        recorder.handle(Request::SetSeed(7));
+        recorder.handle(Request::LoadProgram(assembly.rom, assembly.symbols));
+        recorder.handle(Request::RunProgram(Speed::new(MOVIE_SPEED)));
+        clock.advance(Duration::from_micros(5_300));
+        recorder.run_due();
+        recorder.handle(Request::RecordMovie(PathBuf::new()));
+        let key_presses: &[(Duration, &[Key])] = &[
+            (Duration::from_micros(21_300), &[Key::Num5]),
+            (Duration::from_micros(95_100), &[]),
+            (Duration::from_micros(130_700), &[Key::Num5, Key::A]),
+            (Duration::from_micros(402_900), &[]),
+        ];
+        run_movie(&mut recorder, &clock, MOVIE_FRAMES, key_presses);
+        let Some(MovieMode::Recording { movie, .. }) = recorder.movie.take() else {
+            panic!("not recording");
+        };
+        assert_ne!(recorder.emulator.registers().variable_registers[2], 0);
+        (recorder, movie)
+    }
+
+    /// Play `movie` of `MOVIE_PROGRAM` for `MOVIE_FRAMES` frames.
+    fn play_movie(movie: Movie, platform: Platform) -> Executor {
+        let assembly = crate::octo::compile(MOVIE_PROGRAM).unwrap();
+        let (sender, _responses) = mpsc::channel();
+        let (mut player, clock) = executor(sender);
+        player.handle(Request::LoadSettings(InstructionSettings::from_platform(
+            platform,
+        )));
+        player.handle(Request::LoadProgram(assembly.rom, assembly.symbols));
+        player.handle(Request::PlayMovie(movie));
+        player.handle(Request::RunProgram(Speed::new(MOVIE_SPEED)));
+        run_movie(&mut player, &clock, MOVIE_FRAMES, &[]);
+        player
+    }
+
+    #[test]
+    fn recording_reproduces_run() {
+        let (recorder, movie) = record_movie(Platform::CosmacVip);
+        let player = play_movie(movie, Platform::CosmacVip);
+
+        assert_eq!(player.emulator.registers(), recorder.emulator.registers());
+        assert_eq!(
+            player.emulator.display().content,
+            recorder.emulator.display().content
+        );
+    }
+
+    #[test]
+    fn movie_replays_headless() {
+        // With and without waiting for the vertical blank
+        for platform in [Platform::CosmacVip, Platform::XoChip] {
+            let (_, movie) = record_movie(platform);
+            let settings = InstructionSettings::from_platform(platform);
+            let mut runner = Runner::new(settings, MOVIE_SPEED);
+            runner.processor_mut().set_seed(movie.seed());
+            let assembly = crate::octo::compile(MOVIE_PROGRAM).unwrap();
+            runner.load_program(assembly.rom).unwrap();
+            while runner.frame() < MOVIE_FRAMES {
+                if let Some(keys) = movie.keys_at(runner.frame()) {
+                    runner.processor_mut().handle_keys(keys.clone());
+                }
+                runner.run_frame(None).unwrap();
+            }
+            let player = play_movie(movie, platform);
+
+            assert_eq!(runner.processor().registers(), player.emulator.registers());
+            assert_eq!(
+                runner.processor().display().content,
+                player.emulator.display().content
+            );
+        }
+    }
+}
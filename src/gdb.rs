@@ -0,0 +1,708 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use log::{info, trace, warn};
+use thiserror::Error;
+
+use crate::application::{
+    load_program_with_symbols, load_settings, Args, ProgramFileError, SettingsFileError,
+};
+use crate::breakpoint::{Breakpoint, BreakpointKind};
+use crate::emulator::{Emulator, ProgramState, Registers, Response, Speed};
+use crate::processor::{EmulatorError, NUM_VARIABLE_REGISTERS};
+
+/// Port registered for the GDB remote protocol.
+const DEFAULT_PORT: u16 = 2159;
+
+/// How long to wait for the emulator to answer a request.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often to check for an interrupt from GDB while the program is running.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Maximum packet size announced to GDB.
+const PACKET_SIZE: usize = 0x1000;
+
+/// Byte sent by GDB to interrupt the running program (Ctrl-C).
+const INTERRUPT: u8 = 0x03;
+
+/// The register file: V0 to VF (8 bit each), then I and PC (16 bit, little endian) and SP, the
+/// depth of the stack (8 bit).
+const INDEX_REGISTER: usize = NUM_VARIABLE_REGISTERS as usize;
+const PROGRAM_COUNTER: usize = INDEX_REGISTER + 1;
+const STACK_POINTER: usize = INDEX_REGISTER + 2;
+const NUM_REGISTERS: usize = STACK_POINTER + 1;
+
+/// Stop replies: stopped by a trap (step or breakpoint), by a software breakpoint (if GDB
+/// supports reporting them), by an interrupt, by an invalid instruction, or because the program
+/// exited.
+const STOPPED: &str = "S05";
+const SOFTWARE_BREAKPOINT: &str = "T05swbreak:;";
+const INTERRUPTED: &str = "S02";
+const FAILED: &str = "S04";
+const EXITED: &str = "W00";
+
+/// Command line arguments for the GDB server
+#[derive(clap::Args, Debug)]
+pub struct GdbArgs {
+    /// CHIP-8 ROM or Octo source file to debug
+    #[arg(value_name = "ROM_FILE")]
+    program_file: PathBuf,
+
+    /// Local TCP port to wait for GDB on
+    #[arg(short, long, default_value_t = DEFAULT_PORT)]
+    port: u16,
+}
+
+/// Load the program given on the command line and serve GDB connections, one at a time.
+pub fn run(args: &Args, gdb_args: &GdbArgs) -> Result<(), GdbError> {
+    let settings = load_settings(args.settings_file_path())?;
+    let program = load_program_with_symbols(&gdb_args.program_file, args.symbols_path())?;
+
+    let mut emulator = Emulator::new();
+    emulator.load_settings(settings.instruction_settings());
+    emulator.set_seed(args.seed(&settings));
+    emulator.load_program(program.rom, Some(program.symbols));
+    match emulator.wait_response(RESPONSE_TIMEOUT) {
+        Some(Response::LoadProgram(result)) => result?,
+        _ => return Err(GdbError::NotResponding),
+    }
+
+    let mut server = GdbServer::new(emulator, Speed::new(settings.instructions_per_second()));
+    let listener = TcpListener::bind(("127.0.0.1", gdb_args.port))?;
+    println!("Waiting for GDB on {}", listener.local_addr()?);
+    for stream in listener.incoming() {
+        let stream = stream?;
+        info!("GDB connected from {}", stream.peer_addr()?);
+        if let Err(e) = server.serve(stream) {
+            warn!("GDB session failed: {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// What to do after a packet was handled.
+enum Action {
+    Reply(String),
+    ReplyAndClose(String),
+    Close,
+}
+
+/// A server for the GDB remote serial protocol, controlling an emulator. Supports reading and
+/// writing registers and memory, single steps, continuing (interruptible with Ctrl-C), software
+/// breakpoints and write watchpoints.
+pub struct GdbServer {
+    emulator: Emulator,
+    speed: Speed,
+    /// Breakpoints set by GDB, removed when GDB detaches
+    breakpoints: Vec<Breakpoint>,
+    /// Whether GDB accepts stop replies for software breakpoints (`swbreak`)
+    swbreak: bool,
+}
+
+impl GdbServer {
+    pub fn new(emulator: Emulator, speed: Speed) -> Self {
+        GdbServer {
+            emulator,
+            speed,
+            breakpoints: Vec::new(),
+            swbreak: false,
+        }
+    }
+
+    /// Serve a GDB connection until GDB detaches or kills the program, or the connection is
+    /// closed. The program is stopped while GDB is connected.
+    pub fn serve(&mut self, stream: TcpStream) -> Result<(), GdbError> {
+        self.emulator.stop();
+        self.swbreak = false;
+        let mut connection = Connection::new(stream);
+        match self.session(&mut connection) {
+            Err(GdbError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                info!("GDB disconnected");
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    fn session(&mut self, connection: &mut Connection) -> Result<(), GdbError> {
+        loop {
+            let packet = connection.read_packet()?;
+            trace!("GDB packet: {}", packet);
+            match self.handle(&packet, connection)? {
+                Action::Reply(reply) => connection.write_packet(&reply)?,
+                Action::ReplyAndClose(reply) => {
+                    connection.write_packet(&reply)?;
+                    return Ok(());
+                }
+                Action::Close => return Ok(()),
+            }
+            if packet == "QStartNoAckMode" {
+                connection.no_ack = true;
+            }
+        }
+    }
+
+    /// Dispatch and handle a packet. Unsupported packets get an empty reply.
+    fn handle(&mut self, packet: &str, connection: &mut Connection) -> Result<Action, GdbError> {
+        let error = || Action::Reply("E01".to_string());
+        let ok = || Action::Reply("OK".to_string());
+
+        let (command, arguments) = if packet.is_char_boundary(1) {
+            packet.split_at(1)
+        } else {
+            (packet, "")
+        };
+        let action = match command {
+            "?" => Action::Reply(STOPPED.to_string()),
+            "g" => Action::Reply(to_hex(&register_file(&self.registers()?))),
+            "G" => {
+                let mut registers = self.registers()?;
+                match from_hex(arguments).and_then(|data| load_register_file(&mut registers, &data))
+                {
+                    Some(()) => {
+                        self.emulator.write_registers(registers);
+                        ok()
+                    }
+                    None => error(),
+                }
+            }
+            "p" => {
+                let registers = self.registers()?;
+                match usize::from_str_radix(arguments, 16)
+                    .ok()
+                    .and_then(|number| register_bytes(&registers, number))
+                {
+                    Some(bytes) => Action::Reply(to_hex(&bytes)),
+                    None => error(),
+                }
+            }
+            "P" => {
+                let mut registers = self.registers()?;
+                let written = arguments.split_once('=').and_then(|(number, value)| {
+                    let number = usize::from_str_radix(number, 16).ok()?;
+                    set_register(&mut registers, number, &from_hex(value)?)
+                });
+                match written {
+                    Some(()) => {
+                        self.emulator.write_registers(registers);
+                        ok()
+                    }
+                    None => error(),
+                }
+            }
+            "m" => match parse_range(arguments) {
+                Some((address, length)) => {
+                    let memory = self.memory()?;
+                    // Two hex digits per byte must fit into a packet
+                    let length = length.min(PACKET_SIZE / 2);
+                    let start = (address as usize).min(memory.len());
+                    let end = start.saturating_add(length).min(memory.len());
+                    Action::Reply(to_hex(&memory[start..end]))
+                }
+                None => error(),
+            },
+            "M" => {
+                let data = arguments.split_once(':').and_then(|(range, data)| {
+                    let (address, length) = parse_range(range)?;
+                    let data = from_hex(data)?;
+                    (data.len() == length).then_some((address, data))
+                });
+                match data {
+                    Some((address, data)) => {
+                        self.emulator.write_memory(address, data);
+                        ok()
+                    }
+                    None => error(),
+                }
+            }
+            "s" => {
+                self.emulator.step();
+                let result = self.wait_for(|response| match response {
+                    Response::Step(result) => Some(result),
+                    _ => None,
+                })?;
+                match result {
+                    Ok(()) => Action::Reply(STOPPED.to_string()),
+                    Err(e) => {
+                        warn!("program failed: {}", e);
+                        Action::Reply(FAILED.to_string())
+                    }
+                }
+            }
+            "c" => Action::Reply(self.resume(connection)?.to_string()),
+            "Z" | "z" => match parse_breakpoint(arguments) {
+                Some(Some(breakpoint)) if command == "Z" => {
+                    self.emulator.set_breakpoint(breakpoint.clone());
+                    self.breakpoints.push(breakpoint);
+                    ok()
+                }
+                Some(Some(breakpoint)) => {
+                    self.emulator.clear_breakpoint(breakpoint.clone());
+                    self.breakpoints.retain(|b| *b != breakpoint);
+                    ok()
+                }
+                Some(None) => Action::Reply(String::new()),
+                None => error(),
+            },
+            "H" => ok(),
+            "k" => Action::Close,
+            "D" => {
+                for breakpoint in self.breakpoints.drain(..) {
+                    self.emulator.clear_breakpoint(breakpoint);
+                }
+                self.emulator.run_program(self.speed);
+                Action::ReplyAndClose("OK".to_string())
+            }
+            _ => Action::Reply(self.handle_query(packet)),
+        };
+        Ok(action)
+    }
+
+    /// Handle general query and set packets.
+    fn handle_query(&mut self, packet: &str) -> String {
+        if let Some(features) = packet.strip_prefix("qSupported") {
+            self.swbreak = features
+                .trim_start_matches(':')
+                .split(';')
+                .any(|feature| feature == "swbreak+");
+            format!(
+                "PacketSize={:x};qXfer:features:read+;swbreak+;QStartNoAckMode+",
+                PACKET_SIZE
+            )
+        } else if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            match parse_range(range) {
+                Some((offset, length)) => {
+                    let xml = target_xml();
+                    // Leave room for the marker
+                    let length = length.min(PACKET_SIZE - 1);
+                    let start = (offset as usize).min(xml.len());
+                    let end = start.saturating_add(length).min(xml.len());
+                    let marker = if end < xml.len() { 'm' } else { 'l' };
+                    format!("{}{}", marker, &xml[start..end])
+                }
+                None => "E01".to_string(),
+            }
+        } else if packet == "qAttached" {
+            "1".to_string()
+        } else if packet == "QStartNoAckMode" {
+            "OK".to_string()
+        } else {
+            String::new()
+        }
+    }
+
+    /// Run the program until it stops (breakpoint, error or exit) or GDB interrupts it. Returns
+    /// the stop reply.
+    fn resume(&mut self, connection: &mut Connection) -> Result<&'static str, GdbError> {
+        self.emulator.run_program(self.speed);
+        connection.stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        let result = self.wait_for_stop(connection);
+        connection.stream.set_read_timeout(None)?;
+        result
+    }
+
+    fn wait_for_stop(&mut self, connection: &mut Connection) -> Result<&'static str, GdbError> {
+        loop {
+            if connection.poll_byte()? == Some(INTERRUPT) {
+                self.emulator.stop();
+                return Ok(INTERRUPTED);
+            }
+            self.emulator.query_state();
+            loop {
+                let response = self
+                    .emulator
+                    .wait_response(RESPONSE_TIMEOUT)
+                    .ok_or(GdbError::NotResponding)?;
+                match response {
+                    Response::BreakpointHit(breakpoint, _) => {
+                        let software =
+                            matches!(breakpoint.kind, BreakpointKind::Address(_)) && self.swbreak;
+                        return Ok(if software {
+                            SOFTWARE_BREAKPOINT
+                        } else {
+                            STOPPED
+                        });
+                    }
+                    Response::RunError(e) => {
+                        warn!("program failed: {}", e);
+                        return Ok(FAILED);
+                    }
+                    // Stopped without a breakpoint or an error, so the program exited
+                    Response::State(ProgramState::Stopped) => return Ok(EXITED),
+                    Response::State(ProgramState::Running) => break,
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn registers(&mut self) -> Result<Registers, GdbError> {
+        self.emulator.query_debug_state();
+        self.wait_for(|response| match response {
+            Response::DebugState(debug_state) => Some(debug_state.registers),
+            _ => None,
+        })
+    }
+
+    fn memory(&mut self) -> Result<Vec<u8>, GdbError> {
+        self.emulator.query_memory();
+        self.wait_for(|response| match response {
+            Response::Memory(memory) => Some(memory),
+            _ => None,
+        })
+    }
+
+    /// Wait for the response picked by `extract`, dropping all other responses.
+    fn wait_for<T>(&mut self, extract: impl Fn(Response) -> Option<T>) -> Result<T, GdbError> {
+        loop {
+            let response = self
+                .emulator
+                .wait_response(RESPONSE_TIMEOUT)
+                .ok_or(GdbError::NotResponding)?;
+            if let Some(value) = extract(response) {
+                return Ok(value);
+            }
+        }
+    }
+}
+
+/// The packet layer of the protocol: `$data#checksum`, acknowledged with `+` (or `-` to request
+/// a retransmission) unless GDB switched to no-ack mode.
+struct Connection {
+    stream: TcpStream,
+    no_ack: bool,
+    last_packet: Vec<u8>,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> Self {
+        Connection {
+            stream,
+            no_ack: false,
+            last_packet: Vec::new(),
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let mut byte = [0];
+        match self.stream.read(&mut byte)? {
+            0 => Err(io::ErrorKind::UnexpectedEof.into()),
+            _ => Ok(byte[0]),
+        }
+    }
+
+    /// Read a byte if one arrives within the read timeout.
+    fn poll_byte(&mut self) -> io::Result<Option<u8>> {
+        match self.read_byte() {
+            Ok(byte) => Ok(Some(byte)),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Read the next packet with a valid checksum.
+    fn read_packet(&mut self) -> io::Result<String> {
+        loop {
+            match self.read_byte()? {
+                b'$' => {}
+                b'-' => {
+                    let packet = self.last_packet.clone();
+                    self.stream.write_all(&packet)?;
+                    continue;
+                }
+                // Acknowledgements and interrupts while already stopped
+                _ => continue,
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    byte => data.push(byte),
+                }
+            }
+            let checksum = [self.read_byte()?, self.read_byte()?];
+            let valid = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|checksum| u8::from_str_radix(checksum, 16).ok())
+                == Some(self::checksum(&data));
+            if !self.no_ack {
+                self.stream.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(String::from_utf8_lossy(&data).into_owned());
+            }
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        self.last_packet = packet.into_bytes();
+        self.stream.write_all(&self.last_packet)
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 == 1 {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Parse `address,length` (both hexadecimal).
+fn parse_range(text: &str) -> Option<(u16, usize)> {
+    let (address, length) = text.split_once(',')?;
+    Some((
+        u16::from_str_radix(address, 16).ok()?,
+        usize::from_str_radix(length, 16).ok()?,
+    ))
+}
+
+/// Parse the arguments of a `Z` or `z` packet, `type,address,kind`. Software and hardware
+/// breakpoints map to address breakpoints, write watchpoints to memory write breakpoints. Read
+/// and access watchpoints are not supported (`Some(None)`).
+fn parse_breakpoint(text: &str) -> Option<Option<Breakpoint>> {
+    let (kind, range) = text.split_once(',')?;
+    // Conditions and commands evaluated by the target are not supported
+    let range = range.split(';').next()?;
+    let (address, length) = parse_range(range)?;
+    let kind = match kind {
+        "0" | "1" => BreakpointKind::Address(address),
+        "2" => BreakpointKind::MemoryWrite {
+            start: address,
+            end: address.saturating_add((length as u16).saturating_sub(1)),
+        },
+        _ => return Some(None),
+    };
+    Some(Some(Breakpoint::new(kind)))
+}
+
+fn register_size(number: usize) -> usize {
+    match number {
+        INDEX_REGISTER | PROGRAM_COUNTER => 2,
+        _ => 1,
+    }
+}
+
+fn register_bytes(registers: &Registers, number: usize) -> Option<Vec<u8>> {
+    match number {
+        INDEX_REGISTER => Some(registers.index_register.to_le_bytes().to_vec()),
+        PROGRAM_COUNTER => Some(registers.program_counter.to_le_bytes().to_vec()),
+        STACK_POINTER => Some(vec![registers.stack.len() as u8]),
+        _ => registers
+            .variable_registers
+            .get(number)
+            .map(|&value| vec![value]),
+    }
+}
+
+/// Set a register from its bytes. Setting SP drops entries from the stack or pushes zeros.
+fn set_register(registers: &mut Registers, number: usize, bytes: &[u8]) -> Option<()> {
+    match (number, bytes) {
+        (INDEX_REGISTER, &[low, high]) => {
+            registers.index_register = u16::from_le_bytes([low, high])
+        }
+        (PROGRAM_COUNTER, &[low, high]) => {
+            registers.program_counter = u16::from_le_bytes([low, high])
+        }
+        (STACK_POINTER, &[depth]) => registers.stack.resize(depth as usize, 0),
+        (_, &[value]) => *registers.variable_registers.get_mut(number)? = value,
+        _ => return None,
+    }
+    Some(())
+}
+
+/// All registers in the order of their numbers, as sent by `g`.
+fn register_file(registers: &Registers) -> Vec<u8> {
+    (0..NUM_REGISTERS)
+        .flat_map(|number| register_bytes(registers, number).unwrap_or_default())
+        .collect()
+}
+
+/// Set all registers from the data sent with `G`.
+fn load_register_file(registers: &mut Registers, data: &[u8]) -> Option<()> {
+    let mut offset = 0;
+    for number in 0..NUM_REGISTERS {
+        let size = register_size(number);
+        set_register(registers, number, data.get(offset..offset + size)?)?;
+        offset += size;
+    }
+    (offset == data.len()).then_some(())
+}
+
+/// Target description telling GDB the names and sizes of the registers.
+fn target_xml() -> String {
+    let mut registers: Vec<String> = (0..NUM_VARIABLE_REGISTERS)
+        .map(|n| format!(r#"<reg name="v{:x}" bitsize="8"/>"#, n))
+        .collect();
+    registers.push(r#"<reg name="i" bitsize="16" type="data_ptr"/>"#.to_string());
+    registers.push(r#"<reg name="pc" bitsize="16" type="code_ptr"/>"#.to_string());
+    registers.push(r#"<reg name="sp" bitsize="8"/>"#.to_string());
+    format!(
+        r#"<?xml version="1.0"?><!DOCTYPE target SYSTEM "gdb-target.dtd"><target version="1.0"><feature name="org.jade.chip8">{}</feature></target>"#,
+        registers.concat()
+    )
+}
+
+#[derive(Error, Debug)]
+pub enum GdbError {
+    #[error(transparent)]
+    Settings(#[from] SettingsFileError),
+
+    #[error(transparent)]
+    ProgramFile(#[from] ProgramFileError),
+
+    #[error(transparent)]
+    Emulator(#[from] EmulatorError),
+
+    #[error("connection to GDB failed: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("emulator is not responding")]
+    NotResponding,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A scripted GDB client.
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        fn send_packet(&mut self, data: &str) {
+            let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+            self.stream.write_all(packet.as_bytes()).unwrap();
+            let mut ack = [0];
+            self.stream.read_exact(&mut ack).unwrap();
+            assert_eq!(ack[0], b'+');
+        }
+
+        fn read_reply(&mut self) -> String {
+            let mut reply = Vec::new();
+            let mut byte = [0];
+            self.stream.read_exact(&mut byte).unwrap();
+            assert_eq!(byte[0], b'$');
+            loop {
+                self.stream.read_exact(&mut byte).unwrap();
+                if byte[0] == b'#' {
+                    break;
+                }
+                reply.push(byte[0]);
+            }
+            let mut checksum = [0; 2];
+            self.stream.read_exact(&mut checksum).unwrap();
+            self.stream.write_all(b"+").unwrap();
+            String::from_utf8(reply).unwrap()
+        }
+
+        fn send(&mut self, data: &str) -> String {
+            self.send_packet(data);
+            self.read_reply()
+        }
+    }
+
+    #[test]
+    fn scripted_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let emulator = Emulator::new();
+            // v0 := 5; v1 := 7; v0 += v1; loop: jump loop
+            emulator.load_program(vec![0x60, 0x05, 0x61, 0x07, 0x80, 0x14, 0x12, 0x06], None);
+            let mut server = GdbServer::new(emulator, Speed::new(600));
+            let (stream, _) = listener.accept().unwrap();
+            server.serve(stream).unwrap();
+        });
+
+        let stream = TcpStream::connect(address).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut client = Client { stream };
+
+        assert!(client
+            .send("qSupported:swbreak+")
+            .contains("PacketSize=1000"));
+        assert!(client
+            .send("qXfer:features:read:target.xml:0,1000")
+            .starts_with("l<?xml"));
+        assert_eq!(client.send("?"), "S05");
+        let registers = client.send("g");
+        assert_eq!(registers, "00".repeat(16) + "0000" + "0002" + "00");
+        assert_eq!(client.send("m200,4"), "60056107");
+
+        assert_eq!(client.send("s"), "S05");
+        assert_eq!(client.send("p0"), "05");
+        assert_eq!(client.send("Z0,206,2"), "OK");
+        assert_eq!(client.send("c"), "T05swbreak:;");
+        assert_eq!(client.send("p11"), "0602");
+        assert_eq!(client.send("p0"), "0c");
+        assert_eq!(client.send("z0,206,2"), "OK");
+
+        assert_eq!(client.send("M300,2:abcd"), "OK");
+        assert_eq!(client.send("m300,2"), "abcd");
+        // Lengths are limited to the packet size
+        assert_eq!(client.send("m0,ffffffffffffffff").len(), PACKET_SIZE);
+        assert_eq!(client.send("mfffe,10"), "0000");
+        assert!(client
+            .send("qXfer:features:read:target.xml:10,ffffffffffffffff")
+            .starts_with('l'));
+        assert_eq!(client.send("P10=0003"), "OK");
+        assert_eq!(client.send("p10"), "0003");
+        assert_eq!(client.send("vMustReplyEmpty"), "");
+
+        client.send_packet("c");
+        std::thread::sleep(Duration::from_millis(50));
+        client.stream.write_all(&[INTERRUPT]).unwrap();
+        assert_eq!(client.read_reply(), "S02");
+        assert_eq!(client.send("p11"), "0602");
+
+        client.send_packet("k");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn register_file_round_trip() {
+        let mut registers = Registers {
+            index_register: 0x0304,
+            program_counter: 0x0250,
+            stack: vec![0x0202, 0x0210],
+            ..Default::default()
+        };
+        registers.variable_registers[0xf] = 1;
+        let data = register_file(&registers);
+        assert_eq!(data.len(), 16 + 2 + 2 + 1);
+
+        let mut loaded = Registers::default();
+        load_register_file(&mut loaded, &data).unwrap();
+        assert_eq!(loaded.variable_registers, registers.variable_registers);
+        assert_eq!(loaded.index_register, 0x0304);
+        assert_eq!(loaded.program_counter, 0x0250);
+        assert_eq!(loaded.stack.len(), 2);
+        assert_eq!(load_register_file(&mut loaded, &data[1..]), None);
+    }
+}
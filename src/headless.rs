@@ -0,0 +1,484 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+
+use log::info;
+use thiserror::Error;
+
+use crate::application::{
+    load_program_with_symbols, load_settings, Args, ProgramFileError, SettingsFileError,
+};
+use crate::clock::{Clock, Event, Schedule, VirtualClock};
+use crate::emulator::DEFAULT_INSTRUCTIONS_PER_SECOND;
+use crate::movie::{Movie, MovieError};
+use crate::processor::{Display, EmulatorError, InstructionSettings, Key, Processor};
+use crate::symbols::SymbolMap;
+use crate::trace::{self, Tracer};
+
+/// Command line arguments for running without a window, e.g. for automated tests
+#[derive(clap::Args, Debug, Default)]
+pub struct HeadlessArgs {
+    /// Run without a window
+    #[arg(long)]
+    pub headless: bool,
+
+    /// Number of frames (timer ticks) to run in headless mode
+    #[arg(long, value_name = "N", default_value_t = 600)]
+    pub frames: usize,
+
+    /// Stop in headless mode when the program counter reaches this address (e.g. 0x2a4)
+    #[arg(long, value_name = "ADDR", value_parser = parse_address)]
+    pub until_pc: Option<u16>,
+
+    /// Key script for headless mode, lines of "<frame> <pressed keys>", e.g. "30 5 a"
+    #[arg(long, value_name = "FILE")]
+    pub keys: Option<PathBuf>,
+
+    /// Write the final display to this file, as PNG for *.png, as ASCII otherwise
+    #[arg(long, value_name = "FILE")]
+    pub dump_display: Option<PathBuf>,
+
+    /// Write the final registers as JSON to this file
+    #[arg(long, value_name = "FILE")]
+    pub dump_registers: Option<PathBuf>,
+}
+
+/// Parse an address given in hexadecimal (with or without 0x prefix).
+pub fn parse_address(text: &str) -> Result<u16, String> {
+    let digits = text.trim_start_matches("0x").trim_start_matches("0X");
+    u16::from_str_radix(digits, 16).map_err(|e| format!("invalid address '{}': {}", text, e))
+}
+
+/// Run the program given on the command line without a window and dump the results.
+pub fn run(args: &Args) -> Result<(), HeadlessError> {
+    let headless_args = args.headless_args();
+    let settings = load_settings(args.settings_file_path())?;
+    let program = load_program_with_symbols(args.program_file(), args.symbols_path())?;
+    let program_data = program.rom;
+    let movie = match args.play_movie_path() {
+        Some(path) => Some(Movie::load(path)?),
+        None => None,
+    };
+    if let Some(movie) = &movie {
+        movie.check_rom(&program_data)?;
+    }
+    let key_script = match (&movie, &headless_args.keys) {
+        (Some(movie), _) => movie.key_script().clone(),
+        (None, Some(path)) => KeyScript::parse(&std::fs::read_to_string(path)?)?,
+        (None, None) => KeyScript::default(),
+    };
+    let seed = match &movie {
+        Some(movie) => movie.seed(),
+        None => args.seed(&settings),
+    };
+    let frames = movie.as_ref().map_or(headless_args.frames, Movie::frames);
+    let mut recording = args
+        .record_movie_path()
+        .map(|path| (Movie::new(&program_data, seed), path));
+
+    let mut runner = Runner::new(
+        settings.instruction_settings(),
+        settings.instructions_per_second(),
+    );
+    runner.processor_mut().set_seed(seed);
+    runner.load_program(program_data)?;
+    runner.set_symbols(program.symbols);
+    if let Some(tracer) = args.trace_args().tracer()? {
+        runner.set_tracer(tracer);
+    }
+
+    while runner.frame() < frames {
+        if let Some(keys) = key_script.keys_at(runner.frame()) {
+            runner.processor_mut().handle_keys(keys.clone());
+        }
+        if let Some((movie, _)) = &mut recording {
+            movie.push_frame(runner.processor().keys());
+        }
+        if let Some(reason) = runner.run_frame(headless_args.until_pc)? {
+            info!("stopped after {} frames: {:?}", runner.frame(), reason);
+            break;
+        }
+    }
+    if let Some((movie, path)) = recording {
+        movie.save(path)?;
+    }
+    runner.flush_trace()?;
+
+    let display = runner.processor().display();
+    match &headless_args.dump_display {
+        Some(path)
+            if path
+                .extension()
+                .is_some_and(|e| e.eq_ignore_ascii_case("png")) =>
+        {
+            write_png(&display, path)?
+        }
+        Some(path) => std::fs::write(path, display_to_ascii(&display))?,
+        None if headless_args.dump_registers.is_none() => print!("{}", display_to_ascii(&display)),
+        None => {}
+    }
+    if let Some(path) = &headless_args.dump_registers {
+        let file = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(file, &runner.processor().registers())?;
+    }
+
+    Ok(())
+}
+
+/// Runs a processor frame by frame without threads and timers, so that runs are reproducible.
+/// Instructions are scheduled as in the emulator, on a virtual clock advancing by one timer tick
+/// per frame, so that both execute the same instructions in each frame.
+pub struct Runner {
+    processor: Processor,
+    instructions_per_second: usize,
+    clock: VirtualClock,
+    schedule: Schedule,
+    /// Instructions of the current frame which are due but not executed yet
+    due: usize,
+    frame: usize,
+    tracer: Option<Tracer>,
+    symbols: SymbolMap,
+}
+
+impl Runner {
+    /// Create a runner executing `instructions_per_second` (or a default if zero) instructions
+    /// per second of emulated time.
+    pub fn new(settings: InstructionSettings, instructions_per_second: usize) -> Self {
+        let instructions_per_second = match instructions_per_second {
+            0 => DEFAULT_INSTRUCTIONS_PER_SECOND,
+            ips => ips,
+        };
+        let mut processor = Processor::new();
+        processor.load_settings(settings);
+        let clock = VirtualClock::new();
+        let schedule = Schedule::new(clock.now());
+
+        let mut runner = Runner {
+            processor,
+            instructions_per_second,
+            clock,
+            schedule,
+            due: 0,
+            frame: 0,
+            tracer: None,
+            symbols: SymbolMap::new(),
+        };
+        runner.restart_schedule();
+        runner
+    }
+
+    /// Load a program and start over at frame 0.
+    pub fn load_program(&mut self, data: Vec<u8>) -> Result<(), EmulatorError> {
+        self.frame = 0;
+        self.restart_schedule();
+        self.processor.load_program(data)
+    }
+
+    /// Start a new frame now, with the first instruction due immediately.
+    fn restart_schedule(&mut self) {
+        let now = self.clock.now();
+        self.schedule = Schedule::new(now);
+        self.schedule
+            .start_instructions(self.instructions_per_second, now);
+        self.due = 0;
+    }
+
+    pub fn processor(&self) -> &Processor {
+        &self.processor
+    }
+
+    pub fn processor_mut(&mut self) -> &mut Processor {
+        &mut self.processor
+    }
+
+    /// Record each executed instruction with `tracer`.
+    pub fn set_tracer(&mut self, mut tracer: Tracer) {
+        tracer.set_symbols(self.symbols.clone());
+        self.tracer = Some(tracer);
+    }
+
+    /// Use the symbols of the program in error messages and traces.
+    pub fn set_symbols(&mut self, symbols: SymbolMap) {
+        if let Some(tracer) = &mut self.tracer {
+            tracer.set_symbols(symbols.clone());
+        }
+        self.symbols = symbols;
+    }
+
+    /// Write the buffered part of the trace, if tracing.
+    pub fn flush_trace(&mut self) -> io::Result<()> {
+        match &mut self.tracer {
+            Some(tracer) => tracer.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Number of frames run so far.
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    /// Execute the instructions of one frame, followed by a timer tick. Stops early if the
+    /// program counter reaches `until_pc` or the program exits.
+    pub fn run_frame(
+        &mut self,
+        until_pc: Option<u16>,
+    ) -> Result<Option<StopReason>, EmulatorError> {
+        let frame = self.frame;
+        while self.frame == frame {
+            if self.due == 0 {
+                self.next_event();
+                continue;
+            }
+            if until_pc == Some(self.processor.registers().program_counter) {
+                return Ok(Some(StopReason::ReachedAddress));
+            }
+            self.execute()?;
+            if self.processor.has_exited() {
+                return Ok(Some(StopReason::Exited));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Execute a single instruction. The timer tick follows the last instruction of a frame, or
+    /// comes right away while the program waits for the vertical blank.
+    pub fn step(&mut self) -> Result<(), EmulatorError> {
+        while self.due == 0 {
+            self.next_event();
+        }
+        self.execute()
+    }
+
+    /// Execute one of the instructions due, then handle the tick if it is next.
+    fn execute(&mut self) -> Result<(), EmulatorError> {
+        trace::traced_step(&mut self.processor, &mut self.tracer)
+            .map_err(|e| e.locate(&self.symbols))?;
+        self.due -= 1;
+        if self.processor.is_waiting_for_vblank() {
+            // Drop the remaining instructions, execution resumes with the next timer tick
+            self.schedule.skip_to_next_tick();
+            self.due = 0;
+        }
+        if self.due == 0 {
+            self.next_event();
+        }
+        Ok(())
+    }
+
+    /// Take the next event of the schedule, advancing the clock to the next tick if nothing is
+    /// due. A tick ends the current frame.
+    fn next_event(&mut self) {
+        let event = loop {
+            let now = self.clock.now();
+            if let Some(event) = self.schedule.next_due(now) {
+                break event;
+            }
+            self.clock.advance(self.schedule.time_until_next_tick(now));
+        };
+        match event {
+            Event::Instructions(count) => self.due = count,
+            Event::Tick => {
+                self.processor.handle_timer_tick();
+                self.frame += 1;
+            }
+        }
+    }
+}
+
+/// Why a headless run stopped before the requested number of frames.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    ReachedAddress,
+    Exited,
+}
+
+/// The keys pressed over time. Each line of a key script consists of the frame number followed by
+/// the keys (hexadecimal digits) pressed from that frame on. Lines without keys release all keys,
+/// `#` starts a comment.
+#[derive(Clone, Debug, Default)]
+pub struct KeyScript {
+    events: Vec<(usize, HashSet<Key>)>,
+}
+
+impl KeyScript {
+    pub fn parse(text: &str) -> Result<Self, HeadlessError> {
+        let mut events = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let error = |message: String| HeadlessError::KeyScript {
+                line: line_number,
+                message,
+            };
+            let line = line.split('#').next().unwrap_or_default();
+            let mut words = line.split_whitespace();
+            let Some(frame) = words.next() else {
+                continue;
+            };
+            let frame = frame
+                .parse::<usize>()
+                .map_err(|e| error(format!("invalid frame '{}': {}", frame, e)))?;
+            let keys = words
+                .map(|word| {
+                    u8::from_str_radix(word, 16)
+                        .ok()
+                        .and_then(|value| Key::try_from(value).ok())
+                        .ok_or_else(|| error(format!("invalid key '{}'", word)))
+                })
+                .collect::<Result<HashSet<_>, _>>()?;
+            events.push((frame, keys));
+        }
+        events.sort_by_key(|(frame, _)| *frame);
+        Ok(KeyScript { events })
+    }
+
+    /// The keys pressed from `frame` on, if they change in this frame.
+    pub fn keys_at(&self, frame: usize) -> Option<&HashSet<Key>> {
+        self.events
+            .iter()
+            .rfind(|(f, _)| *f == frame)
+            .map(|(_, keys)| keys)
+    }
+
+    /// The keys pressed after the last event, none if there are no events.
+    pub fn last_keys(&self) -> HashSet<Key> {
+        self.events
+            .last()
+            .map(|(_, keys)| keys.clone())
+            .unwrap_or_default()
+    }
+
+    /// Add an event after the existing ones.
+    pub fn push(&mut self, frame: usize, keys: HashSet<Key>) {
+        self.events.push((frame, keys));
+    }
+}
+
+impl fmt::Display for KeyScript {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (frame, keys) in &self.events {
+            let mut keys: Vec<Key> = keys.iter().copied().collect();
+            keys.sort();
+            write!(f, "{}", frame)?;
+            for key in keys {
+                write!(f, " {:x}", key as u8)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Render the display as text, one line per row. Empty pixels are shown as `.`, pixels of the
+/// first plane as `#`, pixels of the second plane as `+` and pixels of both planes as `@`.
+pub fn display_to_ascii(display: &Display) -> String {
+    const CHARS: [char; 4] = ['.', '#', '+', '@'];
+    let mut text = String::with_capacity((display.width as usize + 1) * display.height as usize);
+    for y in 0..display.height {
+        for x in 0..display.width {
+            text.push(CHARS[display.get(x, y) as usize & 0b11]);
+        }
+        text.push('\n');
+    }
+    text
+}
+
+/// Write the display as an image with one pixel per CHIP-8 pixel.
+pub fn write_png(display: &Display, path: &Path) -> Result<(), HeadlessError> {
+    const PALETTE: [[u8; 3]; 4] = [
+        [0x00, 0x00, 0x00],
+        [0x80, 0x80, 0x80],
+        [0x3c, 0x8d, 0x6e],
+        [0xc8, 0xe6, 0xd8],
+    ];
+    let file = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(file, display.width as u32, display.height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    let data: Vec<u8> = (0..display.height)
+        .flat_map(|y| (0..display.width).map(move |x| (x, y)))
+        .flat_map(|(x, y)| PALETTE[display.get(x, y) as usize & 0b11])
+        .collect();
+    writer.write_image_data(&data)?;
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum HeadlessError {
+    #[error(transparent)]
+    Settings(#[from] SettingsFileError),
+
+    #[error(transparent)]
+    ProgramFile(#[from] ProgramFileError),
+
+    #[error("key script, line {line}: {message}")]
+    KeyScript { line: usize, message: String },
+
+    #[error(transparent)]
+    Emulator(#[from] EmulatorError),
+
+    #[error(transparent)]
+    Movie(#[from] MovieError),
+
+    #[error("cannot write output: {0}")]
+    Write(#[from] io::Error),
+
+    #[error("cannot write PNG: {0}")]
+    Png(#[from] png::EncodingError),
+
+    #[error("cannot write JSON: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_script() {
+        let script = KeyScript::parse("# comment\n10 1 a\n\n20\n").unwrap();
+        assert_eq!(script.keys_at(0), None);
+        assert_eq!(
+            script.keys_at(10),
+            Some(&HashSet::from([Key::Num1, Key::A]))
+        );
+        assert_eq!(script.keys_at(20), Some(&HashSet::new()));
+
+        assert!(matches!(
+            KeyScript::parse("5 x"),
+            Err(HeadlessError::KeyScript { line: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn run_until_address() {
+        // I := font "0", draw at (0, 0), loop forever at 0x204
+        let mut runner = Runner::new(InstructionSettings::default(), 700);
+        runner
+            .load_program(vec![0xa0, 0x50, 0xd0, 0x05, 0x12, 0x04])
+            .unwrap();
+        let reason = runner.run_frame(Some(0x204)).unwrap();
+        assert_eq!(reason, Some(StopReason::ReachedAddress));
+
+        let ascii = display_to_ascii(&runner.processor().display());
+        let mut lines = ascii.lines();
+        assert_eq!(lines.next().unwrap(), format!("####{}", ".".repeat(60)));
+        assert!(lines.next().unwrap().starts_with("#..#...."));
+    }
+
+    #[test]
+    fn error_location() {
+        let assembly = crate::octo::compile(": main\n  v0 := 1\n  return # oops\n").unwrap();
+        let mut runner = Runner::new(InstructionSettings::default(), 700);
+        runner.load_program(assembly.rom).unwrap();
+        runner.set_symbols(assembly.symbols);
+        let error = runner.run_frame(None).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "invalid instruction (0x00ee at 0x0204, main+0x2 (line 3: return)): stack underflow"
+        );
+    }
+}
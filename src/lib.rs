@@ -1,4 +1,19 @@
 pub mod application;
+pub mod assembler;
+pub mod breakpoint;
+pub mod clock;
+pub mod disassembler;
 pub mod emulator;
+pub mod gdb;
+pub mod headless;
+pub mod memory_viewer;
+pub mod movie;
+pub mod octo;
+pub mod opcode;
 pub mod processor;
+pub mod rewind;
+pub mod savestate;
 pub mod sound;
+pub mod symbols;
+pub mod trace;
+pub mod trace_diff;
@@ -1,13 +1,26 @@
 use clap::Parser;
 use eframe::egui;
 
-use jade::application::{Application, Args};
+use jade::application::{Application, Args, Command};
+use jade::{assembler, disassembler, gdb, headless, trace_diff};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     env_logger::init();
 
     let args = Args::parse();
 
+    match args.command() {
+        Some(Command::Disasm(disasm_args)) => return Ok(disassembler::run(disasm_args)?),
+        Some(Command::Asm(asm_args)) => return Ok(assembler::run(asm_args)?),
+        Some(Command::Gdb(gdb_args)) => return Ok(gdb::run(&args, gdb_args)?),
+        Some(Command::TraceDiff(diff_args)) => return Ok(trace_diff::run(&args, diff_args)?),
+        None => {}
+    }
+
+    if args.is_headless() {
+        return Ok(headless::run(&args)?);
+    }
+
     let options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
             .with_inner_size([640.0, 320.0])
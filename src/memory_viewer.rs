@@ -0,0 +1,242 @@
+use eframe::egui::{self, Color32, Pos2, Rect, Rounding, Sense, Vec2};
+
+use crate::emulator::{DebugState, Emulator, ProgramState};
+use crate::headless::parse_address;
+
+/// Number of bytes per row of the hex view.
+const BYTES_PER_ROW: usize = 8;
+
+/// Size of a sprite pixel in the preview.
+const SPRITE_PIXEL_SIZE: f32 = 6.0;
+
+const PC_COLOR: Color32 = Color32::from_rgb(0x3c, 0x8d, 0x6e);
+const INDEX_COLOR: Color32 = Color32::from_rgb(0x8d, 0x5a, 0x3c);
+const SELECTED_COLOR: Color32 = Color32::from_rgb(0x3c, 0x5a, 0x8d);
+
+/// A window showing the memory as hex dump, with the bytes at the program counter and the index
+/// register highlighted, and the bytes at the index register as sprites. While the program is
+/// paused, bytes can be changed.
+#[derive(Default)]
+pub struct MemoryViewer {
+    open: bool,
+    memory: Vec<u8>,
+    follow_pc: bool,
+    address_input: String,
+    scroll_to: Option<u16>,
+    selected: Option<u16>,
+    value_input: String,
+    sprite_rows: u8,
+}
+
+impl MemoryViewer {
+    pub fn new() -> Self {
+        MemoryViewer {
+            sprite_rows: 5,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    pub fn toggle(&mut self) {
+        self.open = !self.open;
+    }
+
+    /// Replace the memory shown with a fresh copy.
+    pub fn update_memory(&mut self, memory: Vec<u8>) {
+        self.memory = memory;
+    }
+
+    pub fn show(
+        &mut self,
+        ctx: &egui::Context,
+        emulator: &Emulator,
+        debug_state: Option<&DebugState>,
+    ) {
+        let mut open = self.open;
+        egui::Window::new("Memory")
+            .open(&mut open)
+            .default_size([420.0, 300.0])
+            .show(ctx, |ui| {
+                let Some(debug_state) = debug_state else {
+                    return;
+                };
+                if self.memory.is_empty() {
+                    return;
+                }
+                self.show_navigation(ui, debug_state);
+                self.show_editor(ui, emulator, debug_state);
+                ui.separator();
+                ui.horizontal_top(|ui| {
+                    self.show_hex_view(ui, debug_state);
+                    ui.separator();
+                    self.show_sprites(ui, debug_state);
+                });
+            });
+        self.open = open;
+    }
+
+    fn show_navigation(&mut self, ui: &mut egui::Ui, debug_state: &DebugState) {
+        let registers = &debug_state.registers;
+        ui.horizontal(|ui| {
+            let input = ui.add(
+                egui::TextEdit::singleline(&mut self.address_input)
+                    .hint_text("address")
+                    .desired_width(60.0),
+            );
+            let submitted = input.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+            if ui.button("Go").clicked() || submitted {
+                self.scroll_to = parse_address(&self.address_input).ok();
+            }
+            if ui.button("PC").clicked() {
+                self.scroll_to = Some(registers.program_counter);
+            }
+            if ui.button("I").clicked() {
+                self.scroll_to = Some(registers.index_register);
+            }
+            ui.checkbox(&mut self.follow_pc, "Follow PC");
+        });
+        if self.follow_pc {
+            self.scroll_to = Some(registers.program_counter);
+        }
+    }
+
+    fn show_editor(&mut self, ui: &mut egui::Ui, emulator: &Emulator, debug_state: &DebugState) {
+        let Some(address) = self.selected else {
+            ui.label("Click a byte to select it.");
+            return;
+        };
+        let paused = debug_state.state == ProgramState::Stopped;
+        ui.add_enabled_ui(paused, |ui| {
+            ui.horizontal(|ui| {
+                ui.monospace(format!("{:04X} :=", address));
+                let input = ui.add(
+                    egui::TextEdit::singleline(&mut self.value_input)
+                        .hint_text("0x00")
+                        .desired_width(40.0),
+                );
+                let submitted = input.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                if ui.button("Write").clicked() || submitted {
+                    match parse_address(&self.value_input) {
+                        Ok(value) if value <= u8::MAX as u16 => {
+                            emulator.write_memory(address, vec![value as u8]);
+                            self.selected = Some(address.wrapping_add(1));
+                            self.value_input.clear();
+                        }
+                        _ => self.value_input.clear(),
+                    }
+                }
+                if !paused {
+                    ui.label("(pause to edit)");
+                }
+            });
+        });
+    }
+
+    fn show_hex_view(&mut self, ui: &mut egui::Ui, debug_state: &DebugState) {
+        let registers = &debug_state.registers;
+        let row_height = ui.text_style_height(&egui::TextStyle::Monospace);
+        let rows = self.memory.len().div_ceil(BYTES_PER_ROW);
+
+        let mut scroll_area = egui::ScrollArea::vertical()
+            .id_salt("hex view")
+            .auto_shrink([true, false]);
+        if let Some(address) = self.scroll_to.take() {
+            let row = address as usize / BYTES_PER_ROW;
+            let offset = row as f32 * (row_height + ui.spacing().item_spacing.y);
+            scroll_area = scroll_area.vertical_scroll_offset(offset);
+        }
+
+        scroll_area.show_rows(ui, row_height, rows, |ui, row_range| {
+            for row in row_range {
+                ui.horizontal(|ui| {
+                    let start = row * BYTES_PER_ROW;
+                    ui.monospace(format!("{:04X}", start));
+                    for (offset, byte) in
+                        self.memory[start..].iter().take(BYTES_PER_ROW).enumerate()
+                    {
+                        let address = (start + offset) as u16;
+                        let mut text = egui::RichText::new(format!("{:02X}", byte)).monospace();
+                        if self.selected == Some(address) {
+                            text = text.background_color(SELECTED_COLOR);
+                        } else if address.wrapping_sub(registers.program_counter) < 2 {
+                            text = text.background_color(PC_COLOR);
+                        } else if address == registers.index_register {
+                            text = text.background_color(INDEX_COLOR);
+                        }
+                        if ui
+                            .add(egui::Label::new(text).sense(Sense::click()))
+                            .clicked()
+                        {
+                            self.selected = Some(address);
+                            self.value_input = format!("{:#04x}", byte);
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    /// Draw the bytes at the index register as 8xN sprite and as 16x16 sprite (SUPER-CHIP).
+    fn show_sprites(&mut self, ui: &mut egui::Ui, debug_state: &DebugState) {
+        let start = debug_state.registers.index_register as usize;
+        let data = &self.memory[start.min(self.memory.len())..];
+
+        ui.vertical(|ui| {
+            ui.label("Sprite at I");
+            ui.add(egui::Slider::new(&mut self.sprite_rows, 1..=15).text("rows"));
+            let rows = self.sprite_rows as usize;
+            draw_sprite(ui, &sprite_pixels(&data[..rows.min(data.len())], 1));
+            ui.label("16x16");
+            draw_sprite(ui, &sprite_pixels(&data[..32.min(data.len())], 2));
+        });
+    }
+}
+
+/// Decode sprite data with `bytes_per_row` bytes (8 pixels each) per row into pixels.
+fn sprite_pixels(data: &[u8], bytes_per_row: usize) -> Vec<Vec<bool>> {
+    data.chunks(bytes_per_row)
+        .map(|row| {
+            row.iter()
+                .flat_map(|byte| (0..8).map(move |bit| byte & (0x80 >> bit) != 0))
+                .collect()
+        })
+        .collect()
+}
+
+fn draw_sprite(ui: &mut egui::Ui, pixels: &[Vec<bool>]) {
+    let width = pixels.iter().map(Vec::len).max().unwrap_or_default();
+    let size = Vec2::new(width as f32, pixels.len() as f32) * SPRITE_PIXEL_SIZE;
+    let (response, painter) = ui.allocate_painter(size, Sense::hover());
+    painter.rect_filled(response.rect, Rounding::ZERO, Color32::BLACK);
+    for (y, row) in pixels.iter().enumerate() {
+        for (x, _) in row.iter().enumerate().filter(|(_, &set)| set) {
+            let rect = Rect::from_min_size(
+                Pos2::new(
+                    response.rect.left() + x as f32 * SPRITE_PIXEL_SIZE,
+                    response.rect.top() + y as f32 * SPRITE_PIXEL_SIZE,
+                ),
+                Vec2::splat(SPRITE_PIXEL_SIZE),
+            );
+            painter.rect_filled(rect, Rounding::ZERO, Color32::WHITE);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_sprite_pixels() {
+        let pixels = sprite_pixels(&[0x81, 0x40, 0xff, 0x00], 2);
+        assert_eq!(pixels.len(), 2);
+        assert_eq!(pixels[0].len(), 16);
+        assert!(pixels[0][0] && pixels[0][7] && pixels[0][9]);
+        assert!(!pixels[0][1] && !pixels[0][8]);
+        assert!(pixels[1][..8].iter().all(|&p| p));
+        assert!(pixels[1][8..].iter().all(|&p| !p));
+    }
+}
@@ -0,0 +1,258 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use thiserror::Error;
+
+use crate::headless::{HeadlessError, KeyScript};
+use crate::processor::Key;
+
+/// First line of every movie file.
+const MOVIE_HEADER: &str = "# Jade movie";
+
+/// The keys pressed in each frame (timer tick) of a run, together with what is needed to replay
+/// it: the ROM (as hash) and the seed of the random number generator. Replays are only exact with
+/// the same instruction settings and speed.
+///
+/// As text, a movie starts with the header lines `rom <hash>`, `seed <seed>` and
+/// `frames <number>`, followed by a key script, i.e. lines of `<frame> <pressed keys>` whenever
+/// the pressed keys change.
+#[derive(Clone, Debug)]
+pub struct Movie {
+    rom_hash: u64,
+    seed: u64,
+    frames: usize,
+    keys: KeyScript,
+}
+
+impl Movie {
+    /// Start an empty movie.
+    pub fn new(rom: &[u8], seed: u64) -> Self {
+        Movie {
+            rom_hash: rom_hash(rom),
+            seed,
+            frames: 0,
+            keys: KeyScript::default(),
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Number of frames recorded.
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    pub fn key_script(&self) -> &KeyScript {
+        &self.keys
+    }
+
+    /// The keys pressed from `frame` on, if they change in this frame.
+    pub fn keys_at(&self, frame: usize) -> Option<&HashSet<Key>> {
+        self.keys.keys_at(frame)
+    }
+
+    /// Record the keys pressed in the next frame.
+    pub fn push_frame(&mut self, keys: &HashSet<Key>) {
+        if self.keys.last_keys() != *keys {
+            self.keys.push(self.frames, keys.clone());
+        }
+        self.frames += 1;
+    }
+
+    /// Check that the movie was recorded with `rom`.
+    pub fn check_rom(&self, rom: &[u8]) -> Result<(), MovieError> {
+        let actual = rom_hash(rom);
+        if actual != self.rom_hash {
+            return Err(MovieError::RomMismatch {
+                expected: self.rom_hash,
+                actual,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn parse(text: &str) -> Result<Self, MovieError> {
+        let mut rom_hash = None;
+        let mut seed = None;
+        let mut frames = None;
+        // Header lines are blanked, so that the key script reports the right line numbers
+        let mut script = String::with_capacity(text.len());
+        for (index, line) in text.lines().enumerate() {
+            let error = |message: String| MovieError::Parse {
+                line: index + 1,
+                message,
+            };
+            let content = line.split('#').next().unwrap_or_default();
+            let mut words = content.split_whitespace();
+            let value = match words.next() {
+                Some(name @ ("rom" | "seed" | "frames")) => {
+                    let value = words
+                        .next()
+                        .ok_or_else(|| error(format!("value of '{}' missing", name)))?;
+                    Some((name, value))
+                }
+                _ => None,
+            };
+            match value {
+                Some(("rom", value)) => {
+                    let hash = u64::from_str_radix(value, 16)
+                        .map_err(|e| error(format!("invalid ROM hash: {}", e)))?;
+                    rom_hash = Some(hash);
+                }
+                Some(("seed", value)) => {
+                    seed = Some(
+                        value
+                            .parse()
+                            .map_err(|e| error(format!("invalid seed: {}", e)))?,
+                    );
+                }
+                Some((_, value)) => {
+                    frames = Some(
+                        value
+                            .parse()
+                            .map_err(|e| error(format!("invalid number of frames: {}", e)))?,
+                    );
+                }
+                None => script.push_str(line),
+            }
+            script.push('\n');
+        }
+
+        let missing = |name: &str| MovieError::Parse {
+            line: 1,
+            message: format!("'{}' missing", name),
+        };
+        let keys = KeyScript::parse(&script).map_err(|e| match e {
+            HeadlessError::KeyScript { line, message } => MovieError::Parse { line, message },
+            e => MovieError::Parse {
+                line: 1,
+                message: e.to_string(),
+            },
+        })?;
+        Ok(Movie {
+            rom_hash: rom_hash.ok_or_else(|| missing("rom"))?,
+            seed: seed.ok_or_else(|| missing("seed"))?,
+            frames: frames.ok_or_else(|| missing("frames"))?,
+            keys,
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Self, MovieError> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), MovieError> {
+        Ok(std::fs::write(path, self.to_string())?)
+    }
+}
+
+impl fmt::Display for Movie {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", MOVIE_HEADER)?;
+        writeln!(f, "rom {:016x}", self.rom_hash)?;
+        writeln!(f, "seed {}", self.seed)?;
+        writeln!(f, "frames {}", self.frames)?;
+        write!(f, "{}", self.keys)
+    }
+}
+
+/// Hash identifying a ROM (64 bit FNV-1a).
+pub fn rom_hash(rom: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+    rom.iter().fold(OFFSET_BASIS, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(PRIME)
+    })
+}
+
+#[derive(Error, Debug)]
+pub enum MovieError {
+    #[error("cannot access movie file: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("movie file, line {line}: {message}")]
+    Parse { line: usize, message: String },
+
+    #[error("movie was recorded with another ROM (hash {expected:016x}, not {actual:016x})")]
+    RomMismatch { expected: u64, actual: u64 },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::headless::Runner;
+    use crate::processor::{InstructionSettings, Registers};
+
+    // v1 := random 0xff; loop: if v0 key then v2 += 1; v0 += 1; v3 := 3; v0 &= v3; jump loop
+    const PROGRAM: [u8; 14] = [
+        0xc1, 0xff, 0xe0, 0xa1, 0x72, 0x01, 0x70, 0x01, 0x63, 0x03, 0x80, 0x32, 0x12, 0x02,
+    ];
+
+    /// Run `frames` frames, pressing the keys of `movie` (if given) or of `keys`, and record a
+    /// movie of it.
+    fn run(
+        seed: u64,
+        frames: usize,
+        keys: impl Fn(usize) -> HashSet<Key>,
+        movie: Option<&Movie>,
+    ) -> (Movie, Registers) {
+        let mut runner = Runner::new(InstructionSettings::default(), 700);
+        runner.processor_mut().set_seed(seed);
+        runner.load_program(PROGRAM.to_vec()).unwrap();
+        let mut recording = Movie::new(&PROGRAM, seed);
+        while runner.frame() < frames {
+            let frame = runner.frame();
+            match movie {
+                Some(movie) => {
+                    if let Some(keys) = movie.keys_at(frame) {
+                        runner.processor_mut().handle_keys(keys.clone());
+                    }
+                }
+                None => runner.processor_mut().handle_keys(keys(frame)),
+            }
+            recording.push_frame(runner.processor().keys());
+            runner.run_frame(None).unwrap();
+        }
+        (recording, runner.processor().registers())
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let keys = |frame: usize| match frame % 7 {
+            0..=2 => HashSet::from([Key::Num1, Key::Num2]),
+            _ => HashSet::new(),
+        };
+        let (movie, registers) = run(42, 30, keys, None);
+        assert_eq!(movie.frames(), 30);
+        assert!(registers.variable_registers[2] > 0);
+
+        let movie = Movie::parse(&movie.to_string()).unwrap();
+        assert_eq!(movie.seed(), 42);
+        movie.check_rom(&PROGRAM).unwrap();
+        assert!(movie.check_rom(&PROGRAM[1..]).is_err());
+        let (_, replayed) = run(
+            movie.seed(),
+            movie.frames(),
+            |_| HashSet::new(),
+            Some(&movie),
+        );
+        assert_eq!(replayed, registers);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let text = "# Jade movie\nrom 00000000000000ff\nseed 1\nframes 10\n0 5\n3 x\n";
+        assert!(matches!(
+            Movie::parse(text),
+            Err(MovieError::Parse { line: 6, .. })
+        ));
+        assert!(matches!(
+            Movie::parse("rom 1\nframes 2\n"),
+            Err(MovieError::Parse { line: 1, .. })
+        ));
+    }
+}
@@ -0,0 +1,970 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+use crate::assembler::Assembly;
+use crate::opcode::Opcode;
+use crate::processor::{FLAG_REGISTER_INDEX, ROM_START_ADDR};
+use crate::symbols::SymbolMap;
+
+/// Maximum number of macro expansions, guards against recursive macros.
+const MAX_MACRO_EXPANSIONS: usize = 10_000;
+
+/// Compile an Octo source file (*.8o).
+pub fn compile_file(path: &Path) -> Result<Assembly, OctoError> {
+    let source = std::fs::read_to_string(path).map_err(|e| OctoError::Read {
+        path: path.to_path_buf(),
+        source: e,
+    })?;
+    compile(&source)
+}
+
+/// Compile Octo source code into a ROM and a symbol map of its labels.
+///
+/// Supported are all instructions of CHIP-8, SUPER-CHIP and XO-CHIP in Octo syntax, labels
+/// (`: name`), `:const`, `:alias`, `:calc`, `:macro`, `:byte`, `:org` and `:call`, structured
+/// control flow with `loop`/`while`/`again` and `if`/`then`/`begin`/`else`/`end`, and the
+/// comparison pseudo-instructions `<`, `>`, `<=` and `>=` (which use `vf`). As in Octo, the
+/// program starts with a jump to the label `main`.
+pub fn compile(source: &str) -> Result<Assembly, OctoError> {
+    Compiler::new(source).compile()
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    text: String,
+    line: usize,
+}
+
+fn tokenize(source: &str) -> VecDeque<Token> {
+    source
+        .lines()
+        .enumerate()
+        .flat_map(|(index, line)| {
+            let code = line.split('#').next().unwrap_or_default();
+            code.split_whitespace().map(move |text| Token {
+                text: text.to_string(),
+                line: index + 1,
+            })
+        })
+        .collect()
+}
+
+/// Parse a numeric literal: decimal, hexadecimal (`0x`) or binary (`0b`), optionally negative.
+fn parse_number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()?
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+/// A reference to a label which was not defined yet, resolved at the end of the compilation.
+struct Fixup {
+    address: u16,
+    name: String,
+    line: usize,
+    long: bool,
+}
+
+/// An open control flow structure.
+enum Control {
+    /// `begin` or `else` with the address of the jump to patch at the next `else` or `end`.
+    Branch { jump: u16, has_else: bool },
+    /// `loop` with its start and the addresses of the jumps of its `while`s.
+    Loop { start: u16, exits: Vec<u16> },
+}
+
+/// The right hand side of a comparison.
+#[derive(Copy, Clone)]
+enum Operand {
+    Register(u8),
+    Value(u8),
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+    LessOrEqual,
+    GreaterOrEqual,
+}
+
+enum Condition {
+    Compare {
+        x: u8,
+        comparison: Comparison,
+        rhs: Operand,
+    },
+    Key {
+        x: u8,
+        pressed: bool,
+    },
+}
+
+struct Macro {
+    parameters: Vec<String>,
+    body: Vec<Token>,
+}
+
+struct Compiler<'a> {
+    source: &'a str,
+    tokens: VecDeque<Token>,
+    line: usize,
+    /// The source line of each instruction
+    line_numbers: BTreeMap<u16, usize>,
+    rom: Vec<u8>,
+    here: u16,
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<Fixup>,
+    control: Vec<Control>,
+    expansions: usize,
+}
+
+impl<'a> Compiler<'a> {
+    fn new(source: &'a str) -> Self {
+        Compiler {
+            source,
+            tokens: tokenize(source),
+            line: 1,
+            line_numbers: BTreeMap::new(),
+            rom: Vec::new(),
+            here: ROM_START_ADDR,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: Vec::new(),
+            control: Vec::new(),
+            expansions: 0,
+        }
+    }
+
+    fn compile(mut self) -> Result<Assembly, OctoError> {
+        // Reserve the jump to main, which has no source line
+        self.jump_to_label(Opcode::Jump, "main")?;
+        self.line_numbers.clear();
+        while !self.tokens.is_empty() {
+            self.statement()?;
+        }
+        if let Some(control) = self.control.last() {
+            return Err(self.error(match control {
+                Control::Branch { .. } => "missing 'end'",
+                Control::Loop { .. } => "missing 'again'",
+            }));
+        }
+
+        for fixup in &self.fixups {
+            let Some(&target) = self.labels.get(&fixup.name) else {
+                return Err(OctoError::Source {
+                    line: fixup.line,
+                    message: format!("undefined name '{}'", fixup.name),
+                });
+            };
+            let offset = (fixup.address - ROM_START_ADDR) as usize;
+            if fixup.long {
+                self.rom[offset + 2..offset + 4].copy_from_slice(&target.to_be_bytes());
+            } else {
+                check_address(target, &format!("'{}'", fixup.name), fixup.line)?;
+                self.rom[offset] = self.rom[offset] & 0xf0 | (target >> 8) as u8;
+                self.rom[offset + 1] = target as u8;
+            }
+        }
+
+        let mut symbols = SymbolMap::new();
+        for (name, address) in self.labels {
+            symbols.insert(name, address);
+        }
+        let lines: Vec<&str> = self.source.lines().collect();
+        for (address, line) in self.line_numbers {
+            symbols.insert_source_line(address, line, lines[line - 1]);
+        }
+        Ok(Assembly {
+            rom: self.rom,
+            symbols,
+        })
+    }
+
+    fn error(&self, message: impl Into<String>) -> OctoError {
+        OctoError::Source {
+            line: self.line,
+            message: message.into(),
+        }
+    }
+
+    fn next(&mut self) -> Result<String, OctoError> {
+        let token = self
+            .tokens
+            .pop_front()
+            .ok_or_else(|| self.error("unexpected end of source"))?;
+        self.line = token.line;
+        Ok(token.text)
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.front().map(|t| t.text.as_str())
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), OctoError> {
+        let token = self.next()?;
+        if token != expected {
+            return Err(self.error(format!("expected '{}', found '{}'", expected, token)));
+        }
+        Ok(())
+    }
+
+    fn emit_byte(&mut self, byte: u8) -> Result<(), OctoError> {
+        let offset = (self.here - ROM_START_ADDR) as usize;
+        if offset >= self.rom.len() {
+            self.rom.resize(offset + 1, 0);
+        }
+        self.rom[offset] = byte;
+        self.here = self
+            .here
+            .checked_add(1)
+            .ok_or_else(|| self.error("program does not fit into memory"))?;
+        Ok(())
+    }
+
+    fn emit(&mut self, instruction: Opcode) -> Result<(), OctoError> {
+        self.line_numbers.insert(self.here, self.line);
+        for byte in instruction.encode() {
+            self.emit_byte(byte)?;
+        }
+        Ok(())
+    }
+
+    /// Patch the jump at `address` to jump to the current address, which is the target of the
+    /// keyword `what`.
+    fn patch_jump(&mut self, address: u16, what: &str) -> Result<(), OctoError> {
+        check_address(self.here, &format!("'{}'", what), self.line)?;
+        let offset = (address - ROM_START_ADDR) as usize;
+        let jump = Opcode::Jump(self.here).encode();
+        self.rom[offset..offset + 2].copy_from_slice(&jump);
+        Ok(())
+    }
+
+    /// Emit an instruction referring to the label `name`, which may be defined later.
+    fn jump_to_label(
+        &mut self,
+        instruction: impl Fn(u16) -> Opcode,
+        name: &str,
+    ) -> Result<(), OctoError> {
+        let long = matches!(instruction(0), Opcode::SetIndexLong(_));
+        if let Some(&address) = self.labels.get(name) {
+            if !long {
+                check_address(address, &format!("'{}'", name), self.line)?;
+            }
+            return self.emit(instruction(address));
+        }
+        self.fixups.push(Fixup {
+            address: self.here,
+            name: name.to_string(),
+            line: self.line,
+            long,
+        });
+        self.emit(instruction(0))
+    }
+
+    /// Emit an instruction with an address operand, which is a number, a constant or a label.
+    fn address_operand(
+        &mut self,
+        instruction: impl Fn(u16) -> Opcode,
+        max: i64,
+    ) -> Result<(), OctoError> {
+        let token = self.next()?;
+        if self.labels.contains_key(&token) || !self.is_value(&token) {
+            if !is_identifier(&token) {
+                return Err(self.error(format!("invalid address '{}'", token)));
+            }
+            return self.jump_to_label(instruction, &token);
+        }
+        let address = self.value_in_range(&token, 0, max)?;
+        self.emit(instruction(address as u16))
+    }
+
+    fn is_value(&self, token: &str) -> bool {
+        parse_number(token).is_some() || self.constants.contains_key(token)
+    }
+
+    fn value(&self, token: &str) -> Result<i64, OctoError> {
+        if let Some(value) = parse_number(token) {
+            return Ok(value);
+        }
+        if let Some(&value) = self.constants.get(token) {
+            return Ok(value.floor() as i64);
+        }
+        if let Some(&address) = self.labels.get(token) {
+            return Ok(address as i64);
+        }
+        Err(self.error(format!("undefined name '{}'", token)))
+    }
+
+    fn value_in_range(&self, token: &str, min: i64, max: i64) -> Result<i64, OctoError> {
+        let value = self.value(token)?;
+        self.check_range(value, token, min, max)
+    }
+
+    /// Check that `value`, given by `source`, is in the range from `min` to `max`.
+    fn check_range(&self, value: i64, source: &str, min: i64, max: i64) -> Result<i64, OctoError> {
+        if !(min..=max).contains(&value) {
+            return Err(self.error(format!(
+                "value {} of '{}' is out of range ({} to {})",
+                value, source, min, max
+            )));
+        }
+        Ok(value)
+    }
+
+    fn byte(&mut self) -> Result<u8, OctoError> {
+        let token = self.next()?;
+        Ok(self.value_in_range(&token, -0x80, 0xff)? as u8)
+    }
+
+    fn nibble(&mut self) -> Result<u8, OctoError> {
+        let token = self.next()?;
+        Ok(self.value_in_range(&token, 0, 0xf)? as u8)
+    }
+
+    fn register_index(&self, token: &str) -> Option<u8> {
+        if let Some(&register) = self.aliases.get(token) {
+            return Some(register);
+        }
+        token
+            .strip_prefix(['v', 'V'])
+            .filter(|r| r.len() == 1)
+            .and_then(|r| u8::from_str_radix(r, 16).ok())
+    }
+
+    fn register(&mut self) -> Result<u8, OctoError> {
+        let token = self.next()?;
+        self.register_index(&token)
+            .ok_or_else(|| self.error(format!("expected a register, found '{}'", token)))
+    }
+
+    fn operand(&mut self) -> Result<Operand, OctoError> {
+        let token = self.next()?;
+        match self.register_index(&token) {
+            Some(register) => Ok(Operand::Register(register)),
+            None => Ok(Operand::Value(
+                self.value_in_range(&token, -0x80, 0xff)? as u8
+            )),
+        }
+    }
+
+    fn define_label(&mut self, name: String) -> Result<(), OctoError> {
+        if !is_identifier(&name) {
+            return Err(self.error(format!("invalid name '{}'", name)));
+        }
+        if self.labels.contains_key(&name) {
+            return Err(self.error(format!("label '{}' is already defined", name)));
+        }
+        self.labels.insert(name, self.here);
+        Ok(())
+    }
+
+    /// Collect the tokens up to the matching `}`, after the opening `{`.
+    fn block(&mut self) -> Result<Vec<Token>, OctoError> {
+        self.expect("{")?;
+        let mut depth = 0;
+        let mut tokens = Vec::new();
+        loop {
+            let token = self
+                .tokens
+                .pop_front()
+                .ok_or_else(|| self.error("missing '}'"))?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" if depth == 0 => return Ok(tokens),
+                "}" => depth -= 1,
+                _ => {}
+            }
+            tokens.push(token);
+        }
+    }
+
+    fn statement(&mut self) -> Result<(), OctoError> {
+        use Opcode::*;
+
+        let token = self.next()?;
+        match token.as_str() {
+            ":" => {
+                let name = self.next()?;
+                self.define_label(name)?;
+            }
+            ":const" => {
+                let name = self.next()?;
+                let value = self.next()?;
+                let value = self.value(&value)?;
+                self.constants.insert(name, value as f64);
+            }
+            ":alias" => {
+                let name = self.next()?;
+                let register = self.register()?;
+                self.aliases.insert(name, register);
+            }
+            ":calc" => {
+                let name = self.next()?;
+                let tokens = self.block()?;
+                let value = self.calculate(&tokens)?;
+                self.constants.insert(name, value);
+            }
+            ":macro" => {
+                let name = self.next()?;
+                let mut parameters = Vec::new();
+                while self.peek().is_some_and(|t| t != "{") {
+                    parameters.push(self.next()?);
+                }
+                let body = self.block()?;
+                self.macros.insert(name, Macro { parameters, body });
+            }
+            ":byte" => {
+                let value = if self.peek() == Some("{") {
+                    let tokens = self.block()?;
+                    let value = self.calculate(&tokens)?.floor() as i64;
+                    let expression: Vec<_> = tokens.iter().map(|t| t.text.as_str()).collect();
+                    self.check_range(value, &expression.join(" "), -0x80, 0xff)?
+                } else {
+                    let token = self.next()?;
+                    self.value_in_range(&token, -0x80, 0xff)?
+                };
+                self.emit_byte(value as u8)?;
+            }
+            ":org" => {
+                let token = self.next()?;
+                self.here = self.value_in_range(&token, ROM_START_ADDR as i64, 0xffff)? as u16;
+            }
+            ":call" => self.address_operand(Call, 0xfff)?,
+            "clear" => self.emit(ClearScreen)?,
+            "return" | ";" => self.emit(Return)?,
+            "exit" => self.emit(Exit)?,
+            "lores" => self.emit(LowResolution)?,
+            "hires" => self.emit(HighResolution)?,
+            "scroll-down" => {
+                let n = self.nibble()?;
+                self.emit(ScrollDown(n))?
+            }
+            "scroll-up" => {
+                let n = self.nibble()?;
+                self.emit(ScrollUp(n))?
+            }
+            "scroll-left" => self.emit(ScrollLeft)?,
+            "scroll-right" => self.emit(ScrollRight)?,
+            "audio" => self.emit(LoadAudio)?,
+            "plane" => {
+                let n = self.nibble()?;
+                self.emit(SelectPlanes(n))?
+            }
+            "jump" => self.address_operand(Jump, 0xfff)?,
+            "jump0" => self.address_operand(JumpWithOffset, 0xfff)?,
+            "sprite" => {
+                let x = self.register()?;
+                let y = self.register()?;
+                let rows = self.nibble()?;
+                self.emit(Draw { x, y, rows })?
+            }
+            "bcd" => {
+                let x = self.register()?;
+                self.emit(Bcd(x))?
+            }
+            "save" | "load" => {
+                let x = self.register()?;
+                if self.peek() == Some("-") {
+                    self.next()?;
+                    let y = self.register()?;
+                    self.emit(match token.as_str() {
+                        "save" => SaveRange { x, y },
+                        _ => LoadRange { x, y },
+                    })?
+                } else {
+                    self.emit(match token.as_str() {
+                        "save" => Store(x),
+                        _ => Load(x),
+                    })?
+                }
+            }
+            "saveflags" => {
+                let x = self.register()?;
+                self.emit(StoreFlags(x))?
+            }
+            "loadflags" => {
+                let x = self.register()?;
+                self.emit(LoadFlags(x))?
+            }
+            "i" => self.index_assignment()?,
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.register()?;
+                self.emit(match token.as_str() {
+                    "delay" => SetDelay(x),
+                    "buzzer" => SetSound(x),
+                    _ => SetPitch(x),
+                })?
+            }
+            "if" => self.conditional()?,
+            "else" => match self.control.pop() {
+                Some(Control::Branch {
+                    jump,
+                    has_else: false,
+                }) => {
+                    let end_jump = self.here;
+                    self.emit(Jump(0))?;
+                    self.patch_jump(jump, "else")?;
+                    self.control.push(Control::Branch {
+                        jump: end_jump,
+                        has_else: true,
+                    });
+                }
+                _ => return Err(self.error("'else' without 'begin'")),
+            },
+            "end" => match self.control.pop() {
+                Some(Control::Branch { jump, .. }) => self.patch_jump(jump, "end")?,
+                _ => return Err(self.error("'end' without 'begin'")),
+            },
+            "loop" => self.control.push(Control::Loop {
+                start: self.here,
+                exits: Vec::new(),
+            }),
+            "while" => {
+                let condition = self.condition()?;
+                // Skip the jump out of the loop while the condition holds
+                self.emit_skip(condition, true)?;
+                let exit = self.here;
+                self.emit(Jump(0))?;
+                match self.control.iter_mut().rev().find_map(|c| match c {
+                    Control::Loop { exits, .. } => Some(exits),
+                    _ => None,
+                }) {
+                    Some(exits) => exits.push(exit),
+                    None => return Err(self.error("'while' outside of a loop")),
+                }
+            }
+            "again" => match self.control.pop() {
+                Some(Control::Loop { start, exits }) => {
+                    check_address(start, "'loop'", self.line)?;
+                    self.emit(Jump(start))?;
+                    for exit in exits {
+                        self.patch_jump(exit, "again")?;
+                    }
+                }
+                _ => return Err(self.error("'again' without 'loop'")),
+            },
+            _ => {
+                if let Some(x) = self.register_index(&token) {
+                    self.register_assignment(x)?;
+                } else if let Some(m) = self.macros.get(&token) {
+                    self.expansions += 1;
+                    if self.expansions > MAX_MACRO_EXPANSIONS {
+                        return Err(self.error("too many macro expansions"));
+                    }
+                    let parameters = m.parameters.clone();
+                    let body = m.body.clone();
+                    let mut arguments = HashMap::new();
+                    for parameter in parameters {
+                        arguments.insert(parameter, self.next()?);
+                    }
+                    for mut token in body.into_iter().rev() {
+                        if let Some(argument) = arguments.get(&token.text) {
+                            token.text = argument.clone();
+                        }
+                        self.tokens.push_front(token);
+                    }
+                } else if self.is_value(&token) {
+                    let value = self.value_in_range(&token, -0x80, 0xff)?;
+                    self.emit_byte(value as u8)?;
+                } else if is_identifier(&token) {
+                    self.jump_to_label(Call, &token)?;
+                } else {
+                    return Err(self.error(format!("unexpected '{}'", token)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn index_assignment(&mut self) -> Result<(), OctoError> {
+        use Opcode::*;
+
+        let operator = self.next()?;
+        match operator.as_str() {
+            ":=" => match self.peek() {
+                Some("hex") => {
+                    self.next()?;
+                    let x = self.register()?;
+                    self.emit(Font(x))
+                }
+                Some("bighex") => {
+                    self.next()?;
+                    let x = self.register()?;
+                    self.emit(BigFont(x))
+                }
+                Some("long") => {
+                    self.next()?;
+                    self.address_operand(SetIndexLong, 0xffff)
+                }
+                _ => self.address_operand(SetIndex, 0xfff),
+            },
+            "+=" => {
+                let x = self.register()?;
+                self.emit(AddToIndex(x))
+            }
+            _ => Err(self.error(format!("unexpected '{}' after 'i'", operator))),
+        }
+    }
+
+    fn register_assignment(&mut self, x: u8) -> Result<(), OctoError> {
+        use Opcode::*;
+
+        let operator = self.next()?;
+        let instruction = match operator.as_str() {
+            ":=" => match self.peek() {
+                Some("random") => {
+                    self.next()?;
+                    let mask = self.byte()?;
+                    Random { x, mask }
+                }
+                Some("key") => {
+                    self.next()?;
+                    WaitKey(x)
+                }
+                Some("delay") => {
+                    self.next()?;
+                    GetDelay(x)
+                }
+                _ => match self.operand()? {
+                    Operand::Register(y) => Assign { x, y },
+                    Operand::Value(value) => SetRegister { x, value },
+                },
+            },
+            "+=" => match self.operand()? {
+                Operand::Register(y) => Add { x, y },
+                Operand::Value(value) => AddToRegister { x, value },
+            },
+            "-=" => match self.operand()? {
+                Operand::Register(y) => Subtract { x, y },
+                Operand::Value(value) => AddToRegister {
+                    x,
+                    value: value.wrapping_neg(),
+                },
+            },
+            _ => {
+                let y = self.register()?;
+                match operator.as_str() {
+                    "=-" => SubtractReverse { x, y },
+                    "|=" => Or { x, y },
+                    "&=" => And { x, y },
+                    "^=" => Xor { x, y },
+                    ">>=" => ShiftRight { x, y },
+                    "<<=" => ShiftLeft { x, y },
+                    _ => return Err(self.error(format!("unknown operator '{}'", operator))),
+                }
+            }
+        };
+        self.emit(instruction)
+    }
+
+    fn condition(&mut self) -> Result<Condition, OctoError> {
+        let x = self.register()?;
+        let operator = self.next()?;
+        let comparison = match operator.as_str() {
+            "key" => return Ok(Condition::Key { x, pressed: true }),
+            "-key" => return Ok(Condition::Key { x, pressed: false }),
+            "==" => Comparison::Equal,
+            "!=" => Comparison::NotEqual,
+            "<" => Comparison::Less,
+            ">" => Comparison::Greater,
+            "<=" => Comparison::LessOrEqual,
+            ">=" => Comparison::GreaterOrEqual,
+            _ => return Err(self.error(format!("unknown comparison '{}'", operator))),
+        };
+        let rhs = self.operand()?;
+        Ok(Condition::Compare { x, comparison, rhs })
+    }
+
+    /// Emit instructions which skip the next instruction if the condition equals `skip_if`.
+    fn emit_skip(&mut self, condition: Condition, skip_if: bool) -> Result<(), OctoError> {
+        use Opcode::*;
+        const VF: u8 = FLAG_REGISTER_INDEX;
+
+        let (x, comparison, rhs) = match condition {
+            Condition::Key { x, pressed } => {
+                return self.emit(if pressed == skip_if {
+                    SkipIfKey(x)
+                } else {
+                    SkipIfNotKey(x)
+                });
+            }
+            Condition::Compare { x, comparison, rhs } => (x, comparison, rhs),
+        };
+
+        let equal = match comparison {
+            Comparison::Equal => skip_if,
+            Comparison::NotEqual => !skip_if,
+            _ => {
+                // Compute vf := (a >= b) with a subtraction, then test vf
+                let (a, b) = match comparison {
+                    Comparison::Less | Comparison::GreaterOrEqual => (Operand::Register(x), rhs),
+                    _ => (rhs, Operand::Register(x)),
+                };
+                match (a, b) {
+                    (a, Operand::Register(y)) => {
+                        self.emit(match a {
+                            Operand::Register(a) => Assign { x: VF, y: a },
+                            Operand::Value(value) => SetRegister { x: VF, value },
+                        })?;
+                        self.emit(Subtract { x: VF, y })?;
+                    }
+                    (Operand::Register(a), Operand::Value(value)) => {
+                        self.emit(SetRegister { x: VF, value })?;
+                        self.emit(SubtractReverse { x: VF, y: a })?;
+                    }
+                    (Operand::Value(_), Operand::Value(_)) => unreachable!(),
+                }
+                // The condition holds if vf equals this
+                let flag = matches!(
+                    comparison,
+                    Comparison::GreaterOrEqual | Comparison::LessOrEqual
+                ) as u8;
+                let skip = if skip_if {
+                    SkipIfEqual { x: VF, value: flag }
+                } else {
+                    SkipIfNotEqual { x: VF, value: flag }
+                };
+                return self.emit(skip);
+            }
+        };
+        self.emit(match (rhs, equal) {
+            (Operand::Register(y), true) => SkipIfRegistersEqual { x, y },
+            (Operand::Register(y), false) => SkipIfRegistersNotEqual { x, y },
+            (Operand::Value(value), true) => SkipIfEqual { x, value },
+            (Operand::Value(value), false) => SkipIfNotEqual { x, value },
+        })
+    }
+
+    fn conditional(&mut self) -> Result<(), OctoError> {
+        let condition = self.condition()?;
+        let keyword = self.next()?;
+        match keyword.as_str() {
+            // Skip the next instruction unless the condition holds
+            "then" => self.emit_skip(condition, false),
+            // Skip the jump to else/end if the condition holds
+            "begin" => {
+                self.emit_skip(condition, true)?;
+                let jump = self.here;
+                self.emit(Opcode::Jump(0))?;
+                self.control.push(Control::Branch {
+                    jump,
+                    has_else: false,
+                });
+                Ok(())
+            }
+            _ => Err(self.error(format!("expected 'then' or 'begin', found '{}'", keyword))),
+        }
+    }
+
+    /// Evaluate a `:calc` expression. As in Octo, there is no operator precedence, expressions
+    /// are evaluated from right to left.
+    fn calculate(&self, tokens: &[Token]) -> Result<f64, OctoError> {
+        let mut position = 0;
+        let value = self.calc_expression(tokens, &mut position)?;
+        if position != tokens.len() {
+            return Err(self.error(format!("unexpected '{}' in :calc", tokens[position].text)));
+        }
+        Ok(value)
+    }
+
+    fn calc_expression(&self, tokens: &[Token], position: &mut usize) -> Result<f64, OctoError> {
+        let lhs = self.calc_term(tokens, position)?;
+        let Some(operator) = tokens.get(*position).map(|t| t.text.as_str()) else {
+            return Ok(lhs);
+        };
+        if operator == ")" {
+            return Ok(lhs);
+        }
+        *position += 1;
+        let rhs = self.calc_expression(tokens, position)?;
+        let (a, b) = (lhs as i64, rhs as i64);
+        Ok(match operator {
+            "+" => lhs + rhs,
+            "-" => lhs - rhs,
+            "*" => lhs * rhs,
+            "/" if rhs == 0.0 => return Err(self.error("division by zero in :calc")),
+            "/" => lhs / rhs,
+            "%" if b == 0 => return Err(self.error("division by zero in :calc")),
+            "%" => (a % b) as f64,
+            "&" => (a & b) as f64,
+            "|" => (a | b) as f64,
+            "^" => (a ^ b) as f64,
+            "<<" => a.checked_shl(b as u32).unwrap_or(0) as f64,
+            ">>" => a.checked_shr(b as u32).unwrap_or(0) as f64,
+            _ => return Err(self.error(format!("unknown operator '{}' in :calc", operator))),
+        })
+    }
+
+    fn calc_term(&self, tokens: &[Token], position: &mut usize) -> Result<f64, OctoError> {
+        let token = tokens
+            .get(*position)
+            .ok_or_else(|| self.error("incomplete :calc expression"))?;
+        *position += 1;
+        match token.text.as_str() {
+            "(" => {
+                let value = self.calc_expression(tokens, position)?;
+                match tokens.get(*position) {
+                    Some(t) if t.text == ")" => {
+                        *position += 1;
+                        Ok(value)
+                    }
+                    _ => Err(self.error("missing ')' in :calc")),
+                }
+            }
+            "-" => Ok(-self.calc_term(tokens, position)?),
+            "~" => Ok(!(self.calc_term(tokens, position)? as i64) as f64),
+            "HERE" => Ok(self.here as f64),
+            name => match self.constants.get(name) {
+                Some(&value) => Ok(value),
+                None => Ok(self.value(name)? as f64),
+            },
+        }
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Check that `address`, the target of `what`, fits into the 12-bit address operand of an
+/// instruction.
+fn check_address(address: u16, what: &str, line: usize) -> Result<(), OctoError> {
+    if address > 0xfff {
+        return Err(OctoError::Source {
+            line,
+            message: format!("address of {} is out of range", what),
+        });
+    }
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+pub enum OctoError {
+    #[error("cannot read {path}: {source}")]
+    Read { path: PathBuf, source: io::Error },
+
+    #[error("line {line}: {message}")]
+    Source { line: usize, message: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compile_program() {
+        let source = "
+            :alias counter v3
+            :const ROWS 5
+            :calc HALF { ROWS + 1 * 2 }     # right to left: 5 + 2
+            :macro draw X Y { sprite X Y ROWS }
+
+            : main
+                i := face
+                counter := 0
+                loop
+                    draw v0 v1
+                    counter += 1
+                    if counter != HALF then again   # jump back
+                if v0 key then clear
+                if counter >= 4 begin
+                    v0 := 1
+                else
+                    v0 := 2
+                end
+                exit
+            : face
+                0xF0 0x90 :byte { HALF }
+        ";
+        let assembly = compile(source).unwrap();
+        assert_eq!(
+            assembly.rom,
+            [
+                0x12, 0x02, // jump main
+                0xa2, 0x22, // i := face
+                0x63, 0x00, // counter := 0
+                0xd0, 0x15, // sprite v0 v1 5
+                0x73, 0x01, // counter += 1
+                0x33, 0x07, // skip if counter == 7
+                0x12, 0x06, // again
+                0xe0, 0xa1, // skip if v0 not pressed
+                0x00, 0xe0, // clear
+                0x6f, 0x04, // vf := 4
+                0x8f, 0x37, // vf =- counter
+                0x3f, 0x01, // skip if vf == 1
+                0x12, 0x1e, // jump else
+                0x60, 0x01, // v0 := 1
+                0x12, 0x20, // jump end
+                0x60, 0x02, // v0 := 2
+                0x00, 0xfd, // exit
+                0xf0, 0x90, 0x07,
+            ]
+        );
+        assert_eq!(assembly.symbols.get("main"), Some(0x202));
+        assert_eq!(assembly.symbols.get("face"), Some(0x222));
+        let source = assembly.symbols.source_line(0x208).unwrap();
+        assert_eq!((source.line, source.text.as_str()), (12, "counter += 1"));
+        assert_eq!(assembly.symbols.source_line(0x200), None);
+    }
+
+    #[test]
+    fn errors() {
+        let error = compile(": main\n  v0 := 1\n  jump nowhere\n").unwrap_err();
+        assert_eq!(error.to_string(), "line 3: undefined name 'nowhere'");
+        let error = compile(": main\n loop\n").unwrap_err();
+        assert!(matches!(error, OctoError::Source { line: 2, .. }));
+        let error = compile(": main\n v0 := 300").unwrap_err();
+        assert!(matches!(error, OctoError::Source { line: 2, .. }));
+        let error = compile(": main\n :byte 256").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "line 2: value 256 of '256' is out of range (-128 to 255)"
+        );
+        let error = compile(":const BIG 200\n: main\n :byte { BIG * 2 }").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "line 3: value 400 of 'BIG * 2' is out of range (-128 to 255)"
+        );
+        let rom = compile(": main :byte -1 :byte 0xff").unwrap().rom;
+        assert!(rom.ends_with(&[0xff, 0xff]));
+
+        // Backward references past 0xfff, only `i := long` reaches them
+        let far = ": main\n jump main\n:org 0x1000\n: far\n";
+        for (source, message) in [
+            ("jump far", "address of 'far' is out of range"),
+            (":call far", "address of 'far' is out of range"),
+            ("i := far", "address of 'far' is out of range"),
+            ("loop again", "address of 'loop' is out of range"),
+            ("if v0 == 0 begin end", "address of 'end' is out of range"),
+        ] {
+            let error = compile(&format!("{}{}\n", far, source)).unwrap_err();
+            assert_eq!(error.to_string(), format!("line 5: {}", message));
+        }
+        assert!(compile(&format!("{}i := long far\n", far)).is_ok());
+    }
+}
@@ -0,0 +1,410 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// A decoded CHIP-8, SUPER-CHIP or XO-CHIP instruction. `x` and `y` are register indices.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Opcode {
+    /// 00E0
+    ClearScreen,
+    /// 00EE
+    Return,
+    /// 00CN
+    ScrollDown(u8),
+    /// 00DN
+    ScrollUp(u8),
+    /// 00FB
+    ScrollRight,
+    /// 00FC
+    ScrollLeft,
+    /// 00FD
+    Exit,
+    /// 00FE
+    LowResolution,
+    /// 00FF
+    HighResolution,
+    /// 1NNN
+    Jump(u16),
+    /// 2NNN
+    Call(u16),
+    /// 3XNN
+    SkipIfEqual { x: u8, value: u8 },
+    /// 4XNN
+    SkipIfNotEqual { x: u8, value: u8 },
+    /// 5XY0
+    SkipIfRegistersEqual { x: u8, y: u8 },
+    /// 5XY2
+    SaveRange { x: u8, y: u8 },
+    /// 5XY3
+    LoadRange { x: u8, y: u8 },
+    /// 6XNN
+    SetRegister { x: u8, value: u8 },
+    /// 7XNN
+    AddToRegister { x: u8, value: u8 },
+    /// 8XY0
+    Assign { x: u8, y: u8 },
+    /// 8XY1
+    Or { x: u8, y: u8 },
+    /// 8XY2
+    And { x: u8, y: u8 },
+    /// 8XY3
+    Xor { x: u8, y: u8 },
+    /// 8XY4
+    Add { x: u8, y: u8 },
+    /// 8XY5
+    Subtract { x: u8, y: u8 },
+    /// 8XY6
+    ShiftRight { x: u8, y: u8 },
+    /// 8XY7
+    SubtractReverse { x: u8, y: u8 },
+    /// 8XYE
+    ShiftLeft { x: u8, y: u8 },
+    /// 9XY0
+    SkipIfRegistersNotEqual { x: u8, y: u8 },
+    /// ANNN
+    SetIndex(u16),
+    /// BNNN
+    JumpWithOffset(u16),
+    /// CXNN
+    Random { x: u8, mask: u8 },
+    /// DXYN
+    Draw { x: u8, y: u8, rows: u8 },
+    /// EX9E
+    SkipIfKey(u8),
+    /// EXA1
+    SkipIfNotKey(u8),
+    /// F000 NNNN
+    SetIndexLong(u16),
+    /// F002
+    LoadAudio,
+    /// FN01
+    SelectPlanes(u8),
+    /// FX07
+    GetDelay(u8),
+    /// FX0A
+    WaitKey(u8),
+    /// FX15
+    SetDelay(u8),
+    /// FX18
+    SetSound(u8),
+    /// FX1E
+    AddToIndex(u8),
+    /// FX29
+    Font(u8),
+    /// FX30
+    BigFont(u8),
+    /// FX33
+    Bcd(u8),
+    /// FX3A
+    SetPitch(u8),
+    /// FX55
+    Store(u8),
+    /// FX65
+    Load(u8),
+    /// FX75
+    StoreFlags(u8),
+    /// FX85
+    LoadFlags(u8),
+}
+
+impl Opcode {
+    /// Decode the two byte instruction `word`. The four byte instruction F000 NNNN cannot be
+    /// decoded from its first word alone, see [`Opcode::decode_with_operand`].
+    pub fn decode(word: u16) -> Result<Opcode, DecodeError> {
+        Self::decode_word(word, None)
+    }
+
+    /// Decode `word`, followed by the word `operand`, which is only used by F000 NNNN.
+    pub fn decode_with_operand(word: u16, operand: u16) -> Result<Opcode, DecodeError> {
+        Self::decode_word(word, Some(operand))
+    }
+
+    /// Decode the instruction at the start of `bytes`.
+    pub fn decode_bytes(bytes: &[u8]) -> Result<Opcode, DecodeError> {
+        let word = |offset: usize| {
+            let pair = bytes.get(offset..offset + 2)?;
+            Some(u16::from_be_bytes([pair[0], pair[1]]))
+        };
+        let opcode = word(0).ok_or(DecodeError::Truncated)?;
+        Self::decode_word(opcode, word(2))
+    }
+
+    fn decode_word(opcode: u16, next: Option<u16>) -> Result<Opcode, DecodeError> {
+        use Opcode::*;
+
+        let x = (opcode >> 8 & 0xf) as u8;
+        let y = (opcode >> 4 & 0xf) as u8;
+        let n = (opcode & 0xf) as u8;
+        let nn = (opcode & 0xff) as u8;
+        let nnn = opcode & 0xfff;
+
+        let instruction = match (opcode >> 12, x, y, n) {
+            (0x0, 0x0, 0xE, 0x0) => ClearScreen,
+            (0x0, 0x0, 0xE, 0xE) => Return,
+            (0x0, 0x0, 0xC, n) => ScrollDown(n),
+            (0x0, 0x0, 0xD, n) => ScrollUp(n),
+            (0x0, 0x0, 0xF, 0xB) => ScrollRight,
+            (0x0, 0x0, 0xF, 0xC) => ScrollLeft,
+            (0x0, 0x0, 0xF, 0xD) => Exit,
+            (0x0, 0x0, 0xF, 0xE) => LowResolution,
+            (0x0, 0x0, 0xF, 0xF) => HighResolution,
+            (0x1, ..) => Jump(nnn),
+            (0x2, ..) => Call(nnn),
+            (0x3, ..) => SkipIfEqual { x, value: nn },
+            (0x4, ..) => SkipIfNotEqual { x, value: nn },
+            (0x5, x, y, 0x0) => SkipIfRegistersEqual { x, y },
+            (0x5, x, y, 0x2) => SaveRange { x, y },
+            (0x5, x, y, 0x3) => LoadRange { x, y },
+            (0x6, ..) => SetRegister { x, value: nn },
+            (0x7, ..) => AddToRegister { x, value: nn },
+            (0x8, x, y, 0x0) => Assign { x, y },
+            (0x8, x, y, 0x1) => Or { x, y },
+            (0x8, x, y, 0x2) => And { x, y },
+            (0x8, x, y, 0x3) => Xor { x, y },
+            (0x8, x, y, 0x4) => Add { x, y },
+            (0x8, x, y, 0x5) => Subtract { x, y },
+            (0x8, x, y, 0x6) => ShiftRight { x, y },
+            (0x8, x, y, 0x7) => SubtractReverse { x, y },
+            (0x8, x, y, 0xE) => ShiftLeft { x, y },
+            (0x9, x, y, 0x0) => SkipIfRegistersNotEqual { x, y },
+            (0xA, ..) => SetIndex(nnn),
+            (0xB, ..) => JumpWithOffset(nnn),
+            (0xC, ..) => Random { x, mask: nn },
+            (0xD, x, y, n) => Draw { x, y, rows: n },
+            (0xE, x, 0x9, 0xE) => SkipIfKey(x),
+            (0xE, x, 0xA, 0x1) => SkipIfNotKey(x),
+            (0xF, 0x0, 0x0, 0x0) => SetIndexLong(next.ok_or(DecodeError::Truncated)?),
+            (0xF, 0x0, 0x0, 0x2) => LoadAudio,
+            (0xF, n, 0x0, 0x1) => SelectPlanes(n),
+            (0xF, x, 0x0, 0x7) => GetDelay(x),
+            (0xF, x, 0x0, 0xA) => WaitKey(x),
+            (0xF, x, 0x1, 0x5) => SetDelay(x),
+            (0xF, x, 0x1, 0x8) => SetSound(x),
+            (0xF, x, 0x1, 0xE) => AddToIndex(x),
+            (0xF, x, 0x2, 0x9) => Font(x),
+            (0xF, x, 0x3, 0x0) => BigFont(x),
+            (0xF, x, 0x3, 0x3) => Bcd(x),
+            (0xF, x, 0x3, 0xA) => SetPitch(x),
+            (0xF, x, 0x5, 0x5) => Store(x),
+            (0xF, x, 0x6, 0x5) => Load(x),
+            (0xF, x, 0x7, 0x5) => StoreFlags(x),
+            (0xF, x, 0x8, 0x5) => LoadFlags(x),
+            _ => return Err(DecodeError::Unknown(opcode)),
+        };
+        Ok(instruction)
+    }
+
+    /// Encode the instruction into its binary representation (two or four bytes).
+    pub fn encode(&self) -> Vec<u8> {
+        use Opcode::*;
+
+        let xyn = |base: u16, x: &u8, y: &u8, n: u16| {
+            base | (*x as u16 & 0xf) << 8 | (*y as u16 & 0xf) << 4 | n
+        };
+        let xnn = |base: u16, x: &u8, nn: &u8| base | (*x as u16 & 0xf) << 8 | *nn as u16;
+
+        let opcode = match self {
+            ClearScreen => 0x00E0,
+            Return => 0x00EE,
+            ScrollDown(n) => 0x00C0 | (*n as u16 & 0xf),
+            ScrollUp(n) => 0x00D0 | (*n as u16 & 0xf),
+            ScrollRight => 0x00FB,
+            ScrollLeft => 0x00FC,
+            Exit => 0x00FD,
+            LowResolution => 0x00FE,
+            HighResolution => 0x00FF,
+            Jump(a) => 0x1000 | (a & 0xfff),
+            Call(a) => 0x2000 | (a & 0xfff),
+            SkipIfEqual { x, value } => xnn(0x3000, x, value),
+            SkipIfNotEqual { x, value } => xnn(0x4000, x, value),
+            SkipIfRegistersEqual { x, y } => xyn(0x5000, x, y, 0x0),
+            SaveRange { x, y } => xyn(0x5000, x, y, 0x2),
+            LoadRange { x, y } => xyn(0x5000, x, y, 0x3),
+            SetRegister { x, value } => xnn(0x6000, x, value),
+            AddToRegister { x, value } => xnn(0x7000, x, value),
+            Assign { x, y } => xyn(0x8000, x, y, 0x0),
+            Or { x, y } => xyn(0x8000, x, y, 0x1),
+            And { x, y } => xyn(0x8000, x, y, 0x2),
+            Xor { x, y } => xyn(0x8000, x, y, 0x3),
+            Add { x, y } => xyn(0x8000, x, y, 0x4),
+            Subtract { x, y } => xyn(0x8000, x, y, 0x5),
+            ShiftRight { x, y } => xyn(0x8000, x, y, 0x6),
+            SubtractReverse { x, y } => xyn(0x8000, x, y, 0x7),
+            ShiftLeft { x, y } => xyn(0x8000, x, y, 0xE),
+            SkipIfRegistersNotEqual { x, y } => xyn(0x9000, x, y, 0x0),
+            SetIndex(a) => 0xA000 | (a & 0xfff),
+            JumpWithOffset(a) => 0xB000 | (a & 0xfff),
+            Random { x, mask } => xnn(0xC000, x, mask),
+            Draw { x, y, rows } => xyn(0xD000, x, y, *rows as u16 & 0xf),
+            SkipIfKey(x) => xnn(0xE000, x, &0x9E),
+            SkipIfNotKey(x) => xnn(0xE000, x, &0xA1),
+            SetIndexLong(a) => {
+                let [high, low] = a.to_be_bytes();
+                return vec![0xF0, 0x00, high, low];
+            }
+            LoadAudio => 0xF002,
+            SelectPlanes(n) => xnn(0xF000, n, &0x01),
+            GetDelay(x) => xnn(0xF000, x, &0x07),
+            WaitKey(x) => xnn(0xF000, x, &0x0A),
+            SetDelay(x) => xnn(0xF000, x, &0x15),
+            SetSound(x) => xnn(0xF000, x, &0x18),
+            AddToIndex(x) => xnn(0xF000, x, &0x1E),
+            Font(x) => xnn(0xF000, x, &0x29),
+            BigFont(x) => xnn(0xF000, x, &0x30),
+            Bcd(x) => xnn(0xF000, x, &0x33),
+            SetPitch(x) => xnn(0xF000, x, &0x3A),
+            Store(x) => xnn(0xF000, x, &0x55),
+            Load(x) => xnn(0xF000, x, &0x65),
+            StoreFlags(x) => xnn(0xF000, x, &0x75),
+            LoadFlags(x) => xnn(0xF000, x, &0x85),
+        };
+        opcode.to_be_bytes().to_vec()
+    }
+
+    /// Length of the instruction in bytes.
+    pub fn size(&self) -> u16 {
+        match self {
+            Opcode::SetIndexLong(_) => 4,
+            _ => 2,
+        }
+    }
+
+    /// Return true if the instruction conditionally skips the next instruction.
+    pub fn is_skip(&self) -> bool {
+        use Opcode::*;
+        matches!(
+            self,
+            SkipIfEqual { .. }
+                | SkipIfNotEqual { .. }
+                | SkipIfRegistersEqual { .. }
+                | SkipIfRegistersNotEqual { .. }
+                | SkipIfKey(_)
+                | SkipIfNotKey(_)
+        )
+    }
+
+    /// The instruction set extension which introduced the instruction.
+    pub fn extension(&self) -> Extension {
+        use Opcode::*;
+        match self {
+            ScrollDown(_) | ScrollRight | ScrollLeft | Exit | LowResolution | HighResolution
+            | BigFont(_) | StoreFlags(_) | LoadFlags(_) => Extension::SuperChip,
+            ScrollUp(_)
+            | SaveRange { .. }
+            | LoadRange { .. }
+            | SetIndexLong(_)
+            | LoadAudio
+            | SelectPlanes(_)
+            | SetPitch(_) => Extension::XoChip,
+            _ => Extension::Chip8,
+        }
+    }
+
+    /// Return true if the instruction can be executed by an interpreter supporting `extension`.
+    /// Each extension includes the instructions of the previous ones.
+    pub fn is_supported(&self, extension: Extension) -> bool {
+        self.extension() <= extension
+    }
+}
+
+/// The instruction sets, ordered such that each one is a superset of the previous one.
+#[derive(
+    Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum Extension {
+    /// The original CHIP-8 instructions
+    Chip8,
+    /// SUPER-CHIP 1.1: high resolution, scrolling, big font and RPL user flags
+    SuperChip,
+    /// XO-CHIP: two drawing planes, audio patterns, 16 bit addresses and register ranges
+    #[default]
+    XoChip,
+}
+
+impl fmt::Display for Extension {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Extension::Chip8 => "CHIP-8",
+            Extension::SuperChip => "SUPER-CHIP",
+            Extension::XoChip => "XO-CHIP",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Error, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    #[error("unknown instruction '{0:#06x}'")]
+    Unknown(u16),
+
+    #[error("instruction is truncated")]
+    Truncated,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode() {
+        use Opcode::*;
+        assert_eq!(Opcode::decode(0x00e0), Ok(ClearScreen));
+        assert_eq!(Opcode::decode(0x00c4), Ok(ScrollDown(4)));
+        assert_eq!(Opcode::decode(0x8ab6), Ok(ShiftRight { x: 0xa, y: 0xb }));
+        assert_eq!(Opcode::decode(0x8ab8), Err(DecodeError::Unknown(0x8ab8)));
+        assert_eq!(
+            Opcode::decode_with_operand(0xf000, 0x1234),
+            Ok(SetIndexLong(0x1234))
+        );
+        assert_eq!(Opcode::decode(0xf000), Err(DecodeError::Truncated));
+        assert_eq!(
+            Opcode::decode_bytes(&[0xf0, 0x00, 0x12]),
+            Err(DecodeError::Truncated)
+        );
+        assert_eq!(Opcode::decode(0xf301), Ok(SelectPlanes(3)));
+        assert_eq!(Opcode::decode(0x0123), Err(DecodeError::Unknown(0x0123)));
+        // The operand is ignored by two byte instructions
+        assert_eq!(Opcode::decode_with_operand(0x1234, 0xffff), Ok(Jump(0x234)));
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        for word in 0..=u16::MAX {
+            let expected = word.to_be_bytes();
+            match Opcode::decode(word) {
+                Ok(opcode) => {
+                    assert_eq!(opcode.size(), 2, "{:04X}", word);
+                    assert_eq!(opcode.encode(), expected, "{:04X}", word);
+                    assert_eq!(Opcode::decode_bytes(&opcode.encode()), Ok(opcode));
+                }
+                Err(DecodeError::Unknown(unknown)) => assert_eq!(unknown, word),
+                Err(DecodeError::Truncated) => {
+                    let opcode = Opcode::decode_with_operand(word, 0x1234).unwrap();
+                    assert_eq!(opcode.size(), 4);
+                    assert_eq!(opcode.encode(), [expected[0], expected[1], 0x12, 0x34]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn extensions() {
+        let supported = |extension| {
+            (0..=u16::MAX)
+                .filter_map(|word| Opcode::decode_with_operand(word, 0).ok())
+                .filter(|opcode| opcode.is_supported(extension))
+                .count()
+        };
+        assert!(supported(Extension::Chip8) < supported(Extension::SuperChip));
+        assert!(supported(Extension::SuperChip) < supported(Extension::XoChip));
+
+        let extension = |word| Opcode::decode(word).unwrap().extension();
+        assert_eq!(extension(0x00ff), Extension::SuperChip);
+        assert_eq!(extension(0x00d1), Extension::XoChip);
+        assert_eq!(extension(0xd120), Extension::Chip8);
+        assert!(!Opcode::LoadAudio.is_supported(Extension::SuperChip));
+        assert!(Opcode::BigFont(1).is_supported(Extension::XoChip));
+    }
+}
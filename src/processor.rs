@@ -1,34 +1,60 @@
 use std::collections::HashSet;
 
-use rand::random;
+use rand::{Rng, SeedableRng};
+use rand_pcg::Pcg32;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+use crate::opcode::{DecodeError, Extension, Opcode};
+use crate::symbols::SymbolMap;
+
 pub const NUM_VARIABLE_REGISTERS: u8 = 16;
 pub const FLAG_REGISTER_INDEX: u8 = 0xF;
 pub const ROM_START_ADDR: u16 = 0x200;
 pub const FONT_START_ADDR: u16 = 0x50;
+pub const BIG_FONT_START_ADDR: u16 = 0xA0;
 pub const DISPLAY_WIDTH: u8 = 64;
 pub const DISPLAY_HEIGHT: u8 = 32;
-pub const MEMORY_SIZE: usize = 4096;
+pub const HIRES_DISPLAY_WIDTH: u8 = 128;
+pub const HIRES_DISPLAY_HEIGHT: u8 = 64;
+pub const MEMORY_SIZE: usize = 0x10000;
+pub const CLASSIC_MEMORY_SIZE: usize = 0x1000;
 pub const NUM_FONT_CHARS: u8 = 16;
 pub const BYTES_PER_CHAR: u8 = 5;
+pub const BYTES_PER_BIG_CHAR: u8 = 10;
+pub const NUM_RPL_FLAGS: u8 = 16;
+pub const NUM_PLANES: u8 = 2;
+pub const AUDIO_PATTERN_SIZE: u8 = 16;
+pub const DEFAULT_PITCH: u8 = 64;
 
 /// The core of the CHIP-8 emulator. Contains memory, stack, register and instructions execution.
-#[derive(Default)]
+/// The complete state can be serialized, see the `savestate` module.
+#[derive(Serialize, Deserialize)]
 pub struct Processor {
     program_data: Vec<u8>,
     settings: InstructionSettings,
     memory: Vec<u8>,
     stack: Vec<u16>,
     program_counter: u16,
-    display: Vec<bool>,
+    display: Vec<u8>,
+    hires: bool,
+    selected_planes: u8,
     index_register: u16,
     variable_registers: [u8; NUM_VARIABLE_REGISTERS as usize],
     delay_timer: u8,
     sound_timer: u8,
+    audio_pattern: Option<[u8; AUDIO_PATTERN_SIZE as usize]>,
+    pitch: u8,
+    rpl_flags: [u8; NUM_RPL_FLAGS as usize],
     blocking: Option<BlockingState>,
+    waiting_for_vblank: bool,
+    exited: bool,
     keys: HashSet<Key>,
+    seed: u64,
+    rng: Pcg32,
+    /// Derived from memory and settings, hence not part of the state
+    #[serde(skip)]
+    decode_cache: DecodeCache,
 }
 
 impl Processor {
@@ -51,44 +77,116 @@ impl Processor {
         0xF0, 0x80, 0xF0, 0x80, 0x80, // F
     ];
 
-    /// Create a new processor with default settings.
-    pub fn new() -> Self {
-        let mut memory = vec![0; MEMORY_SIZE];
-
-        // Copy font to memory, from 0x50 to 0x9F (incl.) resp. A0 (excl.)
-        let start = ROM_START_ADDR as usize;
-        let end = start + BYTES_PER_CHAR as usize * NUM_FONT_CHARS as usize;
-        memory[start..end].copy_from_slice(&Self::FONT);
+    /// The SUPER-CHIP 8x10 font. The original only defines the digits 0 to 9, the letters A to F
+    /// are taken from Octo.
+    const BIG_FONT: [u8; 160] = [
+        0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, // 0
+        0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, // 1
+        0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, // 2
+        0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, // 3
+        0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, // 4
+        0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, // 5
+        0x3E, 0x7C, 0xE0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, // 6
+        0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, // 7
+        0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, // 8
+        0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C, // 9
+        0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+        0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+        0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+        0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+        0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+        0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+    ];
 
+    /// Create a new processor with default settings and a random seed.
+    pub fn new() -> Self {
+        let seed = rand::random();
         Processor {
             program_data: vec![],
             settings: InstructionSettings::default(),
-            memory,
+            memory: Self::initial_memory(),
             stack: vec![],
             program_counter: ROM_START_ADDR,
-            display: vec![false; DISPLAY_WIDTH as usize * DISPLAY_HEIGHT as usize],
+            display: vec![0; DISPLAY_WIDTH as usize * DISPLAY_HEIGHT as usize],
+            hires: false,
+            selected_planes: 1,
             index_register: 0,
             variable_registers: [0; NUM_VARIABLE_REGISTERS as usize],
             delay_timer: 0,
             sound_timer: 0,
+            audio_pattern: None,
+            pitch: DEFAULT_PITCH,
+            rpl_flags: [0; NUM_RPL_FLAGS as usize],
             blocking: None,
+            waiting_for_vblank: false,
+            exited: false,
             keys: HashSet::new(),
+            seed,
+            rng: Pcg32::seed_from_u64(seed),
+            decode_cache: DecodeCache::default(),
         }
     }
 
+    /// Create the memory content after a reset, i.e. an empty memory with the fonts loaded.
+    fn initial_memory() -> Vec<u8> {
+        let mut memory = vec![0; MEMORY_SIZE];
+
+        // Copy font to memory, from 0x50 to 0x9F (incl.) resp. A0 (excl.)
+        let start = FONT_START_ADDR as usize;
+        let end = start + BYTES_PER_CHAR as usize * NUM_FONT_CHARS as usize;
+        memory[start..end].copy_from_slice(&Self::FONT);
+
+        // Copy big font to memory, from 0xA0 to 0x13F (incl.) resp. 0x140 (excl.)
+        let start = BIG_FONT_START_ADDR as usize;
+        let end = start + BYTES_PER_BIG_CHAR as usize * NUM_FONT_CHARS as usize;
+        memory[start..end].copy_from_slice(&Self::BIG_FONT);
+
+        memory
+    }
+
     /// Configure how certain instructions are interpreted.
     pub fn load_settings(&mut self, settings: InstructionSettings) {
         self.settings = settings;
+        // The supported instructions may have changed
+        self.decode_cache.clear();
+    }
+
+    /// Enable or disable the cache of decoded instructions (enabled by default). Without the
+    /// cache, every instruction is fetched and decoded again each time it is executed, which is
+    /// only useful for comparisons.
+    pub fn set_decode_cache(&mut self, enabled: bool) {
+        self.decode_cache.enabled = enabled;
+        self.decode_cache.clear();
+    }
+
+    /// Replace the state with `state`, e.g. one restored from a save state, keeping the decode
+    /// cache setting of this processor.
+    pub fn restore(&mut self, state: Processor) {
+        let decode_cache = self.decode_cache.enabled;
+        *self = state;
+        self.set_decode_cache(decode_cache);
+    }
+
+    /// Seed the random number generator used by CXNN. The generator is re-seeded with this seed
+    /// whenever a program is loaded, so that runs are reproducible.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng = Pcg32::seed_from_u64(seed);
+    }
+
+    /// Get the seed of the random number generator.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// The ROM loaded last.
+    pub fn program_data(&self) -> &[u8] {
+        &self.program_data
     }
 
     /// Reset the emulator (memory, registers, etc.) and load a ROM.
     pub fn load_program(&mut self, program_data: Vec<u8>) -> Result<(), EmulatorError> {
-        let mut memory = vec![0; MEMORY_SIZE];
-
-        // Copy font to memory, from 0x50 to 0x9F (incl.) resp. A0 (excl.)
-        let start = ROM_START_ADDR as usize;
-        let end = start + BYTES_PER_CHAR as usize * NUM_FONT_CHARS as usize;
-        memory[start..end].copy_from_slice(&Self::FONT);
+        let mut memory = Self::initial_memory();
 
         // Copy rom to memory, starting at 0x0200
         let rom_size = program_data.len();
@@ -101,14 +199,23 @@ impl Processor {
 
         self.program_data = program_data;
         self.memory = memory;
+        self.decode_cache.clear();
         self.stack = vec![];
         self.program_counter = ROM_START_ADDR;
-        self.display = vec![false; DISPLAY_WIDTH as usize * DISPLAY_HEIGHT as usize];
+        self.display = vec![0; DISPLAY_WIDTH as usize * DISPLAY_HEIGHT as usize];
+        self.hires = false;
+        self.selected_planes = 1;
         self.index_register = 0;
         self.variable_registers = [0; NUM_VARIABLE_REGISTERS as usize];
         self.delay_timer = 0;
         self.sound_timer = 0;
-        // settings stay unchanged
+        self.audio_pattern = None;
+        self.pitch = DEFAULT_PITCH;
+        self.blocking = None;
+        self.waiting_for_vblank = false;
+        self.exited = false;
+        self.rng = Pcg32::seed_from_u64(self.seed);
+        // settings, seed and RPL flags stay unchanged
 
         Ok(())
     }
@@ -116,322 +223,550 @@ impl Processor {
     /// Perform a single step, i.e. load the instruction at the program counter (PC) and
     /// execute it.
     pub fn step(&mut self) -> Result<(), EmulatorError> {
-        // Fetch
+        // After 00FD the processor stays put until a new program is loaded
+        if self.exited {
+            return Ok(());
+        }
+
+        // With the display wait quirk, execution resumes with the next timer tick after a draw
+        if self.waiting_for_vblank {
+            return Ok(());
+        }
+
         let address = self.program_counter;
-        let pc = self.program_counter as usize;
-        let instruction = u16::from_be_bytes([self.memory[pc], self.memory[pc + 1]]);
-        self.program_counter += 2;
+        let opcode = match self.decode_cache.get(address) {
+            Some(opcode) => opcode,
+            None => self.fetch_and_decode(address)?,
+        };
+        self.program_counter = address.wrapping_add(opcode.size());
 
-        self.execute(instruction)
+        self.execute(opcode).map_err(|source| {
+            let code = opcode.encode();
+            EmulatorError::Execution {
+                address,
+                instruction: u16::from_be_bytes([code[0], code[1]]),
+                location: None,
+                source,
+            }
+        })
+    }
+
+    /// Fetch and decode the instruction at `address` and add it to the decode cache.
+    fn fetch_and_decode(&mut self, address: u16) -> Result<Opcode, EmulatorError> {
+        let instruction = self
+            .fetch(address)
             .map_err(|source| EmulatorError::Execution {
                 address,
-                instruction,
+                instruction: 0,
+                location: None,
                 source,
-            })
+            })?;
+        self.program_counter = address.wrapping_add(2);
+
+        let opcode =
+            self.decode(address, instruction)
+                .map_err(|source| EmulatorError::Execution {
+                    address,
+                    instruction,
+                    location: None,
+                    source,
+                })?;
+        self.decode_cache.insert(address, opcode);
+        Ok(opcode)
     }
 
-    /// Execute a single instruction, i.e. decode the opcode and act on it.
-    fn execute(&mut self, instruction: u16) -> Result<(), ExecutionError> {
-        // Decode
-        let nibbles = [
-            nibble(instruction, 0),
-            nibble(instruction, 1),
-            nibble(instruction, 2),
-            nibble(instruction, 3),
-        ];
+    /// Read the two bytes of the instruction at `address`.
+    fn fetch(&self, address: u16) -> Result<u16, ExecutionError> {
+        let high = self.memory(address)?;
+        let low = self.memory(offset_address(address, 1)?)?;
+        Ok(u16::from_be_bytes([high, low]))
+    }
 
-        // Execute
-        if instruction == 0x00e0 {
-            // 00E0 - clear screen
-            self.display = vec![false; DISPLAY_WIDTH as usize * DISPLAY_HEIGHT as usize];
-        } else if instruction == 0x00ee {
-            // 00EE - return from subroutine by popping the last address from the stack
-            if let Some(address) = self.stack.pop() {
+    /// Decode `instruction`, which was fetched from `address`, and check that it is part of the
+    /// instruction set of the configured platform. The operand of F000 NNNN is fetched from the
+    /// following two bytes.
+    fn decode(&self, address: u16, instruction: u16) -> Result<Opcode, ExecutionError> {
+        let opcode = match Opcode::decode(instruction) {
+            Err(DecodeError::Truncated) => {
+                let operand = self.fetch(offset_address(address, 2)?)?;
+                Opcode::decode_with_operand(instruction, operand)
+            }
+            decoded => decoded,
+        }
+        .map_err(|_| ExecutionError::UnknownInstruction(instruction))?;
+
+        if !opcode.is_supported(self.settings.extension) {
+            return Err(ExecutionError::UnsupportedInstruction {
+                instruction,
+                extension: opcode.extension(),
+            });
+        }
+        Ok(opcode)
+    }
+
+    /// Execute a single decoded instruction. The program counter already points to the next
+    /// instruction.
+    fn execute(&mut self, opcode: Opcode) -> Result<(), ExecutionError> {
+        match opcode {
+            Opcode::ClearScreen => {
+                // 00E0 - clear screen (only the selected planes)
+                self.clear_planes(self.selected_planes);
+            }
+            Opcode::ScrollDown(n) => {
+                // 00CN - scroll the display down by N pixels (SUPER-CHIP)
+                self.scroll(0, n as i16);
+            }
+            Opcode::ScrollUp(n) => {
+                // 00DN - scroll the display up by N pixels (XO-CHIP)
+                self.scroll(0, -(n as i16));
+            }
+            Opcode::ScrollRight => {
+                // 00FB - scroll the display right by 4 pixels (SUPER-CHIP)
+                self.scroll(4, 0);
+            }
+            Opcode::ScrollLeft => {
+                // 00FC - scroll the display left by 4 pixels (SUPER-CHIP)
+                self.scroll(-4, 0);
+            }
+            Opcode::Exit => {
+                // 00FD - exit the interpreter (SUPER-CHIP). Stay at the current instruction.
+                self.exited = true;
+                self.program_counter = self.program_counter.wrapping_sub(2);
+            }
+            Opcode::LowResolution => {
+                // 00FE - switch to low resolution mode, 64x32 (SUPER-CHIP)
+                self.hires = false;
+                self.clear_planes(0b11);
+            }
+            Opcode::HighResolution => {
+                // 00FF - switch to high resolution mode, 128x64 (SUPER-CHIP)
+                self.hires = true;
+                self.clear_planes(0b11);
+            }
+            Opcode::Return => {
+                // 00EE - return from subroutine by popping the last address from the stack
+                let address = self.stack.pop().ok_or(ExecutionError::StackUnderflow)?;
+                self.program_counter = address;
+            }
+            Opcode::Jump(address) => {
+                // 1NNN - jump NNN
+                self.program_counter = address;
+            }
+            Opcode::Call(address) => {
+                // 2NNN - Call subroutine at NNN
+                self.stack.push(self.program_counter);
                 self.program_counter = address;
-            } else {
-                return Err(ExecutionError::StackUnderflow);
-            }
-        } else if nibbles[0] == 1 {
-            // 1NNN - jump NNN
-            self.program_counter = instruction & 0x0fff;
-        } else if nibbles[0] == 2 {
-            // 2NNN - Call subroutine at NNN
-            self.stack.push(self.program_counter);
-            self.program_counter = instruction & 0x0fff;
-        } else if nibbles[0] == 3 {
-            // 3XNN - SKip one instruction if VX is equal to NN
-            let register_index = nibbles[1] as usize;
-            let register_value = self.variable_registers[register_index];
-            let immediate_value = (instruction & 0x00ff) as u8;
-            if register_value == immediate_value {
-                self.program_counter += 2;
-            }
-        } else if nibbles[0] == 4 {
-            // 4XNN - SKip one instruction if VX is not equal to NN
-            let register_index = nibbles[1] as usize;
-            let register_value = self.variable_registers[register_index];
-            let immediate_value = (instruction & 0x00ff) as u8;
-            if register_value != immediate_value {
-                self.program_counter += 2;
-            }
-        } else if nibbles[0] == 5 {
-            // 5Xy0 - SKip one instruction if VX is equal to VY
-            let register_index_x = nibbles[1] as usize;
-            let register_index_y = nibbles[2] as usize;
-            let register_value_x = self.variable_registers[register_index_x];
-            let register_value_y = self.variable_registers[register_index_y];
-            if register_value_x == register_value_y {
-                self.program_counter += 2;
-            }
-        } else if nibbles[0] == 6 {
-            // 6XNN - set register VX to NN
-            let register_index = nibbles[1] as usize;
-            let value = (instruction & 0x00ff) as u8;
-            self.variable_registers[register_index] = value;
-        } else if nibbles[0] == 7 {
-            // 7XNN - add value NN to register VX
-            let register_index = nibbles[1] as usize;
-            let value = (instruction & 0x00ff) as u8;
-            self.variable_registers[register_index] = self.variable_registers[register_index]
-                .overflowing_add(value)
-                .0;
-            // Ignore overflow indicator
-        } else if nibbles[0] == 8 {
-            // Arithmetic and logic instructions
-            let register_index_x = nibbles[1];
-            // let register_index_y = nibbles[2];
-            let register_value_x = self.register(nibbles[1])?;
-            let register_value_y = self.register(nibbles[2])?;
-
-            if nibbles[3] == 0 {
+            }
+            Opcode::SkipIfEqual { x, value } => {
+                // 3XNN - SKip one instruction if VX is equal to NN
+                if self.register(x)? == value {
+                    self.skip_instruction();
+                }
+            }
+            Opcode::SkipIfNotEqual { x, value } => {
+                // 4XNN - SKip one instruction if VX is not equal to NN
+                if self.register(x)? != value {
+                    self.skip_instruction();
+                }
+            }
+            Opcode::SkipIfRegistersEqual { x, y } => {
+                // 5XY0 - SKip one instruction if VX is equal to VY
+                if self.register(x)? == self.register(y)? {
+                    self.skip_instruction();
+                }
+            }
+            Opcode::SaveRange { x, y } => {
+                // 5XY2 - store registers VX to VY (possibly in reverse order) in memory pointed to
+                // by the index register, which is not changed (XO-CHIP)
+                for (offset, register_index) in register_range(x, y).enumerate() {
+                    let address = offset_address(self.index_register, offset)?;
+                    self.set_memory(address, self.register(register_index)?)?;
+                }
+            }
+            Opcode::LoadRange { x, y } => {
+                // 5XY3 - load registers VX to VY (possibly in reverse order) from memory pointed
+                // to by the index register, which is not changed (XO-CHIP)
+                for (offset, register_index) in register_range(x, y).enumerate() {
+                    let address = offset_address(self.index_register, offset)?;
+                    self.set_register(register_index, self.memory(address)?)?;
+                }
+            }
+            Opcode::SetRegister { x, value } => {
+                // 6XNN - set register VX to NN
+                self.set_register(x, value)?;
+            }
+            Opcode::AddToRegister { x, value } => {
+                // 7XNN - add value NN to register VX. The carry flag is not changed.
+                self.set_register(x, self.register(x)?.wrapping_add(value))?;
+            }
+            Opcode::Assign { x, y } => {
                 // 8XY0 - set VX to the value of VY
-                self.set_register(register_index_x, register_value_y)?;
-            } else if nibbles[3] == 1 {
+                self.set_register(x, self.register(y)?)?;
+            }
+            Opcode::Or { x, y } => {
                 // 8XY1 - set VX to the bitwise OR of VX and VY
-                self.set_register(register_index_x, register_value_x | register_value_y)?;
-            } else if nibbles[3] == 2 {
+                self.set_register(x, self.register(x)? | self.register(y)?)?;
+                if self.settings.reset_vf_in_8xy1_8xy2_8xy3 {
+                    self.set_flag_register(0);
+                }
+            }
+            Opcode::And { x, y } => {
                 // 8XY2 - set VX to the bitwise AND of VX and VY
-                self.set_register(register_index_x, register_value_x & register_value_y)?;
-            } else if nibbles[3] == 3 {
+                self.set_register(x, self.register(x)? & self.register(y)?)?;
+                if self.settings.reset_vf_in_8xy1_8xy2_8xy3 {
+                    self.set_flag_register(0);
+                }
+            }
+            Opcode::Xor { x, y } => {
                 // 8XY3 - set VX to the bitwise XOR of VX and VY
-                self.set_register(register_index_x, register_value_x ^ register_value_y)?;
-            } else if nibbles[3] == 4 {
+                self.set_register(x, self.register(x)? ^ self.register(y)?)?;
+                if self.settings.reset_vf_in_8xy1_8xy2_8xy3 {
+                    self.set_flag_register(0);
+                }
+            }
+            Opcode::Add { x, y } => {
                 // 8XY4 - set VX to the sum of VX and VY
-                let (result, overflow) = register_value_x.overflowing_add(register_value_y);
-                self.set_register(register_index_x, result)?;
+                let (result, overflow) = self.register(x)?.overflowing_add(self.register(y)?);
+                self.set_register(x, result)?;
                 self.set_flag_register(overflow as u8);
-            } else if nibbles[3] == 5 {
+            }
+            Opcode::Subtract { x, y } => {
                 // 8XY5 - set VX to the result of VX - VY
-                let (result, overflow) = register_value_x.overflowing_sub(register_value_y);
-                self.set_register(register_index_x, result)?;
+                let (result, overflow) = self.register(x)?.overflowing_sub(self.register(y)?);
+                self.set_register(x, result)?;
                 self.set_flag_register((!overflow) as u8);
-            } else if nibbles[3] == 6 {
+            }
+            Opcode::ShiftRight { x, y } => {
                 // 8XY6 Shift the value of VX one bit to the right
                 if self.settings.use_vy_in_8xy6 {
-                    self.set_register(register_index_x, register_value_y)?;
+                    self.set_register(x, self.register(y)?)?;
                 }
-                let value = self.register(register_index_x)?;
-                let lowest_bit = value & 0b1;
-                let result = value >> 1;
-                self.set_flag_register(lowest_bit);
-                self.set_register(register_index_x, result)?;
-            } else if nibbles[3] == 7 {
+                let value = self.register(x)?;
+                self.set_flag_register(value & 0b1);
+                self.set_register(x, value >> 1)?;
+            }
+            Opcode::SubtractReverse { x, y } => {
                 // 8XY7 - set VX to the result of VY - VX
-                let (result, overflow) = register_value_y.overflowing_sub(register_value_x);
-                self.set_register(register_index_x, result)?;
+                let (result, overflow) = self.register(y)?.overflowing_sub(self.register(x)?);
+                self.set_register(x, result)?;
                 self.set_flag_register((!overflow) as u8);
             }
-            // Note instructions ending with 8 to D are not defined in the instruction set.
-            else if nibbles[3] == 0xE {
-                // 8XY6 Shift the value of VX one bit to the left
+            Opcode::ShiftLeft { x, y } => {
+                // 8XYE Shift the value of VX one bit to the left
                 if self.settings.use_vy_in_8xye {
-                    self.set_register(register_index_x, register_value_y)?;
+                    self.set_register(x, self.register(y)?)?;
                 }
-                let value = self.register(register_index_x)?;
-                let highest_bit = value & 0b10000000; // == 0x80
-                let flag = highest_bit >> 7;
-                let result = value << 1;
-                self.set_flag_register(flag);
-                self.set_register(register_index_x, result)?;
-            }
-        } else if nibbles[0] == 9 {
-            // 9Xy0 - SKip one instruction if VX is not equal to VY
-            let register_index_x = nibbles[1] as usize;
-            let register_index_y = nibbles[2] as usize;
-            let register_value_x = self.variable_registers[register_index_x];
-            let register_value_y = self.variable_registers[register_index_y];
-            if register_value_x != register_value_y {
-                self.program_counter += 2;
-            }
-        } else if nibbles[0] == 0xA {
-            // ANNN - set index register to value NNN
-            let value = instruction & 0x0fff;
-            self.index_register = value;
-        } else if nibbles[0] == 0xB {
-            // BNNN - jump address NNN plus the value in V0
-            let value = instruction & 0x0fff;
-            if self.settings.use_bxnn_instead_bnnn {
-                // BXNN
-                let register_index_x = nibbles[1];
-                let register_value_x = self.register(register_index_x)?;
-                self.program_counter = value + register_value_x as u16;
-            } else {
-                self.program_counter = value + self.variable_registers[0] as u16;
-            }
-        } else if nibbles[0] == 0xC {
-            // CXNN - generate random number, AND it with NN, store in VX
-            let random_number: u8 = random();
-            let value = (instruction & 0x00FF) as u8;
-            //*self.register_mut(nibbles[1])? = random_number & value;
-            self.set_register(nibbles[1], random_number & value)?;
-        } else if nibbles[0] == 0xD {
-            // DXYN - Draw an N pixels tall sprite from the memory location that the index register
-            // is holding to the screen at the x coordinate in VX and y coordinate in VY.
-            let register_index = nibbles[1] as usize;
-            let dx = self.variable_registers[register_index];
-            let register_index = nibbles[2] as usize;
-            let dy = self.variable_registers[register_index];
-
-            let rows = nibbles[3];
-            // Take modulo operation on the x and y coordinates
-            let mut dx = dx % DISPLAY_WIDTH;
-            let dx_orig = dx;
-            let mut dy = dy % DISPLAY_HEIGHT;
-
-            // Clear VF
-            self.variable_registers[0xf] = 0;
-
-            // Loop over sprite rows, 1 sprite row = 1 byte = 8 pixels
-            for n in 0..rows {
-                let sprite_row = self.memory[self.index_register as usize + n as usize];
-                for i in 0..8 {
-                    let bit = sprite_row >> (7 - i) & 1;
-                    let pixel =
-                        &mut self.display[dy as usize * DISPLAY_WIDTH as usize + dx as usize];
-                    if bit == 1 && *pixel {
-                        *pixel = false;
-                        self.variable_registers[0xf] = 1;
-                    } else if bit == 1 && !(*pixel) {
-                        *pixel = true;
-                    }
-                    dx += 1;
-                    if dx >= DISPLAY_WIDTH {
-                        break;
-                    }
+                let value = self.register(x)?;
+                self.set_flag_register(value >> 7);
+                self.set_register(x, value << 1)?;
+            }
+            Opcode::SkipIfRegistersNotEqual { x, y } => {
+                // 9XY0 - SKip one instruction if VX is not equal to VY
+                if self.register(x)? != self.register(y)? {
+                    self.skip_instruction();
                 }
-                dy += 1;
-                if dy >= DISPLAY_HEIGHT {
-                    break;
+            }
+            Opcode::SetIndex(address) => {
+                // ANNN - set index register to value NNN
+                self.index_register = address;
+            }
+            Opcode::JumpWithOffset(address) => {
+                // BNNN - jump address NNN plus the value in V0
+                let offset = if self.settings.use_bxnn_instead_bnnn {
+                    // BXNN - jump to address XNN plus the value in VX
+                    self.register((address >> 8) as u8)?
+                } else {
+                    self.register(0)?
+                };
+                self.program_counter = address + offset as u16;
+            }
+            Opcode::Random { x, mask } => {
+                // CXNN - generate random number, AND it with NN, store in VX
+                let random_number: u8 = self.rng.gen();
+                self.set_register(x, random_number & mask)?;
+            }
+            Opcode::Draw { x, y, rows } => {
+                // DXYN - Draw an N pixels tall sprite from the memory location that the index
+                // register is holding to the screen at the x coordinate in VX and y coordinate in
+                // VY.
+                // DXY0 - Draw a 16x16 sprite (SUPER-CHIP)
+                let dx = self.register(x)?;
+                let dy = self.register(y)?;
+                if rows == 0 {
+                    self.draw_sprite(dx, dy, 16, 16)?;
+                } else {
+                    self.draw_sprite(dx, dy, 8, rows)?;
                 }
-                dx = dx_orig;
-            }
-        } else if nibbles[0] == 0xE {
-            if nibbles[2] == 0x9 && nibbles[3] == 0xE {
-                // EX9E - Skip the next instruction if the key corresponding to the value in VX is currently pressed
-                let value = self.register(nibbles[1])?;
-                if let Ok(key) = Key::try_from(value) {
-                    if self.keys.contains(&key) {
-                        self.program_counter += 2;
-                    }
+                if self.settings.display_wait {
+                    self.waiting_for_vblank = true;
                 }
-                // Maybe emit a warning if the value in register VX is > 16 and hence cannot be represented as a key
-            } else if nibbles[2] == 0xA && nibbles[3] == 0x1 {
-                // EXA1 - Skip the next instruction if the key corresponding to the value in VX is currently not pressed
-                let value = self.register(nibbles[1])?;
-                if let Ok(key) = Key::try_from(value) {
-                    if !self.keys.contains(&key) {
-                        self.program_counter += 2;
-                    }
+            }
+            Opcode::SkipIfKey(x) => {
+                // EX9E - Skip the next instruction if the key corresponding to the value in VX is
+                // currently pressed. Values which are no keys are never pressed.
+                let value = self.register(x)?;
+                if Key::try_from(value).is_ok_and(|key| self.keys.contains(&key)) {
+                    self.skip_instruction();
+                }
+            }
+            Opcode::SkipIfNotKey(x) => {
+                // EXA1 - Skip the next instruction if the key corresponding to the value in VX is
+                // currently not pressed
+                let value = self.register(x)?;
+                if Key::try_from(value).is_ok_and(|key| !self.keys.contains(&key)) {
+                    self.skip_instruction();
                 }
-                // Maybe emit a warning if the value in register VX is > 16 and hence cannot be represented as a key
             }
-        } else if nibbles[0] == 0xF {
-            if nibbles[2] == 0x0 && nibbles[3] == 0x7 {
+            Opcode::SetIndexLong(address) => {
+                // F000 NNNN - load the 16 bit address NNNN, stored in the next two bytes, into
+                // the index register (XO-CHIP)
+                self.index_register = address;
+            }
+            Opcode::LoadAudio => {
+                // F002 - load the 16 byte audio pattern from the memory pointed to by the index
+                // register (XO-CHIP)
+                let mut pattern = [0; AUDIO_PATTERN_SIZE as usize];
+                for (offset, sample) in pattern.iter_mut().enumerate() {
+                    *sample = self.memory(offset_address(self.index_register, offset)?)?;
+                }
+                self.audio_pattern = Some(pattern);
+            }
+            Opcode::SelectPlanes(planes) => {
+                // FN01 - select the drawing planes given by the bitmask N (XO-CHIP)
+                self.selected_planes = planes & 0b11;
+            }
+            Opcode::GetDelay(x) => {
                 // FX07 - set VX to value of the delay timer
-                self.set_register(nibbles[1], self.delay_timer)?;
-            } else if nibbles[2] == 0x1 && nibbles[3] == 0x5 {
+                self.set_register(x, self.delay_timer)?;
+            }
+            Opcode::SetDelay(x) => {
                 // FX15 - set the delay timer to the value in VX
-                self.delay_timer = self.register(nibbles[1])?;
-            } else if nibbles[2] == 0x1 && nibbles[3] == 0x8 {
+                self.delay_timer = self.register(x)?;
+            }
+            Opcode::SetSound(x) => {
                 // FX18 - set the sound timer to the value in VX
-                self.sound_timer = self.register(nibbles[1])?;
+                self.sound_timer = self.register(x)?;
             }
-            if nibbles[2] == 0x1 && nibbles[3] == 0xE {
+            Opcode::AddToIndex(x) => {
                 // FX1E - add the value of VX to the index register
-                let value = self.register(nibbles[1])?;
-                self.index_register += value as u16;
+                let value = self.register(x)?;
+                self.index_register = self.index_register.wrapping_add(value as u16);
                 if self.settings.set_vf_on_overflow_in_fx1e {
-                    // Note: not the overflow of u16, but addressing memory outside the common range,
-                    // i.e. addresses above 0x0FFF.
-                    if self.index_register >= MEMORY_SIZE as u16 {
-                        self.set_register(0xF, 1)?;
+                    // Note: not the overflow of u16, but addressing memory outside the common
+                    // range, i.e. addresses above 0x0FFF.
+                    if self.index_register >= CLASSIC_MEMORY_SIZE as u16 {
+                        self.set_flag_register(1);
                     }
                 }
             }
-            if nibbles[2] == 0x0 && nibbles[3] == 0xA {
+            Opcode::WaitKey(x) => {
                 // FX0A - block until get key
                 if let Some(blocking_state) = &mut self.blocking {
                     if let Some(key) = blocking_state.compare_and_update(&self.keys) {
                         // A key was released. Store the key in VX.
-                        self.set_register(nibbles[1], key as u8)?;
+                        self.set_register(x, key as u8)?;
                         self.blocking = None;
                         // Continue execution, program counter is already increased
                     } else {
-                        self.program_counter -= 2;
+                        self.program_counter = self.program_counter.wrapping_sub(2);
                     }
                 } else {
                     // Enter blocking state. Remember the keys which were pressed when we entered.
                     self.blocking = Some(BlockingState::new(&self.keys));
-                    // Undo the usual advancement of the program counter. Stay at the current instruction.
-                    self.program_counter -= 2;
+                    // Undo the usual advancement of the program counter. Stay at the current
+                    // instruction.
+                    self.program_counter = self.program_counter.wrapping_sub(2);
                 }
             }
-            if nibbles[2] == 0x2 && nibbles[3] == 0x9 {
-                // FX29 - point index register to font character
-                let value = self.register(nibbles[1])?;
-                if value < 16 {
-                    self.index_register = FONT_START_ADDR + value as u16 * 5;
+            Opcode::Font(x) => {
+                // FX29 - point index register to font character. Values above 0xF are ignored.
+                let value = self.register(x)?;
+                if value < NUM_FONT_CHARS {
+                    self.index_register = FONT_START_ADDR + value as u16 * BYTES_PER_CHAR as u16;
                 }
-                // No warning is emitted.
-            } else if nibbles[2] == 0x3 && nibbles[3] == 0x3 {
+            }
+            Opcode::BigFont(x) => {
+                // FX30 - point index register to big font character (SUPER-CHIP)
+                let value = self.register(x)?;
+                if value < NUM_FONT_CHARS {
+                    self.index_register =
+                        BIG_FONT_START_ADDR + value as u16 * BYTES_PER_BIG_CHAR as u16;
+                }
+            }
+            Opcode::SetPitch(x) => {
+                // FX3A - set the audio pitch register to the value in VX (XO-CHIP)
+                self.pitch = self.register(x)?;
+            }
+            Opcode::Bcd(x) => {
                 // FX33 - binary-coded decimal conversion
-                let value = self.register(nibbles[1])?;
-                let digit1 = value / 100;
-                self.memory[self.index_register as usize] = digit1;
-                let value = value % 100;
-                let digit2 = value / 10;
-                self.memory[self.index_register as usize + 1] = digit2;
-                let value = value % 10;
-                let digit3 = value;
-                self.memory[self.index_register as usize + 2] = digit3;
-            } else if nibbles[2] == 0x5 && nibbles[3] == 0x5 {
+                let value = self.register(x)?;
+                let digits = [value / 100, value / 10 % 10, value % 10];
+                for (offset, digit) in digits.into_iter().enumerate() {
+                    self.set_memory(offset_address(self.index_register, offset)?, digit)?;
+                }
+            }
+            Opcode::Store(max) => {
                 // FX55 - store registers up to VX in memory pointed to by index register
-                let max = nibbles[1];
                 for i in 0..=max {
-                    self.set_memory(self.index_register + i as u16, self.register(i)?)?;
+                    let address = offset_address(self.index_register, i as usize)?;
+                    self.set_memory(address, self.register(i)?)?;
                 }
                 if self.settings.inc_i_in_fx55_and_fx65 {
-                    self.index_register += max as u16 + 1;
+                    self.index_register = self.index_register.wrapping_add(max as u16 + 1);
                 }
-            } else if nibbles[2] == 0x6 && nibbles[3] == 0x5 {
+            }
+            Opcode::Load(max) => {
                 // FX65 - load registers from memory
-                let max = nibbles[1];
                 for i in 0..=max {
-                    self.set_register(i, self.memory(self.index_register + i as u16)?)?;
+                    let address = offset_address(self.index_register, i as usize)?;
+                    self.set_register(i, self.memory(address)?)?;
                 }
                 if self.settings.inc_i_in_fx55_and_fx65 {
-                    self.index_register += max as u16 + 1;
+                    self.index_register = self.index_register.wrapping_add(max as u16 + 1);
+                }
+            }
+            Opcode::StoreFlags(max) => {
+                // FX75 - store registers up to VX in the RPL user flags (SUPER-CHIP)
+                for i in 0..=max {
+                    self.rpl_flags[i as usize] = self.register(i)?;
+                }
+            }
+            Opcode::LoadFlags(max) => {
+                // FX85 - load registers up to VX from the RPL user flags (SUPER-CHIP)
+                for i in 0..=max {
+                    self.set_register(i, self.rpl_flags[i as usize])?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Draw a sprite of `width` (8 or 16) by `rows` pixels at the given coordinates. The sprite data
+    /// is read from the memory location that the index register is holding. If both planes are
+    /// selected, the data for the second plane directly follows the data for the first plane.
+    /// Sprites are clipped at the edges of the screen, or wrap around if clipping is disabled in
+    /// the settings. Set VF if any pixel was turned off.
+    fn draw_sprite(&mut self, x: u8, y: u8, width: u8, rows: u8) -> Result<(), ExecutionError> {
+        let display_width = self.display_width();
+        let display_height = self.display_height();
+        let bytes_per_row = width as u16 / 8;
+
+        // Take modulo operation on the x and y coordinates
+        let x = x % display_width;
+        let y = y % display_height;
+
+        // Clear VF
+        self.set_flag_register(0);
+
+        let mut offset = 0;
+        for plane in 0..NUM_PLANES {
+            let plane_mask = 1 << plane;
+            if self.selected_planes & plane_mask == 0 {
+                continue;
+            }
+
+            // Loop over sprite rows, 1 sprite row = `width` pixels
+            for row in 0..rows {
+                let mut sprite_row = 0u16;
+                for _ in 0..bytes_per_row {
+                    let address = offset_address(self.index_register, offset)?;
+                    sprite_row = sprite_row << 8 | self.memory(address)? as u16;
+                    offset += 1;
+                }
+                let mut dy = y + row;
+                if dy >= display_height {
+                    if self.settings.clip_sprites {
+                        continue;
+                    }
+                    dy %= display_height;
+                }
+                for column in 0..width {
+                    let mut dx = x + column;
+                    if dx >= display_width {
+                        if self.settings.clip_sprites {
+                            break;
+                        }
+                        dx %= display_width;
+                    }
+                    let bit = sprite_row >> (width - 1 - column) & 1;
+                    if bit == 0 {
+                        continue;
+                    }
+                    let index = dy as usize * display_width as usize + dx as usize;
+                    let pixel = &mut self.display[index];
+                    if *pixel & plane_mask != 0 {
+                        self.variable_registers[FLAG_REGISTER_INDEX as usize] = 1;
+                    }
+                    *pixel ^= plane_mask;
                 }
             }
-        } else {
-            return Err(ExecutionError::UnknownInstruction(instruction));
         }
         Ok(())
     }
 
+    /// Clear the given planes of the display in the current resolution.
+    fn clear_planes(&mut self, planes: u8) {
+        let size = self.display_width() as usize * self.display_height() as usize;
+        self.display.resize(size, 0);
+        for pixel in &mut self.display {
+            *pixel &= !planes;
+        }
+    }
+
+    /// Scroll the selected planes of the display by `dx` pixels to the right and `dy` pixels
+    /// downwards. Negative values scroll to the left respectively upwards. Pixels scrolled in at
+    /// the edges are empty.
+    fn scroll(&mut self, dx: i16, dy: i16) {
+        let width = self.display_width() as i16;
+        let height = self.display_height() as i16;
+        let planes = self.selected_planes;
+        let previous = self.display.clone();
+        for y in 0..height {
+            for x in 0..width {
+                let (source_x, source_y) = (x - dx, y - dy);
+                let scrolled = if (0..width).contains(&source_x) && (0..height).contains(&source_y)
+                {
+                    previous[(source_y * width + source_x) as usize]
+                } else {
+                    0
+                };
+                let pixel = &mut self.display[(y * width + x) as usize];
+                *pixel = (*pixel & !planes) | (scrolled & planes);
+            }
+        }
+    }
+
+    /// Skip the next instruction. The XO-CHIP instruction F000 NNNN is four bytes long and is
+    /// skipped as a whole.
+    fn skip_instruction(&mut self) {
+        let pc = self.program_counter;
+        let next = self.fetch(pc);
+        let length = if let Ok(0xf000) = next { 4 } else { 2 };
+        self.program_counter = pc.wrapping_add(length);
+    }
+
+    /// Width of the display in the current resolution.
+    fn display_width(&self) -> u8 {
+        if self.hires {
+            HIRES_DISPLAY_WIDTH
+        } else {
+            DISPLAY_WIDTH
+        }
+    }
+
+    /// Height of the display in the current resolution.
+    fn display_height(&self) -> u8 {
+        if self.hires {
+            HIRES_DISPLAY_HEIGHT
+        } else {
+            DISPLAY_HEIGHT
+        }
+    }
+
     /// Handle the clock signal (60 times per second) by decreasing the delay timer and sound timer
-    /// registers.
+    /// registers. The clock signal also marks the vertical blank interval of the display.
     pub fn handle_timer_tick(&mut self) {
+        self.waiting_for_vblank = false;
         if self.delay_timer > 0 {
             self.delay_timer -= 1;
         }
@@ -478,7 +813,7 @@ impl Processor {
         self.memory
             .get(address as usize)
             .copied()
-            .ok_or(ExecutionError::MemoryAccessOutOfBounds)
+            .ok_or(ExecutionError::MemoryAccessOutOfBounds(address as usize))
     }
 
     /// Write to the memory cell at `address`.
@@ -486,7 +821,23 @@ impl Processor {
         *self
             .memory
             .get_mut(index as usize)
-            .ok_or(ExecutionError::MemoryAccessOutOfBounds)? = value;
+            .ok_or(ExecutionError::MemoryAccessOutOfBounds(index as usize))? = value;
+        self.decode_cache.invalidate(index);
+        Ok(())
+    }
+
+    /// Check that memory and display have the sizes the rest of the processor relies on. Used for
+    /// states which were not created by the processor itself, e.g. deserialized ones.
+    pub(crate) fn check_consistency(&self) -> Result<(), &'static str> {
+        if self.memory.len() != MEMORY_SIZE {
+            return Err("memory has the wrong size");
+        }
+        if self.display.len() != self.display_width() as usize * self.display_height() as usize {
+            return Err("display has the wrong size for its resolution");
+        }
+        if self.display.iter().any(|&planes| planes > 0b11) {
+            return Err("display has pixels outside of the two planes");
+        }
         Ok(())
     }
 
@@ -494,26 +845,92 @@ impl Processor {
     pub fn display(&self) -> Display {
         Display {
             content: self.display.clone(),
+            width: self.display_width(),
+            height: self.display_height(),
         }
     }
 
-    #[allow(dead_code)]
     pub fn is_blocking(&self) -> bool {
         self.blocking.is_some()
     }
 
+    /// Return true if the processor waits for the next timer tick (vertical blank) after a draw
+    /// instruction. Only happens if the display wait quirk is enabled.
+    pub fn is_waiting_for_vblank(&self) -> bool {
+        self.waiting_for_vblank
+    }
+
+    /// Return true if the program has exited via 00FD.
+    pub fn has_exited(&self) -> bool {
+        self.exited
+    }
+
     /// Return true if sound is currently playing.
     pub fn playing_sound(&self) -> bool {
         self.sound_timer > 0
     }
 
+    /// Get a copy of the registers, the stack and the timers.
+    pub fn registers(&self) -> Registers {
+        Registers {
+            variable_registers: self.variable_registers,
+            index_register: self.index_register,
+            program_counter: self.program_counter,
+            stack: self.stack.clone(),
+            delay_timer: self.delay_timer,
+            sound_timer: self.sound_timer,
+        }
+    }
+
+    /// Overwrite the registers, e.g. from a debugger.
+    pub fn load_registers(&mut self, registers: &Registers) {
+        self.variable_registers = registers.variable_registers;
+        self.index_register = registers.index_register;
+        self.program_counter = registers.program_counter;
+        self.stack = registers.stack.clone();
+        self.delay_timer = registers.delay_timer;
+        self.sound_timer = registers.sound_timer;
+    }
+
+    /// Read up to `length` bytes of memory starting at `address`. Fewer bytes are returned at the
+    /// end of the memory.
+    pub fn read_memory(&self, address: u16, length: usize) -> Vec<u8> {
+        let start = (address as usize).min(self.memory.len());
+        let end = (start + length).min(self.memory.len());
+        self.memory[start..end].to_vec()
+    }
+
+    /// Overwrite memory starting at `address`. Bytes beyond the end of the memory are dropped.
+    pub fn write_memory(&mut self, address: u16, data: &[u8]) {
+        let start = (address as usize).min(self.memory.len());
+        let end = (start + data.len()).min(self.memory.len());
+        self.memory[start..end].copy_from_slice(&data[..end - start]);
+        for offset in 0..(end - start) {
+            self.decode_cache
+                .invalidate(address.wrapping_add(offset as u16));
+        }
+    }
+
+    /// Get the current audio pattern and pitch.
+    pub fn audio(&self) -> Audio {
+        Audio {
+            pattern: self.audio_pattern,
+            pitch: self.pitch,
+        }
+    }
+
     /// Accept keyboard input.
     pub fn handle_keys(&mut self, keys: HashSet<Key>) {
         self.keys = keys;
     }
+
+    /// The keys currently pressed.
+    pub fn keys(&self) -> &HashSet<Key> {
+        &self.keys
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct BlockingState {
     keys_on_enter: HashSet<Key>,
 }
@@ -534,19 +951,95 @@ impl BlockingState {
     }
 }
 
+/// Decoded instructions by address, so that loops do not decode the same instructions again and
+/// again. Only instructions supported by the settings are cached. The entries covering an
+/// address are dropped when it is written, so self-modifying code works as usual.
+#[derive(Debug, Clone)]
+struct DecodeCache {
+    enabled: bool,
+    /// Empty until the first instruction is cached, otherwise one entry per address
+    entries: Vec<Option<Opcode>>,
+}
+
+impl DecodeCache {
+    fn get(&self, address: u16) -> Option<Opcode> {
+        self.entries.get(address as usize).copied().flatten()
+    }
+
+    fn insert(&mut self, address: u16, opcode: Opcode) {
+        if !self.enabled {
+            return;
+        }
+        if self.entries.is_empty() {
+            self.entries = vec![None; MEMORY_SIZE];
+        }
+        self.entries[address as usize] = Some(opcode);
+    }
+
+    /// Drop the instructions which contain the byte at `address`, i.e. which start up to three
+    /// bytes earlier (F000 NNNN is four bytes long).
+    fn invalidate(&mut self, address: u16) {
+        if self.entries.is_empty() {
+            return;
+        }
+        for offset in 0..4 {
+            self.entries[address.wrapping_sub(offset) as usize] = None;
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries = vec![];
+    }
+}
+
+impl Default for DecodeCache {
+    fn default() -> Self {
+        DecodeCache {
+            enabled: true,
+            entries: vec![],
+        }
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum EmulatorError {
     #[error(transparent)]
     Loading(#[from] LoadingError),
 
-    #[error("invalid instruction ({instruction:#06x} at {address:#06x}): {source}")]
+    #[error(
+        "invalid instruction ({instruction:#06x} at {address:#06x}{}): {source}",
+        .location.as_ref().map(|l| format!(", {}", l)).unwrap_or_default()
+    )]
     Execution {
         address: u16,
         instruction: u16,
+        /// The address relative to a symbol and the source line, see [`EmulatorError::locate`]
+        location: Option<String>,
         source: ExecutionError,
     },
 }
 
+impl EmulatorError {
+    /// Add the location of a failed instruction in terms of `symbols`, e.g. `main+0x12` and the
+    /// source line, to the error message.
+    pub fn locate(self, symbols: &SymbolMap) -> Self {
+        match self {
+            EmulatorError::Execution {
+                address,
+                instruction,
+                location: None,
+                source,
+            } => EmulatorError::Execution {
+                address,
+                instruction,
+                location: symbols.describe(address),
+                source,
+            },
+            error => error,
+        }
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum LoadingError {
     #[error("ROM too large")]
@@ -561,51 +1054,205 @@ pub enum ExecutionError {
     #[error("register index '{0}' out of range")]
     RegisterIndexOutOfRange(u8),
 
-    #[error("memory access out of bounds")]
-    MemoryAccessOutOfBounds,
+    #[error("memory access at {0:#06x} out of bounds")]
+    MemoryAccessOutOfBounds(usize),
 
     #[error("unknown instruction '{0:#06x}'")]
     UnknownInstruction(u16),
+
+    #[error("instruction '{instruction:#06x}' requires {extension}")]
+    UnsupportedInstruction {
+        instruction: u16,
+        extension: Extension,
+    },
 }
 
-#[derive(Clone, Debug, Default)]
+/// The content of the display together with its current resolution, which is either 64x32
+/// (low resolution) or 128x64 (high resolution, SUPER-CHIP). Each pixel holds the bits of the two
+/// XO-CHIP drawing planes, i.e. a value from 0 to 3.
+#[derive(Clone, Debug)]
 pub struct Display {
-    pub content: Vec<bool>,
+    pub content: Vec<u8>,
+    pub width: u8,
+    pub height: u8,
 }
 
 impl Display {
-    pub fn get(&self, x: u8, y: u8) -> bool {
-        // If the display is empty (content vector has length zero), return black, i.e. false
-        let index = y as usize * DISPLAY_WIDTH as usize + x as usize;
-        self.content.get(index).copied().unwrap_or(false)
+    /// Get the plane bits of the pixel at (x, y). Bit 0 is the first plane, bit 1 the second.
+    pub fn get(&self, x: u8, y: u8) -> u8 {
+        // If the display is empty (content vector has length zero), return black, i.e. 0
+        let index = y as usize * self.width as usize + x as usize;
+        self.content.get(index).copied().unwrap_or(0)
+    }
+
+    /// Return true if the display is in high resolution mode.
+    pub fn is_hires(&self) -> bool {
+        self.width == HIRES_DISPLAY_WIDTH
+    }
+}
+
+impl Default for Display {
+    fn default() -> Self {
+        Display {
+            content: vec![],
+            width: DISPLAY_WIDTH,
+            height: DISPLAY_HEIGHT,
+        }
+    }
+}
+
+/// A copy of the processor registers, the stack and the timers.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Registers {
+    pub variable_registers: [u8; NUM_VARIABLE_REGISTERS as usize],
+    pub index_register: u16,
+    pub program_counter: u16,
+    pub stack: Vec<u16>,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+}
+
+/// The XO-CHIP audio state. The pattern consists of 128 1-bit samples which are played back in a
+/// loop at a sample rate derived from the pitch register. Programs which have not loaded a
+/// pattern (CHIP-8, SUPER-CHIP) play a default tone instead.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Audio {
+    pub pattern: Option<[u8; AUDIO_PATTERN_SIZE as usize]>,
+    pub pitch: u8,
+}
+
+impl Audio {
+    /// Playback rate of the pattern in samples per second, 4000 Hz at the default pitch of 64.
+    pub fn sample_rate(&self) -> f32 {
+        4000.0 * 2f32.powf((self.pitch as f32 - DEFAULT_PITCH as f32) / 48.0)
+    }
+}
+
+/// The address `offset` bytes after `base`. Memory does not wrap around, going past its end is an
+/// error.
+fn offset_address(base: u16, offset: usize) -> Result<u16, ExecutionError> {
+    let address = base as usize + offset;
+    u16::try_from(address).map_err(|_| ExecutionError::MemoryAccessOutOfBounds(address))
+}
+
+/// Iterate over the register indices from `x` to `y`, both inclusive. If `x` is greater than `y`,
+/// the registers are iterated in descending order.
+fn register_range(x: u8, y: u8) -> Box<dyn Iterator<Item = u8>> {
+    if x <= y {
+        Box::new(x..=y)
+    } else {
+        Box::new((y..=x).rev())
     }
 }
 
-/// Get the nibble (half-byte) at `index` of `value`.
-///
-/// # Panics
-///
-/// Panics if `index` is greater than 3.
-fn nibble(value: u16, index: u8) -> u8 {
-    match index {
-        0 => (value >> 12 & 0x000f) as u8,
-        1 => (value >> 8 & 0x000f) as u8,
-        2 => (value >> 4 & 0x000f) as u8,
-        3 => (value & 0x000f) as u8,
-        _ => panic!("invalid nibble index"),
+impl Default for Processor {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+/// The quirks of the instruction set, i.e. how certain ambiguous instructions are interpreted, and
+/// the supported instruction set extension. Use [`InstructionSettings::from_platform`] to get the
+/// quirks of a known platform.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct InstructionSettings {
     use_vy_in_8xy6: bool,
     use_vy_in_8xye: bool,
     use_bxnn_instead_bnnn: bool,
     set_vf_on_overflow_in_fx1e: bool,
     inc_i_in_fx55_and_fx65: bool,
+    reset_vf_in_8xy1_8xy2_8xy3: bool,
+    clip_sprites: bool,
+    display_wait: bool,
+    extension: Extension,
+}
+
+impl InstructionSettings {
+    /// Get the complete set of quirks of `platform`.
+    pub fn from_platform(platform: Platform) -> Self {
+        match platform {
+            Platform::CosmacVip => InstructionSettings {
+                use_vy_in_8xy6: true,
+                use_vy_in_8xye: true,
+                use_bxnn_instead_bnnn: false,
+                set_vf_on_overflow_in_fx1e: false,
+                inc_i_in_fx55_and_fx65: true,
+                reset_vf_in_8xy1_8xy2_8xy3: true,
+                clip_sprites: true,
+                display_wait: true,
+                extension: Extension::Chip8,
+            },
+            Platform::Chip48 => InstructionSettings {
+                use_vy_in_8xy6: false,
+                use_vy_in_8xye: false,
+                use_bxnn_instead_bnnn: true,
+                set_vf_on_overflow_in_fx1e: false,
+                inc_i_in_fx55_and_fx65: true,
+                reset_vf_in_8xy1_8xy2_8xy3: false,
+                clip_sprites: true,
+                display_wait: false,
+                extension: Extension::Chip8,
+            },
+            Platform::SchipLegacy => InstructionSettings {
+                use_vy_in_8xy6: false,
+                use_vy_in_8xye: false,
+                use_bxnn_instead_bnnn: true,
+                set_vf_on_overflow_in_fx1e: false,
+                inc_i_in_fx55_and_fx65: false,
+                reset_vf_in_8xy1_8xy2_8xy3: false,
+                clip_sprites: true,
+                display_wait: true,
+                extension: Extension::SuperChip,
+            },
+            Platform::SchipModern => InstructionSettings {
+                use_vy_in_8xy6: false,
+                use_vy_in_8xye: false,
+                use_bxnn_instead_bnnn: true,
+                set_vf_on_overflow_in_fx1e: false,
+                inc_i_in_fx55_and_fx65: false,
+                reset_vf_in_8xy1_8xy2_8xy3: false,
+                clip_sprites: true,
+                display_wait: false,
+                extension: Extension::SuperChip,
+            },
+            Platform::XoChip => InstructionSettings {
+                use_vy_in_8xy6: true,
+                use_vy_in_8xye: true,
+                use_bxnn_instead_bnnn: false,
+                set_vf_on_overflow_in_fx1e: false,
+                inc_i_in_fx55_and_fx65: true,
+                reset_vf_in_8xy1_8xy2_8xy3: false,
+                clip_sprites: false,
+                display_wait: false,
+                extension: Extension::XoChip,
+            },
+        }
+    }
+
+    /// Replace the quirks set in `overrides`, keep all others.
+    pub fn with_overrides(self, overrides: InstructionOverrides) -> Self {
+        InstructionSettings {
+            use_vy_in_8xy6: overrides.use_vy_in_8xy6.unwrap_or(self.use_vy_in_8xy6),
+            use_vy_in_8xye: overrides.use_vy_in_8xye.unwrap_or(self.use_vy_in_8xye),
+            use_bxnn_instead_bnnn: overrides
+                .use_bxnn_instead_bnnn
+                .unwrap_or(self.use_bxnn_instead_bnnn),
+            set_vf_on_overflow_in_fx1e: overrides
+                .set_vf_on_overflow_in_fx1e
+                .unwrap_or(self.set_vf_on_overflow_in_fx1e),
+            inc_i_in_fx55_and_fx65: overrides
+                .inc_i_in_fx55_and_fx65
+                .unwrap_or(self.inc_i_in_fx55_and_fx65),
+            reset_vf_in_8xy1_8xy2_8xy3: overrides
+                .reset_vf_in_8xy1_8xy2_8xy3
+                .unwrap_or(self.reset_vf_in_8xy1_8xy2_8xy3),
+            clip_sprites: overrides.clip_sprites.unwrap_or(self.clip_sprites),
+            display_wait: overrides.display_wait.unwrap_or(self.display_wait),
+            extension: overrides.extension.unwrap_or(self.extension),
+        }
+    }
 }
 
-#[allow(clippy::derivable_impls)]
 impl Default for InstructionSettings {
     fn default() -> Self {
         InstructionSettings {
@@ -614,12 +1261,49 @@ impl Default for InstructionSettings {
             use_bxnn_instead_bnnn: false,
             set_vf_on_overflow_in_fx1e: false,
             inc_i_in_fx55_and_fx65: false,
+            reset_vf_in_8xy1_8xy2_8xy3: false,
+            clip_sprites: true,
+            display_wait: false,
+            extension: Extension::XoChip,
         }
     }
 }
 
+/// Individual quirks which override those of the selected platform. Quirks which are `None` are
+/// taken from the platform.
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InstructionOverrides {
+    pub use_vy_in_8xy6: Option<bool>,
+    pub use_vy_in_8xye: Option<bool>,
+    pub use_bxnn_instead_bnnn: Option<bool>,
+    pub set_vf_on_overflow_in_fx1e: Option<bool>,
+    pub inc_i_in_fx55_and_fx65: Option<bool>,
+    pub reset_vf_in_8xy1_8xy2_8xy3: Option<bool>,
+    pub clip_sprites: Option<bool>,
+    pub display_wait: Option<bool>,
+    pub extension: Option<Extension>,
+}
+
+/// The CHIP-8 platforms (interpreters) whose quirks are known.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Platform {
+    /// The original CHIP-8 interpreter on the COSMAC VIP
+    CosmacVip,
+    /// CHIP-48 on the HP-48 calculators
+    #[serde(rename = "chip-48")]
+    Chip48,
+    /// SUPER-CHIP 1.1 as originally released on the HP-48
+    SchipLegacy,
+    /// SUPER-CHIP as implemented by modern interpreters like Octo
+    SchipModern,
+    /// XO-CHIP as defined by Octo
+    XoChip,
+}
+
 #[repr(u8)]
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum Key {
     Num0 = 0x0,
     Num1 = 0x1,
@@ -669,17 +1353,288 @@ impl TryFrom<u8> for Key {
 mod tests {
     use super::*;
 
+    /// Decode and execute a single two byte instruction.
+    fn execute(processor: &mut Processor, instruction: u16) -> Result<(), ExecutionError> {
+        processor.execute(Opcode::decode(instruction).unwrap())
+    }
+
+    /// Load the given instructions as a program and execute until the end of the program.
+    fn run(instructions: &[u16]) -> Processor {
+        run_with_settings(instructions, InstructionSettings::default())
+    }
+
+    /// Like `run`, but with the given instruction settings.
+    fn run_with_settings(instructions: &[u16], settings: InstructionSettings) -> Processor {
+        let data: Vec<u8> = instructions.iter().flat_map(|i| i.to_be_bytes()).collect();
+        let end = ROM_START_ADDR + data.len() as u16;
+        let mut processor = Processor::new();
+        processor.load_settings(settings);
+        processor.load_program(data).unwrap();
+        while processor.program_counter < end && !processor.has_exited() {
+            if processor.is_waiting_for_vblank() {
+                processor.handle_timer_tick();
+            }
+            processor.step().unwrap();
+        }
+        processor
+    }
+
     #[test]
-    fn test_nibble() {
-        assert_eq!(nibble(0x1234, 0), 1);
-        assert_eq!(nibble(0x1234, 1), 2);
-        assert_eq!(nibble(0x1234, 2), 3);
-        assert_eq!(nibble(0x1234, 3), 4);
+    fn test_schip_hires_and_scroll() {
+        // hires, I := big font "8", draw 16x16 at (V0, V0) = (0, 0), scroll down 3, right 4
+        let processor = run(&[0x00ff, 0x6108, 0xf130, 0xd000, 0x00c3, 0x00fb]);
+        let display = processor.display();
+        assert!(display.is_hires());
+        assert_eq!(display.content.len(), 128 * 64);
+        // The first row of the 16x16 sprite starts with 0x3C, shifted right by 4 and down by 3
+        assert_eq!(display.get(5, 3), 0);
+        assert_eq!(display.get(6, 3), 1);
+        assert_eq!(display.get(9, 3), 1);
+        assert_eq!(display.get(10, 3), 0);
+        assert_eq!(display.get(6, 2), 0);
+    }
+
+    #[test]
+    fn test_schip_rpl_flags_and_exit() {
+        // V0 := 1, V1 := 2, save to flags, clear V0 and V1, load from flags, exit
+        let processor = run(&[0x6001, 0x6102, 0xf175, 0x6000, 0x6100, 0xf185, 0x00fd]);
+        assert_eq!(processor.variable_registers[0], 1);
+        assert_eq!(processor.variable_registers[1], 2);
+        assert!(processor.has_exited());
+        assert_eq!(processor.program_counter, ROM_START_ADDR + 12);
+    }
+
+    #[test]
+    fn test_xochip_planes_and_long_index() {
+        // Select both planes, I := 0x0300 (long), draw a 1 pixel tall sprite at (0, 0)
+        let mut processor = run(&[0xf301, 0xf000, 0x0300]);
+        processor.memory[0x300] = 0b1000_0000; // plane 1
+        processor.memory[0x301] = 0b1100_0000; // plane 2
+        execute(&mut processor, 0xd001).unwrap();
+        let display = processor.display();
+        assert_eq!(processor.index_register, 0x0300);
+        assert_eq!(display.get(0, 0), 0b11);
+        assert_eq!(display.get(1, 0), 0b10);
+
+        // Select plane 2 only and clear it
+        execute(&mut processor, 0xf201).unwrap();
+        execute(&mut processor, 0x00e0).unwrap();
+        assert_eq!(processor.display().get(0, 0), 0b01);
+    }
+
+    #[test]
+    fn test_xochip_register_range_and_skip() {
+        // V1 := 1, V2 := 2, I := 0x1000 (long), save V2..V1, skip over F000 NNNN
+        let processor = run(&[
+            0x6101, 0x6202, 0xf000, 0x1000, 0x5212, 0x3101, 0xf000, 0x1234,
+        ]);
+        assert_eq!(processor.memory[0x1000], 2);
+        assert_eq!(processor.memory[0x1001], 1);
+        assert_eq!(processor.index_register, 0x1000);
+        assert_eq!(processor.program_counter, ROM_START_ADDR + 16);
+    }
+
+    #[test]
+    fn test_xochip_audio() {
+        assert_eq!(Processor::new().audio().pattern, None);
+
+        // I := font "0", load pattern, V0 := 112, pitch := V0
+        let processor = run(&[0x6000, 0xf029, 0xf002, 0x6070, 0xf03a]);
+        let audio = processor.audio();
+        assert_eq!(audio.pattern.unwrap()[..5], Processor::FONT[..5]);
+        assert_eq!(audio.pitch, 112);
+        assert_eq!(audio.sample_rate(), 8000.0);
+    }
+
+    #[test]
+    fn test_quirks_vf_reset_and_wrapping() {
+        // V0 := 62, I := font "8", draw at (62, 30), VF := 5, V0 |= V1
+        let program = [0x603e, 0x6108, 0xf129, 0xd005, 0x6f05, 0x8011];
+        let settings = InstructionSettings::from_platform(Platform::CosmacVip);
+        let processor = run_with_settings(&program, settings);
+        assert_eq!(processor.variable_registers[0xf], 0);
+        assert_eq!(processor.display().get(63, 30), 1);
+        assert_eq!(processor.display().get(0, 30), 0);
+        assert_eq!(processor.display().get(62, 0), 0);
+
+        let settings = InstructionSettings::from_platform(Platform::XoChip);
+        let processor = run_with_settings(&program, settings);
+        assert_eq!(processor.variable_registers[0xf], 5);
+        assert_eq!(processor.display().get(0, 30), 1);
+        assert_eq!(processor.display().get(62, 0), 1);
+
+        let overrides = InstructionOverrides {
+            clip_sprites: Some(true),
+            ..Default::default()
+        };
+        let processor = run_with_settings(&program, settings.with_overrides(overrides));
+        assert!(processor.settings.use_vy_in_8xy6);
+        assert!(processor.settings.clip_sprites);
+    }
+
+    #[test]
+    fn test_seeded_random_numbers() {
+        // Fill V0 to V7 with random numbers
+        let program: Vec<u16> = (0..8).map(|x| 0xc0ff | x << 8).collect();
+        let random_registers = |seed| {
+            let mut processor = Processor::new();
+            processor.set_seed(seed);
+            let data = program.iter().flat_map(|i| i.to_be_bytes()).collect();
+            processor.load_program(data).unwrap();
+            for _ in 0..program.len() {
+                processor.step().unwrap();
+            }
+            processor.variable_registers
+        };
+        assert_eq!(random_registers(42), random_registers(42));
+        assert_ne!(random_registers(42), random_registers(43));
+    }
+
+    #[test]
+    fn test_display_wait() {
+        let mut processor = Processor::new();
+        processor.load_settings(InstructionSettings::from_platform(Platform::CosmacVip));
+        processor
+            .load_program(vec![0xd0, 0x01, 0x60, 0x01])
+            .unwrap();
+        processor.step().unwrap();
+        assert!(processor.is_waiting_for_vblank());
+        processor.step().unwrap();
+        assert_eq!(processor.program_counter, ROM_START_ADDR + 2);
+
+        processor.handle_timer_tick();
+        assert!(!processor.is_waiting_for_vblank());
+        processor.step().unwrap();
+        assert_eq!(processor.variable_registers[0], 1);
+    }
+
+    #[test]
+    fn test_end_of_memory() {
+        let mut processor = Processor::new();
+        processor.load_settings(InstructionSettings::from_platform(Platform::XoChip));
+        processor.load_program(vec![]).unwrap();
+        let out_of_bounds = |result: Result<(), ExecutionError>| match result {
+            Err(ExecutionError::MemoryAccessOutOfBounds(address)) => Some(address),
+            _ => None,
+        };
+        let step = |processor: &mut Processor| {
+            processor.step().map_err(|e| match e {
+                EmulatorError::Execution { source, .. } => source,
+                e => panic!("unexpected error {}", e),
+            })
+        };
+
+        // An instruction at 0xFFFF runs past the end of memory, one at 0xFFFE does not
+        processor.program_counter = 0xffff;
+        assert_eq!(out_of_bounds(step(&mut processor)), Some(0x10000));
+        processor.write_memory(0xfffe, &[0x00, 0xe0]);
+        processor.program_counter = 0xfffe;
+        processor.step().unwrap();
+
+        // So does the operand of F000 NNNN
+        processor.write_memory(0xfffe, &[0xf0, 0x00]);
+        processor.program_counter = 0xfffe;
+        assert_eq!(out_of_bounds(step(&mut processor)), Some(0x10000));
+
+        // FX33, FX55, FX65 and DXYN past the end of memory
+        processor.variable_registers[0] = 123;
+        processor.index_register = 0xfffe;
+        assert_eq!(
+            out_of_bounds(execute(&mut processor, 0xf033)),
+            Some(0x10000)
+        );
+        execute(&mut processor, 0xf155).unwrap();
+        assert_eq!(processor.read_memory(0xfffe, 2), [123, 0]);
+        processor.index_register = 0xffff;
+        assert_eq!(
+            out_of_bounds(execute(&mut processor, 0xf155)),
+            Some(0x10000)
+        );
+        assert_eq!(
+            out_of_bounds(execute(&mut processor, 0xf265)),
+            Some(0x10000)
+        );
+        assert_eq!(
+            out_of_bounds(execute(&mut processor, 0xd012)),
+            Some(0x10000)
+        );
+        execute(&mut processor, 0xd011).unwrap();
+
+        // Exiting at 0x0000 stays there
+        processor.write_memory(0x0000, &[0x00, 0xfd]);
+        processor.program_counter = 0x0000;
+        processor.step().unwrap();
+        assert!(processor.has_exited());
+        assert_eq!(processor.program_counter, 0x0000);
+    }
+
+    #[test]
+    fn test_unknown_and_unsupported_instructions() {
+        let mut processor = Processor::new();
+        processor.load_settings(InstructionSettings::from_platform(Platform::CosmacVip));
+        processor
+            .load_program(vec![0x80, 0x18, 0x00, 0xff])
+            .unwrap();
+        assert!(matches!(
+            processor.step(),
+            Err(EmulatorError::Execution {
+                address: ROM_START_ADDR,
+                source: ExecutionError::UnknownInstruction(0x8018),
+                ..
+            })
+        ));
+        processor.program_counter = ROM_START_ADDR + 2;
+        let error = processor.step().unwrap_err();
+        assert!(matches!(
+            error,
+            EmulatorError::Execution {
+                source: ExecutionError::UnsupportedInstruction {
+                    instruction: 0x00ff,
+                    extension: Extension::SuperChip,
+                },
+                ..
+            }
+        ));
+        assert!(error.to_string().contains("requires SUPER-CHIP"));
+        assert!(!processor.display().is_hires());
+
+        // The same instruction is fine on SUPER-CHIP
+        processor.load_settings(InstructionSettings::from_platform(Platform::SchipModern));
+        processor.program_counter = ROM_START_ADDR + 2;
+        processor.step().unwrap();
+        assert!(processor.display().is_hires());
+    }
+
+    #[test]
+    fn test_self_modifying_code() {
+        // Call a subroutine which adds 1 to V2, overwrite its first instruction with "V2 += 5"
+        // using FX55 and call it again
+        let program = [
+            0x220e, 0xa20e, 0x6072, 0x6105, 0xf155, 0x220e, 0x1212, 0x7201, 0x00ee,
+        ];
+        let processor = run(&program);
+        assert_eq!(processor.variable_registers[2], 6);
+
+        // Memory written by a debugger
+        let mut processor = run(&program);
+        processor.write_memory(0x20f, &[0x10]);
+        processor.program_counter = 0x20a;
+        processor.step().unwrap();
+        processor.step().unwrap();
+        assert_eq!(processor.variable_registers[2], 0x16);
     }
 
     #[test]
-    #[should_panic]
-    fn test_nibble_invalid_index() {
-        nibble(0x1234, 4);
+    fn test_restore_keeps_decode_cache_setting() {
+        let mut processor = Processor::new();
+        processor.set_decode_cache(false);
+        processor.load_program(vec![0x70, 0x01]).unwrap();
+        let state =
+            crate::savestate::decode(&crate::savestate::encode(&processor).unwrap()).unwrap();
+        assert!(state.decode_cache.enabled);
+        processor.restore(state);
+        assert!(!processor.decode_cache.enabled);
+        processor.step().unwrap();
+        assert!(processor.decode_cache.entries.is_empty());
     }
 }
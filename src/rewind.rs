@@ -0,0 +1,200 @@
+use std::collections::VecDeque;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::processor::Processor;
+use crate::savestate;
+
+/// Settings of the rewind buffer.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RewindSettings {
+    /// Maximum memory used by the snapshots in KiB. Zero disables rewinding.
+    pub memory_budget_kib: usize,
+    /// Number of timer ticks (frames) between two snapshots.
+    pub interval: usize,
+}
+
+impl Default for RewindSettings {
+    fn default() -> Self {
+        RewindSettings {
+            memory_budget_kib: 16 * 1024,
+            interval: 4,
+        }
+    }
+}
+
+/// A ring buffer of processor snapshots, taken every few timer ticks. When the memory budget is
+/// exhausted, the oldest snapshots are dropped.
+pub struct RewindBuffer {
+    settings: RewindSettings,
+    snapshots: VecDeque<Vec<u8>>,
+    size: usize,
+    ticks: usize,
+}
+
+impl RewindBuffer {
+    pub fn new(settings: RewindSettings) -> Self {
+        RewindBuffer {
+            settings,
+            snapshots: VecDeque::new(),
+            size: 0,
+            ticks: 0,
+        }
+    }
+
+    /// Change the settings. Drops all snapshots.
+    pub fn configure(&mut self, settings: RewindSettings) {
+        *self = RewindBuffer::new(settings);
+    }
+
+    /// Drop all snapshots, e.g. because a new program was loaded.
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+        self.size = 0;
+        self.ticks = 0;
+    }
+
+    /// Number of snapshots currently stored.
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    /// Count a timer tick and take a snapshot of `processor` if the interval has elapsed.
+    pub fn handle_timer_tick(&mut self, processor: &Processor) {
+        let budget = self.settings.memory_budget_kib * 1024;
+        if budget == 0 {
+            return;
+        }
+        self.ticks += 1;
+        if self.ticks < self.settings.interval.max(1) {
+            return;
+        }
+        self.ticks = 0;
+
+        let snapshot = match savestate::encode(processor) {
+            Ok(data) => compress(&data),
+            Err(e) => {
+                warn!("cannot take rewind snapshot: {}", e);
+                return;
+            }
+        };
+        self.size += snapshot.len();
+        self.snapshots.push_back(snapshot);
+        while self.size > budget {
+            if let Some(oldest) = self.snapshots.pop_front() {
+                self.size -= oldest.len();
+            }
+        }
+    }
+
+    /// Go back in time by (approximately) `frames` timer ticks. Returns the restored processor,
+    /// or `None` if there are no snapshots. Always goes back at least one snapshot.
+    pub fn rewind(&mut self, frames: usize) -> Option<Processor> {
+        let count = (frames / self.settings.interval.max(1)).max(1);
+        let mut snapshot = None;
+        for _ in 0..count {
+            // Keep the oldest snapshot, so that holding the rewind key stops there
+            if self.snapshots.len() <= 1 {
+                snapshot = snapshot.or_else(|| self.snapshots.back().cloned());
+                break;
+            }
+            if let Some(s) = self.snapshots.pop_back() {
+                self.size -= s.len();
+                snapshot = Some(s);
+            }
+        }
+        self.ticks = 0;
+
+        match savestate::decode(&decompress(&snapshot?)) {
+            Ok(processor) => Some(processor),
+            Err(e) => {
+                warn!("cannot restore rewind snapshot: {}", e);
+                None
+            }
+        }
+    }
+}
+
+/// Compress the snapshot data by run-length encoding runs of zeros, which make up most of the
+/// memory. A zero byte is followed by the length of the run (1 to 255).
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut compressed = Vec::with_capacity(data.len() / 4);
+    let mut iter = data.iter().peekable();
+    while let Some(&byte) = iter.next() {
+        compressed.push(byte);
+        if byte == 0 {
+            let mut run: u8 = 1;
+            while run < u8::MAX && iter.next_if_eq(&&0).is_some() {
+                run += 1;
+            }
+            compressed.push(run);
+        }
+    }
+    compressed
+}
+
+/// Reverse of [`compress`].
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut decompressed = Vec::with_capacity(data.len() * 4);
+    let mut iter = data.iter();
+    while let Some(&byte) = iter.next() {
+        if byte == 0 {
+            let run = iter.next().copied().unwrap_or(1);
+            decompressed.resize(decompressed.len() + run as usize, 0);
+        } else {
+            decompressed.push(byte);
+        }
+    }
+    decompressed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compression_round_trip() {
+        let mut data = vec![1, 0, 2, 3, 0, 0, 0];
+        data.resize(1000, 0);
+        data.push(7);
+        let compressed = compress(&data);
+        assert!(compressed.len() < 20);
+        assert_eq!(decompress(&compressed), data);
+    }
+
+    #[test]
+    fn rewind_to_earlier_state() {
+        let mut buffer = RewindBuffer::new(RewindSettings {
+            memory_budget_kib: 1024,
+            interval: 1,
+        });
+        // V0 += 1, jump back
+        let program = vec![0x70, 0x01, 0x12, 0x00];
+        let run = |frames, buffer: &mut RewindBuffer| {
+            let mut processor = Processor::new();
+            processor.set_seed(0);
+            processor.load_program(program.clone()).unwrap();
+            for _ in 0..frames {
+                processor.step().unwrap();
+                processor.step().unwrap();
+                buffer.handle_timer_tick(&processor);
+            }
+            processor
+        };
+        let expected = savestate::encode(&run(8, &mut buffer)).unwrap();
+        buffer.clear();
+        run(10, &mut buffer);
+        assert_eq!(buffer.len(), 10);
+
+        // Go back to the 8th snapshot, i.e. after 8 frames
+        let restored = buffer.rewind(3).unwrap();
+        assert_eq!(buffer.len(), 7);
+        assert_eq!(savestate::encode(&restored).unwrap(), expected);
+    }
+}
@@ -0,0 +1,163 @@
+use std::io;
+use std::path::Path;
+
+use bincode::Options;
+use thiserror::Error;
+
+use crate::processor::Processor;
+
+/// Magic bytes at the start of every save state file.
+pub const MAGIC: &[u8; 8] = b"JADESAVE";
+
+/// Version of the save state format. Increase whenever the serialized layout of `Processor`
+/// changes, older save states are rejected then.
+pub const VERSION: u16 = 3;
+
+/// Upper limit for the size of the serialized state. Valid states are far smaller (the memory and
+/// the program are at most 64 KiB each), the limit stops corrupt length fields from allocating
+/// arbitrary amounts of memory.
+const MAX_STATE_SIZE: u64 = 1 << 20;
+
+/// Serialize the complete processor state into the versioned binary save state format. The
+/// format consists of the magic bytes, the format version (little endian) and the state.
+pub fn encode(processor: &Processor) -> Result<Vec<u8>, SaveStateError> {
+    let mut data = Vec::from(*MAGIC);
+    data.extend_from_slice(&VERSION.to_le_bytes());
+    bincode::serialize_into(&mut data, processor)?;
+    Ok(data)
+}
+
+/// Restore a processor from data created by [`encode`].
+pub fn decode(data: &[u8]) -> Result<Processor, SaveStateError> {
+    let header_size = MAGIC.len() + std::mem::size_of::<u16>();
+    if data.len() < header_size || &data[..MAGIC.len()] != MAGIC {
+        return Err(SaveStateError::InvalidHeader);
+    }
+    let version = u16::from_le_bytes([data[MAGIC.len()], data[MAGIC.len() + 1]]);
+    if version != VERSION {
+        return Err(SaveStateError::UnsupportedVersion(version));
+    }
+    let processor: Processor = bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(MAX_STATE_SIZE)
+        .deserialize(&data[header_size..])?;
+    processor
+        .check_consistency()
+        .map_err(SaveStateError::Inconsistent)?;
+    Ok(processor)
+}
+
+/// Write the processor state to a save state file.
+pub fn save(processor: &Processor, path: &Path) -> Result<(), SaveStateError> {
+    let data = encode(processor)?;
+    std::fs::write(path, data)?;
+    Ok(())
+}
+
+/// Read a processor state from a save state file.
+pub fn load(path: &Path) -> Result<Processor, SaveStateError> {
+    let data = std::fs::read(path)?;
+    decode(&data)
+}
+
+#[derive(Debug, Error)]
+pub enum SaveStateError {
+    #[error("cannot access save state file: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("not a Jade save state")]
+    InvalidHeader,
+
+    #[error("unsupported save state version {0} (expected {VERSION})")]
+    UnsupportedVersion(u16),
+
+    #[error("corrupt save state: {0}")]
+    Serialization(#[from] bincode::Error),
+
+    #[error("corrupt save state: {0}")]
+    Inconsistent(&'static str),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::{DISPLAY_HEIGHT, DISPLAY_WIDTH, MEMORY_SIZE};
+
+    #[test]
+    fn round_trip() {
+        let mut processor = Processor::new();
+        // V3 := 0x42, I := 0x123, call 0x208
+        let program = vec![0x63, 0x42, 0xa1, 0x23, 0x22, 0x08];
+        processor.load_program(program).unwrap();
+        for _ in 0..3 {
+            processor.step().unwrap();
+        }
+
+        let data = encode(&processor).unwrap();
+        let restored = decode(&data).unwrap();
+        assert_eq!(encode(&restored).unwrap(), data);
+
+        let mut data = data;
+        data[MAGIC.len()] = 0xff;
+        assert!(matches!(
+            decode(&data),
+            Err(SaveStateError::UnsupportedVersion(0x00ff))
+        ));
+        assert!(matches!(
+            decode(b"JADE"),
+            Err(SaveStateError::InvalidHeader)
+        ));
+    }
+
+    #[test]
+    fn corrupt_data() {
+        // I := 0x123
+        let mut processor = Processor::new();
+        processor.load_program(vec![0xa1, 0x23]).unwrap();
+        let data = encode(&processor).unwrap();
+
+        for length in [MAGIC.len() + 2, data.len() / 2, data.len() - 1] {
+            assert!(matches!(
+                decode(&data[..length]),
+                Err(SaveStateError::Serialization(_))
+            ));
+        }
+
+        // A huge length of the program data is rejected without allocating it
+        let mut huge = data.clone();
+        let program_length = MAGIC.len() + 2;
+        huge[program_length..program_length + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(
+            decode(&huge),
+            Err(SaveStateError::Serialization(_))
+        ));
+
+        // Memory of the wrong size
+        let memory_length = (MEMORY_SIZE as u64).to_le_bytes();
+        let position = data
+            .windows(8)
+            .position(|window| window == memory_length)
+            .unwrap();
+        let mut short = data.clone();
+        short[position..position + 8].copy_from_slice(&0x1000u64.to_le_bytes());
+        short.drain(position + 8 + 0x1000..position + 8 + MEMORY_SIZE);
+        assert!(matches!(
+            decode(&short),
+            Err(SaveStateError::Inconsistent(_))
+        ));
+
+        // A pixel outside of the two drawing planes
+        let display_length = (DISPLAY_WIDTH as u64 * DISPLAY_HEIGHT as u64).to_le_bytes();
+        let position = data
+            .windows(8)
+            .position(|window| window == display_length)
+            .unwrap();
+        let mut bad_pixel = data.clone();
+        bad_pixel[position + 8] = 0xff;
+        assert!(matches!(
+            decode(&bad_pixel),
+            Err(SaveStateError::Inconsistent(_))
+        ));
+    }
+}
@@ -3,12 +3,15 @@ use rodio::source::{Function, SignalGenerator, Source};
 use rodio::{OutputStream, OutputStreamHandle, PlayError, Sink, StreamError};
 use thiserror::Error;
 
+use crate::processor::{Audio, AUDIO_PATTERN_SIZE};
+
 pub struct Sound {
     #[allow(dead_code)]
     stream: OutputStream,
     #[allow(dead_code)]
     stream_handle: OutputStreamHandle,
     sink: Sink,
+    playing: Option<Audio>,
 }
 
 impl Sound {
@@ -20,22 +23,84 @@ impl Sound {
             stream,
             stream_handle,
             sink,
+            playing: None,
         })
     }
 
-    pub fn play(&self) {
-        let source = SignalGenerator::new(SampleRate(48000), 220.0, Function::Sawtooth)
-            .repeat_infinite()
-            .amplify(0.1)
-            .fade_in(std::time::Duration::from_millis(100));
-        self.sink.append(source);
+    /// Play the given audio pattern, or a 220 Hz sawtooth if no pattern is loaded. If the audio
+    /// changed since the last call, e.g. because the pitch was modified, playback restarts with
+    /// the new pattern.
+    pub fn play(&mut self, audio: Audio) {
+        if self.playing == Some(audio) {
+            return;
+        }
+        self.sink.clear();
+        if let Some(pattern) = audio.pattern {
+            let source = PatternSource::new(pattern, audio.sample_rate()).amplify(0.1);
+            self.sink.append(source);
+        } else {
+            let source = SignalGenerator::new(SampleRate(48000), 220.0, Function::Sawtooth)
+                .repeat_infinite()
+                .amplify(0.1)
+                .fade_in(std::time::Duration::from_millis(100));
+            self.sink.append(source);
+        }
         self.sink.play();
+        self.playing = Some(audio);
     }
 
-    pub fn pause(&self) {
+    pub fn pause(&mut self) {
         // This abrupt stop may lead to a popping noise. It is unclear if rodio currently supports
         // fading out an infinite source.
-        self.sink.clear();
+        if self.playing.take().is_some() {
+            self.sink.clear();
+        }
+    }
+}
+
+/// An infinite source looping over the 1-bit samples of an XO-CHIP audio pattern.
+struct PatternSource {
+    pattern: [u8; AUDIO_PATTERN_SIZE as usize],
+    sample_rate: u32,
+    position: usize,
+}
+
+impl PatternSource {
+    fn new(pattern: [u8; AUDIO_PATTERN_SIZE as usize], sample_rate: f32) -> Self {
+        PatternSource {
+            pattern,
+            sample_rate: (sample_rate.round() as u32).max(1),
+            position: 0,
+        }
+    }
+}
+
+impl Iterator for PatternSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let byte = self.pattern[self.position / 8];
+        let bit = byte >> (7 - self.position % 8) & 1;
+        self.position = (self.position + 1) % (AUDIO_PATTERN_SIZE as usize * 8);
+        Some(if bit == 1 { 1.0 } else { -1.0 })
+    }
+}
+
+impl Source for PatternSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<std::time::Duration> {
+        None
     }
 }
 
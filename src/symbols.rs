@@ -0,0 +1,246 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+/// Names of addresses, e.g. the labels of an assembled program, and optionally the source lines
+/// the instructions were assembled from.
+///
+/// As text, a symbol map consists of lines of the form `name = 0x0204` (or `:const name 0x204` as
+/// in Octo) and `source 0x0204 12 v0 := 1` for the source line 12 at address 0x204. `#` starts a
+/// comment.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SymbolMap {
+    symbols: BTreeMap<String, u16>,
+    source: BTreeMap<u16, SourceLine>,
+}
+
+/// The source code an instruction was assembled from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceLine {
+    /// Line number, starting at 1
+    pub line: usize,
+    /// The code of the line, without comments
+    pub text: String,
+}
+
+impl fmt::Display for SourceLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.text)
+    }
+}
+
+impl SymbolMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a symbol, replacing an existing one with the same name.
+    pub fn insert(&mut self, name: impl Into<String>, address: u16) {
+        self.symbols.insert(name.into(), address);
+    }
+
+    /// The address of the symbol `name`.
+    pub fn get(&self, name: &str) -> Option<u16> {
+        self.symbols.get(name).copied()
+    }
+
+    /// A name of `address`. If there are several, the alphabetically first one is returned.
+    pub fn name_at(&self, address: u16) -> Option<&str> {
+        self.symbols
+            .iter()
+            .find(|(_, &a)| a == address)
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// The symbol at or closest before `address`, with the offset of `address` from it. Of
+    /// several symbols at the same address, the alphabetically first one is returned.
+    pub fn locate(&self, address: u16) -> Option<(&str, u16)> {
+        self.symbols
+            .iter()
+            .rev()
+            .filter(|(_, &a)| a <= address)
+            .max_by_key(|(_, &a)| a)
+            .map(|(name, a)| (name.as_str(), address - a))
+    }
+
+    /// `address` relative to the closest symbol, e.g. `main+0x12`, or as plain hex number if
+    /// there is no symbol before it.
+    pub fn format_address(&self, address: u16) -> String {
+        match self.locate(address) {
+            Some((name, 0)) => name.to_string(),
+            Some((name, offset)) => format!("{}+{:#x}", name, offset),
+            None => format!("{:#06x}", address),
+        }
+    }
+
+    /// `address` relative to the closest symbol, followed by the source line of the instruction
+    /// at `address` if it is known, e.g. `main+0x12 (line 14: v0 += 1)`. None if neither is
+    /// known.
+    pub fn describe(&self, address: u16) -> Option<String> {
+        let source = self.source_line(address);
+        if self.locate(address).is_none() && source.is_none() {
+            return None;
+        }
+        let location = self.format_address(address);
+        Some(match source {
+            Some(source) => format!("{} ({})", location, source),
+            None => location,
+        })
+    }
+
+    /// Record that the instruction at `address` was assembled from `text` in line `line`.
+    pub fn insert_source_line(&mut self, address: u16, line: usize, text: impl Into<String>) {
+        let text = text.into();
+        let text = text
+            .split('#')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_string();
+        self.source.insert(address, SourceLine { line, text });
+    }
+
+    /// The source line of the instruction at `address`.
+    pub fn source_line(&self, address: u16) -> Option<&SourceLine> {
+        self.source.get(&address)
+    }
+
+    /// The names of the addresses, for labeling a disassembly. Of several symbols at the same
+    /// address, the alphabetically first one is used.
+    pub fn labels(&self) -> BTreeMap<u16, String> {
+        let mut labels = BTreeMap::new();
+        for (name, address) in self.iter() {
+            labels.entry(address).or_insert_with(|| name.to_string());
+        }
+        labels
+    }
+
+    /// All symbols ordered by name.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u16)> {
+        self.symbols
+            .iter()
+            .map(|(name, &address)| (name.as_str(), address))
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    /// Return true if there are neither symbols nor source lines.
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty() && self.source.is_empty()
+    }
+
+    pub fn parse(text: &str) -> Result<Self, SymbolMapError> {
+        let mut map = SymbolMap::new();
+        for (index, line) in text.lines().enumerate() {
+            let error = || SymbolMapError::Entry { line: index + 1 };
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(source) = line.strip_prefix("source ") {
+                let mut parts = source.trim_start().splitn(3, char::is_whitespace);
+                let address = parse_hex(parts.next().unwrap_or_default()).ok_or_else(error)?;
+                let number = parts
+                    .next()
+                    .and_then(|number| number.parse().ok())
+                    .ok_or_else(error)?;
+                map.insert_source_line(address, number, parts.next().unwrap_or_default());
+            } else if let Some(constant) = line.strip_prefix(":const ") {
+                let mut parts = constant.split_whitespace();
+                let (Some(name), Some(value), None) = (parts.next(), parts.next(), parts.next())
+                else {
+                    return Err(error());
+                };
+                let address = parse_hex(value)
+                    .or_else(|| value.parse().ok())
+                    .ok_or_else(error)?;
+                map.insert(name, address);
+            } else {
+                let (name, address) = line.split_once('=').ok_or_else(error)?;
+                let address = parse_hex(address.trim()).ok_or_else(error)?;
+                map.insert(name.trim(), address);
+            }
+        }
+        Ok(map)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, SymbolMapError> {
+        let text = std::fs::read_to_string(path).map_err(|e| SymbolMapError::Read {
+            path: path.to_path_buf(),
+            source: e,
+        })?;
+        Self::parse(&text)
+    }
+}
+
+/// Parse a hexadecimal number with `0x` prefix.
+fn parse_hex(text: &str) -> Option<u16> {
+    let digits = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_prefix("0X"))?;
+    u16::from_str_radix(digits, 16).ok()
+}
+
+impl fmt::Display for SymbolMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, address) in self.iter() {
+            writeln!(f, "{} = {:#06x}", name, address)?;
+        }
+        for (address, source) in &self.source {
+            writeln!(f, "source {:#06x} {} {}", address, source.line, source.text)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum SymbolMapError {
+    #[error("cannot read symbol file {path}: {source}")]
+    Read { path: PathBuf, source: io::Error },
+
+    #[error("invalid symbol map entry in line {line}")]
+    Entry { line: usize },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut map = SymbolMap::new();
+        map.insert("main", 0x200);
+        map.insert("sprite", 0x2a4);
+        let text = map.to_string();
+        assert_eq!(text, "main = 0x0200\nsprite = 0x02a4\n");
+        assert_eq!(SymbolMap::parse(&text).unwrap(), map);
+        assert_eq!(map.name_at(0x2a4), Some("sprite"));
+
+        assert!(matches!(
+            SymbolMap::parse("a = 1\n"),
+            Err(SymbolMapError::Entry { line: 1 })
+        ));
+    }
+
+    #[test]
+    fn source_lines_and_locations() {
+        let text = "# symbols\n:const main 0x200\nloop = 0x20a\nsource 0x020c 7 v0 += 1 # count\n";
+        let map = SymbolMap::parse(text).unwrap();
+        assert_eq!(map.get("main"), Some(0x200));
+        assert_eq!(map.format_address(0x200), "main");
+        assert_eq!(map.format_address(0x208), "main+0x8");
+        assert_eq!(map.format_address(0x1fe), "0x01fe");
+        assert_eq!(
+            map.describe(0x20c).as_deref(),
+            Some("loop+0x2 (line 7: v0 += 1)")
+        );
+        assert_eq!(map.describe(0x1fe), None);
+        assert_eq!(SymbolMap::parse(&map.to_string()).unwrap(), map);
+        assert!(SymbolMap::parse("source 0x20c x\n").is_err());
+    }
+}
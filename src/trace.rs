@@ -0,0 +1,566 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+use crate::disassembler::Syntax;
+use crate::headless::parse_address;
+use crate::opcode::Opcode;
+use crate::processor::{EmulatorError, Processor, Registers};
+use crate::symbols::SymbolMap;
+
+/// Start of a binary trace file, followed by a format version.
+const BINARY_MAGIC: &[u8; 4] = b"JTRC";
+const BINARY_VERSION: u8 = 1;
+
+/// Command line arguments for recording an execution trace
+#[derive(clap::Args, Debug, Default)]
+pub struct TraceArgs {
+    /// Record each executed instruction to this file
+    #[arg(long, value_name = "FILE")]
+    pub trace: Option<PathBuf>,
+
+    /// Format of the trace file
+    #[arg(long, value_enum, default_value_t)]
+    pub trace_format: TraceFormat,
+
+    /// Only record instructions in this address range, e.g. 0x200-0x2ff
+    #[arg(long, value_name = "RANGE", value_parser = AddressRange::parse)]
+    pub trace_range: Option<AddressRange>,
+
+    /// Only record instructions of these classes (comma separated)
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub trace_class: Vec<OpcodeClass>,
+}
+
+impl TraceArgs {
+    /// Create the tracer requested on the command line, if any.
+    pub fn tracer(&self) -> io::Result<Option<Tracer>> {
+        let Some(path) = &self.trace else {
+            return Ok(None);
+        };
+        let filter = TraceFilter {
+            range: self.trace_range,
+            classes: self.trace_class.clone(),
+        };
+        Tracer::create(path, self.trace_format, filter).map(Some)
+    }
+}
+
+/// Text traces have one line per instruction. Binary traces are more compact, they leave out the
+/// disassembly, which can be recovered from the opcode.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TraceFormat {
+    #[default]
+    Text,
+    Binary,
+}
+
+/// An inclusive range of addresses.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct AddressRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl AddressRange {
+    /// Parse `start-end` with both addresses in hexadecimal.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let (start, end) = text
+            .split_once('-')
+            .ok_or_else(|| format!("invalid address range '{}', expected start-end", text))?;
+        Ok(AddressRange {
+            start: parse_address(start.trim())?,
+            end: parse_address(end.trim())?,
+        })
+    }
+
+    pub fn contains(&self, address: u16) -> bool {
+        (self.start..=self.end).contains(&address)
+    }
+}
+
+/// Groups of instructions for filtering traces.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum OpcodeClass {
+    /// Jumps, calls, returns, skips and exit
+    Flow,
+    /// Arithmetic and logic on the V registers, random numbers
+    Arithmetic,
+    /// Index register, loads and stores
+    Memory,
+    /// Drawing, scrolling, resolution and planes
+    Display,
+    /// Delay and sound timer
+    Timer,
+    /// Keyboard
+    Input,
+    /// Audio pattern and pitch (XO-CHIP)
+    Audio,
+}
+
+impl OpcodeClass {
+    pub fn of(instruction: &Opcode) -> Self {
+        use Opcode::*;
+        match instruction {
+            SkipIfKey(_) | SkipIfNotKey(_) | WaitKey(_) => OpcodeClass::Input,
+            _ if instruction.is_skip() => OpcodeClass::Flow,
+            Return | Jump(_) | Call(_) | JumpWithOffset(_) | Exit => OpcodeClass::Flow,
+            SetRegister { .. }
+            | AddToRegister { .. }
+            | Assign { .. }
+            | Or { .. }
+            | And { .. }
+            | Xor { .. }
+            | Add { .. }
+            | Subtract { .. }
+            | ShiftRight { .. }
+            | SubtractReverse { .. }
+            | ShiftLeft { .. }
+            | Random { .. } => OpcodeClass::Arithmetic,
+            SetIndex(_)
+            | SetIndexLong(_)
+            | AddToIndex(_)
+            | Font(_)
+            | BigFont(_)
+            | Bcd(_)
+            | Store(_)
+            | Load(_)
+            | StoreFlags(_)
+            | LoadFlags(_)
+            | SaveRange { .. }
+            | LoadRange { .. } => OpcodeClass::Memory,
+            ClearScreen
+            | ScrollDown(_)
+            | ScrollUp(_)
+            | ScrollRight
+            | ScrollLeft
+            | LowResolution
+            | HighResolution
+            | Draw { .. }
+            | SelectPlanes(_) => OpcodeClass::Display,
+            GetDelay(_) | SetDelay(_) | SetSound(_) => OpcodeClass::Timer,
+            LoadAudio | SetPitch(_) => OpcodeClass::Audio,
+            // The remaining skips, classified above
+            _ => OpcodeClass::Flow,
+        }
+    }
+}
+
+/// Which instructions are recorded. An empty filter records everything.
+#[derive(Clone, Debug, Default)]
+pub struct TraceFilter {
+    pub range: Option<AddressRange>,
+    pub classes: Vec<OpcodeClass>,
+}
+
+impl TraceFilter {
+    pub fn matches(&self, record: &TraceRecord) -> bool {
+        let in_range = self.range.is_none_or(|r| r.contains(record.address));
+        let in_classes = self.classes.is_empty()
+            || record
+                .instruction()
+                .is_some_and(|i| self.classes.contains(&OpcodeClass::of(&i)));
+        in_range && in_classes
+    }
+}
+
+/// A register changed by an instruction, with its new value. SP is the depth of the stack.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    V(u8, u8),
+    I(u16),
+    PC(u16),
+    SP(u8),
+    DT(u8),
+    ST(u8),
+}
+
+impl Change {
+    /// Identifier and value in binary traces.
+    fn to_binary(self) -> (u8, u16) {
+        match self {
+            Change::V(x, value) => (x, value as u16),
+            Change::I(value) => (0x10, value),
+            Change::PC(value) => (0x11, value),
+            Change::SP(value) => (0x12, value as u16),
+            Change::DT(value) => (0x13, value as u16),
+            Change::ST(value) => (0x14, value as u16),
+        }
+    }
+
+    fn from_binary(id: u8, value: u16) -> Option<Self> {
+        let change = match id {
+            0x0..=0xF => Change::V(id, value as u8),
+            0x10 => Change::I(value),
+            0x11 => Change::PC(value),
+            0x12 => Change::SP(value as u8),
+            0x13 => Change::DT(value as u8),
+            0x14 => Change::ST(value as u8),
+            _ => return None,
+        };
+        Some(change)
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::V(x, value) => write!(f, "V{:X}={:02x}", x, value),
+            Change::I(value) => write!(f, "I={:04x}", value),
+            Change::PC(value) => write!(f, "PC={:04x}", value),
+            Change::SP(value) => write!(f, "SP={}", value),
+            Change::DT(value) => write!(f, "DT={:02x}", value),
+            Change::ST(value) => write!(f, "ST={:02x}", value),
+        }
+    }
+}
+
+/// An executed instruction: its address, its opcode (2 or 4 bytes) and the registers it changed.
+/// The program counter is only included if execution does not continue with the next
+/// instruction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub address: u16,
+    pub code: Vec<u8>,
+    pub changes: Vec<Change>,
+}
+
+impl TraceRecord {
+    /// Create a record from the registers before and after executing `code`, the memory at the
+    /// program counter (at least the opcode).
+    pub fn new(code: &[u8], before: &Registers, after: &Registers) -> Self {
+        let size = Opcode::decode_bytes(code).map_or(2, |i| i.size()) as usize;
+        let code = code[..size.min(code.len())].to_vec();
+        let address = before.program_counter;
+
+        let mut changes = Vec::new();
+        for (x, (old, new)) in before
+            .variable_registers
+            .iter()
+            .zip(after.variable_registers)
+            .enumerate()
+        {
+            if *old != new {
+                changes.push(Change::V(x as u8, new));
+            }
+        }
+        if before.index_register != after.index_register {
+            changes.push(Change::I(after.index_register));
+        }
+        if after.program_counter != address.wrapping_add(code.len() as u16) {
+            changes.push(Change::PC(after.program_counter));
+        }
+        if before.stack.len() != after.stack.len() {
+            changes.push(Change::SP(after.stack.len() as u8));
+        }
+        if before.delay_timer != after.delay_timer {
+            changes.push(Change::DT(after.delay_timer));
+        }
+        if before.sound_timer != after.sound_timer {
+            changes.push(Change::ST(after.sound_timer));
+        }
+
+        TraceRecord {
+            address,
+            code,
+            changes,
+        }
+    }
+
+    pub fn instruction(&self) -> Option<Opcode> {
+        Opcode::decode_bytes(&self.code).ok()
+    }
+
+    /// Record format: address (u16), code length (u8), code, number of changes (u8), changes
+    /// (identifier u8, value u16). All numbers are little endian.
+    pub fn write_binary(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(&self.address.to_le_bytes())?;
+        writer.write_all(&[self.code.len() as u8])?;
+        writer.write_all(&self.code)?;
+        writer.write_all(&[self.changes.len() as u8])?;
+        for change in &self.changes {
+            let (id, value) = change.to_binary();
+            writer.write_all(&[id])?;
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Read the next record of a binary trace. Returns `None` at the end of the trace.
+    pub fn read_binary(reader: &mut impl Read) -> io::Result<Option<Self>> {
+        let mut address = [0; 2];
+        match reader.read_exact(&mut address) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        }
+        let mut code = vec![0; read_u8(reader)? as usize];
+        reader.read_exact(&mut code)?;
+        let mut changes = Vec::new();
+        for _ in 0..read_u8(reader)? {
+            let id = read_u8(reader)?;
+            let mut value = [0; 2];
+            reader.read_exact(&mut value)?;
+            let change = Change::from_binary(id, u16::from_le_bytes(value))
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid register"))?;
+            changes.push(change);
+        }
+        Ok(Some(TraceRecord {
+            address: u16::from_le_bytes(address),
+            code,
+            changes,
+        }))
+    }
+}
+
+/// Text format: address, opcode, disassembly (Octo syntax) and changed registers.
+impl TraceRecord {
+    /// Format the record as line of a text trace, with `labels` used for addresses in the
+    /// disassembly.
+    pub fn format(&self, labels: &BTreeMap<u16, String>) -> String {
+        let opcode: String = self.code.iter().map(|b| format!("{:02x}", b)).collect();
+        let disassembly = match self.instruction() {
+            Some(instruction) => instruction.format(Syntax::Octo, labels),
+            None => "???".to_string(),
+        };
+        let changes: Vec<String> = self.changes.iter().map(Change::to_string).collect();
+        let line = format!(
+            "{:04x}  {:<8}  {:<24}  {}",
+            self.address,
+            opcode,
+            disassembly,
+            changes.join(" ")
+        );
+        line.trim_end().to_string()
+    }
+}
+
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.format(&BTreeMap::new()))
+    }
+}
+
+fn read_u8(reader: &mut impl Read) -> io::Result<u8> {
+    let mut byte = [0];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+/// Check the header of a binary trace.
+pub fn read_binary_header(reader: &mut impl Read) -> io::Result<()> {
+    let mut header = [0; 5];
+    reader.read_exact(&mut header)?;
+    if &header[..4] != BINARY_MAGIC || header[4] != BINARY_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a Jade trace file",
+        ));
+    }
+    Ok(())
+}
+
+/// Writes the records passing the filter to a trace file.
+pub struct Tracer {
+    writer: Box<dyn Write + Send>,
+    format: TraceFormat,
+    filter: TraceFilter,
+    symbols: SymbolMap,
+    labels: BTreeMap<u16, String>,
+}
+
+impl Tracer {
+    pub fn new(
+        mut writer: Box<dyn Write + Send>,
+        format: TraceFormat,
+        filter: TraceFilter,
+    ) -> io::Result<Self> {
+        if format == TraceFormat::Binary {
+            writer.write_all(BINARY_MAGIC)?;
+            writer.write_all(&[BINARY_VERSION])?;
+        }
+        Ok(Tracer {
+            writer,
+            format,
+            filter,
+            symbols: SymbolMap::new(),
+            labels: BTreeMap::new(),
+        })
+    }
+
+    /// Use `symbols` in text traces: addresses in the disassembly are shown as labels and each
+    /// line ends with the location of the instruction and its source line, e.g.
+    /// `# main+0x4 (line 3: v0 := 5)`.
+    pub fn set_symbols(&mut self, symbols: SymbolMap) {
+        self.labels = symbols.labels();
+        self.symbols = symbols;
+    }
+
+    /// Create a trace file, replacing an existing one.
+    pub fn create(path: &Path, format: TraceFormat, filter: TraceFilter) -> io::Result<Self> {
+        Self::new(
+            Box::new(BufWriter::new(File::create(path)?)),
+            format,
+            filter,
+        )
+    }
+
+    pub fn record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        if !self.filter.matches(record) {
+            return Ok(());
+        }
+        match self.format {
+            TraceFormat::Text => match self.symbols.describe(record.address) {
+                Some(location) => writeln!(
+                    self.writer,
+                    "{:<56}  # {}",
+                    record.format(&self.labels),
+                    location
+                ),
+                None => writeln!(self.writer, "{}", record.format(&self.labels)),
+            },
+            TraceFormat::Binary => record.write_binary(&mut self.writer),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tracer")
+            .field("format", &self.format)
+            .field("filter", &self.filter)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Execute one instruction like `Processor::step`, recording it if there is a tracer. Waiting for
+/// a key is only recorded once a key was pressed. An instruction which fails is recorded before
+/// the error is returned. If writing fails, the tracer is dropped.
+pub fn traced_step(
+    processor: &mut Processor,
+    tracer: &mut Option<Tracer>,
+) -> Result<(), EmulatorError> {
+    let Some(active) = tracer else {
+        return processor.step();
+    };
+    if processor.has_exited() || processor.is_waiting_for_vblank() {
+        return processor.step();
+    }
+
+    let before = processor.registers();
+    let code = processor.read_memory(before.program_counter, 4);
+    let result = processor.step();
+    if result.is_ok() && processor.is_blocking() {
+        return Ok(());
+    }
+    let record = TraceRecord::new(&code, &before, &processor.registers());
+    if let Err(e) = active.record(&record) {
+        warn!("cannot write trace, stopped tracing: {}", e);
+        *tracer = None;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    /// A writer whose output can be inspected after it was handed to a tracer.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(data)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn trace(program: Vec<u8>, steps: usize, format: TraceFormat, filter: TraceFilter) -> Vec<u8> {
+        let buffer = SharedBuffer::default();
+        let mut tracer = Some(Tracer::new(Box::new(buffer.clone()), format, filter).unwrap());
+        let mut processor = Processor::new();
+        processor.load_program(program).unwrap();
+        for _ in 0..steps {
+            traced_step(&mut processor, &mut tracer).unwrap();
+        }
+        let data = buffer.0.lock().unwrap().clone();
+        data
+    }
+
+    // v0 := 5; i := 0x300; call 0x208; 0x206: jump 0x206; 0x208: v0 += 1; return
+    const PROGRAM: [u8; 12] = [
+        0x60, 0x05, 0xa3, 0x00, 0x22, 0x08, 0x12, 0x06, 0x70, 0x01, 0x00, 0xee,
+    ];
+
+    #[test]
+    fn text_trace() {
+        let text = trace(
+            PROGRAM.to_vec(),
+            5,
+            TraceFormat::Text,
+            TraceFilter::default(),
+        );
+        let lines: Vec<&str> = std::str::from_utf8(&text).unwrap().lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(
+            lines[0].split_whitespace().collect::<Vec<_>>(),
+            ["0200", "6005", "v0", ":=", "0x05", "V0=05"]
+        );
+        assert!(lines[1].ends_with("I=0300"));
+        assert!(lines[2].ends_with("PC=0208 SP=1"));
+        assert!(lines[4].ends_with("PC=0206 SP=0"));
+    }
+
+    #[test]
+    fn binary_trace_with_filter() {
+        let filter = TraceFilter {
+            range: Some(AddressRange::parse("0x200-0x207").unwrap()),
+            classes: vec![OpcodeClass::Flow, OpcodeClass::Memory],
+        };
+        let data = trace(PROGRAM.to_vec(), 6, TraceFormat::Binary, filter);
+        let mut reader = &data[..];
+        read_binary_header(&mut reader).unwrap();
+        let mut records = Vec::new();
+        while let Some(record) = TraceRecord::read_binary(&mut reader).unwrap() {
+            records.push(record);
+        }
+        let addresses: Vec<u16> = records.iter().map(|r| r.address).collect();
+        assert_eq!(addresses, [0x202, 0x204, 0x206]);
+        assert_eq!(records[0].changes, [Change::I(0x300)]);
+        assert_eq!(records[1].code, [0x22, 0x08]);
+    }
+
+    #[test]
+    fn failing_instruction() {
+        let buffer = SharedBuffer::default();
+        let format = TraceFormat::Text;
+        let filter = TraceFilter::default();
+        let mut tracer = Some(Tracer::new(Box::new(buffer.clone()), format, filter).unwrap());
+        let mut processor = Processor::new();
+        // v0 := 5, then an unknown instruction
+        processor
+            .load_program(vec![0x60, 0x05, 0xff, 0xff])
+            .unwrap();
+        traced_step(&mut processor, &mut tracer).unwrap();
+        assert!(traced_step(&mut processor, &mut tracer).is_err());
+        tracer.as_mut().unwrap().flush().unwrap();
+
+        let text = buffer.0.lock().unwrap().clone();
+        let lines: Vec<&str> = std::str::from_utf8(&text).unwrap().lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].starts_with("0202  ffff"));
+    }
+}
@@ -0,0 +1,339 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use log::warn;
+use thiserror::Error;
+
+use crate::application::{
+    load_program_with_symbols, load_settings, Args, ProgramFileError, SettingsFileError,
+};
+use crate::breakpoint::Register;
+use crate::headless::{parse_address, Runner};
+use crate::processor::EmulatorError;
+use crate::trace::TraceRecord;
+
+/// Command line arguments for comparing Jade with a reference trace
+#[derive(clap::Args, Debug)]
+pub struct TraceDiffArgs {
+    /// CHIP-8 ROM or Octo source file to run
+    #[arg(value_name = "ROM_FILE")]
+    program_file: PathBuf,
+
+    /// Reference trace, a CSV file with a header row naming the registers (PC, I, V0 to VF, DT,
+    /// ST) and one row with their values (hexadecimal) before each instruction
+    #[arg(value_name = "CSV_FILE")]
+    reference: PathBuf,
+
+    /// Number of instructions to compare
+    #[arg(short = 'n', long, default_value_t = 1000)]
+    instructions: usize,
+
+    /// Number of instructions to show before a divergence
+    #[arg(long, default_value_t = 5)]
+    context: usize,
+}
+
+/// Run the ROM headlessly and compare it step by step with the reference trace. A divergence is
+/// reported on stdout and results in an error.
+pub fn run(args: &Args, diff_args: &TraceDiffArgs) -> Result<(), TraceDiffError> {
+    let settings = load_settings(args.settings_file_path())?;
+    let program = load_program_with_symbols(&diff_args.program_file, args.symbols_path())?;
+    let reference = ReferenceTrace::parse(&std::fs::read_to_string(&diff_args.reference)?)?;
+
+    let mut runner = Runner::new(
+        settings.instruction_settings(),
+        settings.instructions_per_second(),
+    );
+    runner.processor_mut().set_seed(args.seed(&settings));
+    runner.load_program(program.rom)?;
+    runner.set_symbols(program.symbols);
+
+    let instructions = diff_args.instructions.min(reference.rows.len());
+    match compare(&mut runner, &reference, instructions, diff_args.context) {
+        Some(divergence) => {
+            print!("{}", divergence);
+            Err(TraceDiffError::Diverged {
+                instruction: divergence.instruction,
+            })
+        }
+        None => {
+            println!("No divergence in {} instructions", instructions);
+            Ok(())
+        }
+    }
+}
+
+/// A trace of another emulator. Columns which do not name a register are ignored.
+#[derive(Clone, Debug)]
+pub struct ReferenceTrace {
+    header: String,
+    registers: Vec<Register>,
+    rows: Vec<ReferenceRow>,
+}
+
+#[derive(Clone, Debug)]
+struct ReferenceRow {
+    line: usize,
+    text: String,
+    values: Vec<u16>,
+}
+
+impl ReferenceTrace {
+    /// Parse CSV text. Empty lines and lines starting with `#` are skipped.
+    pub fn parse(text: &str) -> Result<Self, TraceDiffError> {
+        let mut lines = text
+            .lines()
+            .enumerate()
+            .map(|(index, line)| (index + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'));
+
+        let (header_line, header) = lines.next().ok_or(TraceDiffError::Reference {
+            line: 1,
+            message: "header row missing".to_string(),
+        })?;
+        let mut columns = Vec::new();
+        let mut registers = Vec::new();
+        for (column, name) in header.split(',').map(str::trim).enumerate() {
+            match Register::parse(name) {
+                Ok(register) => {
+                    columns.push(column);
+                    registers.push(register);
+                }
+                Err(_) => warn!("ignoring column '{}' of the reference trace", name),
+            }
+        }
+        if registers.is_empty() {
+            return Err(TraceDiffError::Reference {
+                line: header_line,
+                message: format!("no register columns in header '{}'", header),
+            });
+        }
+
+        let mut rows = Vec::new();
+        for (line, text) in lines {
+            let fields: Vec<&str> = text.split(',').map(str::trim).collect();
+            let values = columns
+                .iter()
+                .map(|&column| {
+                    let field = fields
+                        .get(column)
+                        .ok_or_else(|| TraceDiffError::Reference {
+                            line,
+                            message: format!("column {} missing", column + 1),
+                        })?;
+                    parse_address(field)
+                        .map_err(|message| TraceDiffError::Reference { line, message })
+                })
+                .collect::<Result<_, _>>()?;
+            rows.push(ReferenceRow {
+                line,
+                text: text.to_string(),
+                values,
+            });
+        }
+
+        Ok(ReferenceTrace {
+            header: header.to_string(),
+            registers,
+            rows,
+        })
+    }
+}
+
+/// The first instruction before which Jade and the reference differ, or which Jade failed to
+/// execute.
+#[derive(Clone, Debug)]
+pub struct Divergence {
+    /// Number of instructions executed before the divergence
+    pub instruction: usize,
+    /// Registers which differ, with the expected and the actual value
+    pub differences: Vec<(Register, u16, u16)>,
+    /// The error of the failed instruction
+    pub error: Option<String>,
+    /// The last instructions executed by Jade, including the failed one
+    recent: Vec<TraceRecord>,
+    /// The reference header and rows up to the divergence
+    header: String,
+    reference: Vec<ReferenceRow>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(error) = &self.error {
+            writeln!(
+                f,
+                "Jade failed at instruction {}: {}",
+                self.instruction, error
+            )?;
+        } else {
+            writeln!(f, "Divergence before instruction {}", self.instruction)?;
+            writeln!(f, "  register  expected  actual")?;
+            for (register, expected, actual) in &self.differences {
+                writeln!(
+                    f,
+                    "  {:<8}  {:<#8x}  {:#x}",
+                    register.to_string(),
+                    expected,
+                    actual
+                )?;
+            }
+        }
+        writeln!(f, "Last instructions of Jade:")?;
+        for record in &self.recent {
+            writeln!(f, "  {}", record)?;
+        }
+        writeln!(f, "Reference:")?;
+        writeln!(f, "  {:>6}  {}", "line", self.header)?;
+        for row in &self.reference {
+            writeln!(f, "  {:>6}  {}", row.line, row.text)?;
+        }
+        Ok(())
+    }
+}
+
+/// Compare the registers before each of the first `instructions` instructions with the reference.
+/// `context` is the number of preceding instructions included in a divergence. An instruction
+/// which Jade fails to execute is a divergence, too.
+pub fn compare(
+    runner: &mut Runner,
+    reference: &ReferenceTrace,
+    instructions: usize,
+    context: usize,
+) -> Option<Divergence> {
+    let mut recent = VecDeque::with_capacity(context + 1);
+    for (instruction, row) in reference.rows.iter().enumerate().take(instructions) {
+        let registers = runner.processor().registers();
+        let differences: Vec<_> = reference
+            .registers
+            .iter()
+            .zip(&row.values)
+            .map(|(register, &expected)| (*register, expected, register.value(&registers)))
+            .filter(|(_, expected, actual)| expected != actual)
+            .collect();
+        if !differences.is_empty() {
+            let first_row = instruction.saturating_sub(context);
+            return Some(Divergence {
+                instruction,
+                differences,
+                error: None,
+                recent: recent.into(),
+                header: reference.header.clone(),
+                reference: reference.rows[first_row..=instruction].to_vec(),
+            });
+        }
+
+        let code = runner.processor().read_memory(registers.program_counter, 4);
+        let result = runner.step();
+        recent.push_back(TraceRecord::new(
+            &code,
+            &registers,
+            &runner.processor().registers(),
+        ));
+        if let Err(e) = result {
+            let first_row = instruction.saturating_sub(context);
+            return Some(Divergence {
+                instruction,
+                differences: Vec::new(),
+                error: Some(e.to_string()),
+                recent: recent.into(),
+                header: reference.header.clone(),
+                reference: reference.rows[first_row..=instruction].to_vec(),
+            });
+        }
+        if recent.len() > context {
+            recent.pop_front();
+        }
+    }
+    None
+}
+
+#[derive(Error, Debug)]
+pub enum TraceDiffError {
+    #[error(transparent)]
+    Settings(#[from] SettingsFileError),
+
+    #[error(transparent)]
+    ProgramFile(#[from] ProgramFileError),
+
+    #[error("cannot read reference trace: {0}")]
+    Read(#[from] io::Error),
+
+    #[error("reference trace, line {line}: {message}")]
+    Reference { line: usize, message: String },
+
+    #[error(transparent)]
+    Emulator(#[from] EmulatorError),
+
+    #[error("traces diverge before instruction {instruction}")]
+    Diverged { instruction: usize },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::InstructionSettings;
+
+    // v0 := 5; i := 0x300; v0 += 1; jump 0x206
+    const PROGRAM: [u8; 8] = [0x60, 0x05, 0xa3, 0x00, 0x70, 0x01, 0x12, 0x06];
+
+    fn runner() -> Runner {
+        let mut runner = Runner::new(InstructionSettings::default(), 700);
+        runner.load_program(PROGRAM.to_vec()).unwrap();
+        runner
+    }
+
+    #[test]
+    fn matching_trace() {
+        let csv = "cycle,PC,I,V0\n\
+                   0,0x200,0,0\n\
+                   1,202,0,5\n\
+                   2,204,300,5\n\
+                   3,206,300,6\n\
+                   4,206,300,6\n";
+        let reference = ReferenceTrace::parse(csv).unwrap();
+        assert_eq!(
+            reference.registers,
+            [Register::PC, Register::I, Register::V(0)]
+        );
+        assert!(compare(&mut runner(), &reference, 10, 2).is_none());
+    }
+
+    #[test]
+    fn first_divergence() {
+        let csv = "PC,I,V0\n200,0,0\n202,0,5\n204,302,5\n206,302,6\n";
+        let reference = ReferenceTrace::parse(csv).unwrap();
+        let divergence = compare(&mut runner(), &reference, 10, 1).unwrap();
+        assert_eq!(divergence.instruction, 2);
+        assert_eq!(divergence.differences, [(Register::I, 0x302, 0x300)]);
+        assert_eq!(divergence.recent.len(), 1);
+        assert_eq!(divergence.recent[0].address, 0x202);
+        let report = divergence.to_string();
+        assert!(report.contains("I         0x302     0x300"));
+        assert!(report.contains("     4  204,302,5"));
+
+        let error = ReferenceTrace::parse("PC,V0\n200\n").unwrap_err();
+        assert!(matches!(error, TraceDiffError::Reference { line: 2, .. }));
+        // Without register columns, nothing would be compared
+        let error = ReferenceTrace::parse("PC;I;V0\n200;300;5\n").unwrap_err();
+        assert!(matches!(error, TraceDiffError::Reference { line: 1, .. }));
+    }
+
+    #[test]
+    fn failing_instruction() {
+        let mut runner = Runner::new(InstructionSettings::default(), 700);
+        // v0 := 5, then an unknown instruction
+        runner.load_program(vec![0x60, 0x05, 0xff, 0xff]).unwrap();
+        let csv = "PC,V0\n200,0\n202,5\n204,5\n";
+        let reference = ReferenceTrace::parse(csv).unwrap();
+        let divergence = compare(&mut runner, &reference, 10, 1).unwrap();
+        assert_eq!(divergence.instruction, 1);
+        assert!(divergence.error.is_some());
+        let addresses: Vec<u16> = divergence.recent.iter().map(|r| r.address).collect();
+        assert_eq!(addresses, [0x200, 0x202]);
+        assert!(divergence
+            .to_string()
+            .starts_with("Jade failed at instruction 1: "));
+    }
+}
@@ -0,0 +1,149 @@
+//! Golden-image regression tests: the ROMs listed in `tests/golden/cases.toml` are run headlessly
+//! and the display is compared with a checked-in image. Set `JADE_BLESS=1` to (re)create the
+//! golden images instead.
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use jade::application::load_program;
+use jade::headless::{display_to_ascii, write_png, KeyScript, Runner};
+use jade::processor::{Display, InstructionSettings, Platform};
+
+#[derive(Deserialize)]
+struct Cases {
+    case: Vec<Case>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Case {
+    name: String,
+    rom: PathBuf,
+    frames: usize,
+    platform: Option<Platform>,
+    keys: Option<PathBuf>,
+    /// Golden image in `images/`, by default `<name>.txt`
+    golden: Option<PathBuf>,
+    #[serde(default)]
+    instructions_per_second: usize,
+}
+
+impl Case {
+    fn golden_path(&self) -> PathBuf {
+        let file = match &self.golden {
+            Some(file) => file.clone(),
+            None => PathBuf::from(format!("{}.txt", self.name)),
+        };
+        golden_dir().join("images").join(file)
+    }
+
+    fn run(&self) -> Display {
+        let rom = load_program(&golden_dir().join(&self.rom))
+            .unwrap_or_else(|e| panic!("{}: {}", self.name, e))
+            .rom;
+        let key_script = match &self.keys {
+            Some(path) => {
+                let text = std::fs::read_to_string(golden_dir().join(path)).unwrap();
+                KeyScript::parse(&text).unwrap_or_else(|e| panic!("{}: {}", self.name, e))
+            }
+            None => KeyScript::default(),
+        };
+        let settings = self.platform.map_or_else(
+            InstructionSettings::default,
+            InstructionSettings::from_platform,
+        );
+
+        let mut runner = Runner::new(settings, self.instructions_per_second);
+        runner.processor_mut().set_seed(0);
+        runner.load_program(rom).unwrap();
+        while runner.frame() < self.frames {
+            if let Some(keys) = key_script.keys_at(runner.frame()) {
+                runner.processor_mut().handle_keys(keys.clone());
+            }
+            let stopped = runner
+                .run_frame(None)
+                .unwrap_or_else(|e| panic!("{}: {}", self.name, e));
+            if stopped.is_some() {
+                break;
+            }
+        }
+        runner.processor().display()
+    }
+}
+
+fn golden_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden")
+}
+
+fn is_png(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("png"))
+}
+
+/// Decode a PNG file into its dimensions and pixel data.
+fn read_png(path: &Path) -> (u32, u32, Vec<u8>) {
+    let decoder = png::Decoder::new(std::io::BufReader::new(File::open(path).unwrap()));
+    let mut reader = decoder.read_info().unwrap();
+    let mut data = vec![0; reader.output_buffer_size().unwrap()];
+    let info = reader.next_frame(&mut data).unwrap();
+    data.truncate(info.buffer_size());
+    (info.width, info.height, data)
+}
+
+/// Write the display to `path`, as PNG or as text depending on the extension.
+fn write_image(display: &Display, path: &Path) {
+    if is_png(path) {
+        write_png(display, path).unwrap();
+    } else {
+        std::fs::write(path, display_to_ascii(display)).unwrap();
+    }
+}
+
+/// Compare the display with the golden image. On a mismatch, the actual image is written next to
+/// the build artifacts and its path is returned.
+fn compare(case: &Case, display: &Display) -> Result<(), PathBuf> {
+    let golden = case.golden_path();
+    let extension = golden.extension().unwrap_or_default().to_string_lossy();
+    let actual =
+        Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("{}.actual.{}", case.name, extension));
+    write_image(display, &actual);
+    let matches = if is_png(&golden) {
+        read_png(&golden) == read_png(&actual)
+    } else {
+        std::fs::read_to_string(&golden).unwrap_or_default() == display_to_ascii(display)
+    };
+    if matches {
+        Ok(())
+    } else {
+        Err(actual)
+    }
+}
+
+#[test]
+fn golden_images() {
+    let text = std::fs::read_to_string(golden_dir().join("cases.toml")).unwrap();
+    let cases: Cases = toml::from_str(&text).unwrap();
+    let bless = std::env::var_os("JADE_BLESS").is_some_and(|value| value != "0");
+
+    let mut failures = Vec::new();
+    for case in &cases.case {
+        let display = case.run();
+        if bless {
+            write_image(&display, &case.golden_path());
+        } else if let Err(actual) = compare(case, &display) {
+            failures.push(format!(
+                "{}: display differs from {}, actual image in {}",
+                case.name,
+                case.golden_path().display(),
+                actual.display()
+            ));
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "golden image mismatch (rerun with JADE_BLESS=1 to update intended changes):\n{}",
+        failures.join("\n")
+    );
+}
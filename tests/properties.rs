@@ -0,0 +1,181 @@
+//! Property tests for the processor: random ROMs are executed from random states on all
+//! platforms. Whatever the program does, the processor must report problems as errors instead of
+//! panicking, and stay in a consistent state.
+
+use std::collections::HashSet;
+
+use proptest::prelude::*;
+
+use jade::processor::{
+    EmulatorError, ExecutionError, InstructionSettings, Key, Platform, Processor, Registers,
+    MEMORY_SIZE, NUM_VARIABLE_REGISTERS, ROM_START_ADDR,
+};
+
+/// Number of instructions executed per case.
+const STEPS: usize = 64;
+
+/// Execute a timer tick after this many instructions, so that programs waiting for the vertical
+/// blank continue.
+const STEPS_PER_FRAME: usize = 8;
+
+fn platform() -> impl Strategy<Value = Platform> {
+    prop_oneof![
+        Just(Platform::CosmacVip),
+        Just(Platform::Chip48),
+        Just(Platform::SchipLegacy),
+        Just(Platform::SchipModern),
+        Just(Platform::XoChip),
+    ]
+}
+
+/// Addresses anywhere in memory, with a bias towards the end of memory.
+fn address() -> impl Strategy<Value = u16> {
+    prop_oneof![any::<u16>(), 0xffc0..=u16::MAX]
+}
+
+fn registers() -> impl Strategy<Value = Registers> {
+    (
+        any::<[u8; NUM_VARIABLE_REGISTERS as usize]>(),
+        address(),
+        address(),
+        prop::collection::vec(address(), 0..16),
+        any::<u8>(),
+        any::<u8>(),
+    )
+        .prop_map(
+            |(variable_registers, index_register, program_counter, stack, delay, sound)| {
+                Registers {
+                    variable_registers,
+                    index_register,
+                    program_counter,
+                    stack,
+                    delay_timer: delay,
+                    sound_timer: sound,
+                }
+            },
+        )
+}
+
+fn keys() -> impl Strategy<Value = HashSet<Key>> {
+    prop::collection::hash_set(0..16u8, 0..4).prop_map(|keys| {
+        keys.into_iter()
+            .map(|key| Key::try_from(key).expect("keys are below 16"))
+            .collect()
+    })
+}
+
+/// A processor with `rom` loaded, in the state given by `registers`, with `code` at the program
+/// counter.
+fn processor(
+    platform: Platform,
+    rom: &[u8],
+    registers: &Registers,
+    code: &[u8],
+    keys: &HashSet<Key>,
+) -> Processor {
+    let mut processor = Processor::new();
+    processor.load_settings(InstructionSettings::from_platform(platform));
+    processor.set_seed(0);
+    processor.load_program(rom.to_vec()).unwrap();
+    processor.load_registers(registers);
+    processor.write_memory(registers.program_counter, code);
+    processor.handle_keys(keys.clone());
+    processor
+}
+
+/// Run the processor and record the outcome of each step, checking the display after each one.
+fn run(processor: &mut Processor) -> Vec<Result<(), String>> {
+    let mut results = Vec::with_capacity(STEPS);
+    for step in 1..=STEPS {
+        results.push(processor.step().map_err(|e| e.to_string()));
+        if step % STEPS_PER_FRAME == 0 {
+            processor.handle_timer_tick();
+        }
+
+        let display = processor.display();
+        assert_eq!(
+            display.content.len(),
+            display.width as usize * display.height as usize
+        );
+        assert!(display.content.iter().all(|&pixel| pixel <= 0b11));
+    }
+    results
+}
+
+proptest! {
+    #[test]
+    fn random_programs_do_not_panic(
+        platform in platform(),
+        rom in prop::collection::vec(any::<u8>(), 0..256),
+        registers in registers(),
+        code in prop::collection::vec(any::<u8>(), 0..64),
+        keys in keys(),
+    ) {
+        let mut first = processor(platform, &rom, &registers, &code, &keys);
+        let results = run(&mut first);
+
+        // Execution is deterministic
+        let mut second = processor(platform, &rom, &registers, &code, &keys);
+        prop_assert_eq!(run(&mut second), results.clone());
+        prop_assert_eq!(second.registers(), first.registers());
+        prop_assert_eq!(second.display().content, first.display().content);
+
+        // The decode cache does not change the results, even for self-modifying code
+        let mut uncached = processor(platform, &rom, &registers, &code, &keys);
+        uncached.set_decode_cache(false);
+        prop_assert_eq!(run(&mut uncached), results);
+        prop_assert_eq!(uncached.registers(), first.registers());
+        prop_assert_eq!(uncached.display().content, first.display().content);
+    }
+}
+
+/// An instruction accessing memory at the index register, with the number of bytes it accesses.
+fn memory_access() -> impl Strategy<Value = (u16, usize)> {
+    prop_oneof![
+        (0..16u16).prop_map(|x| (0xf033 | x << 8, 3)),
+        (0..16u16).prop_map(|x| (0xf055 | x << 8, x as usize + 1)),
+        (0..16u16).prop_map(|x| (0xf065 | x << 8, x as usize + 1)),
+        Just((0xf002, 16)),
+        (1..16u16).prop_map(|n| (0xd000 | n, n as usize)),
+        (0..16u16, 0..16u16)
+            .prop_map(|(x, y)| { (0x5002 | x << 8 | y << 4, x.abs_diff(y) as usize + 1) }),
+        (0..16u16, 0..16u16)
+            .prop_map(|(x, y)| { (0x5003 | x << 8 | y << 4, x.abs_diff(y) as usize + 1) }),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn accesses_past_the_end_of_memory_are_errors(
+        (instruction, length) in memory_access(),
+        index_register in 0xffc0..=u16::MAX,
+    ) {
+        let registers = Registers {
+            index_register,
+            program_counter: ROM_START_ADDR,
+            ..Default::default()
+        };
+        let code = instruction.to_be_bytes();
+        let mut processor = processor(Platform::XoChip, &[], &registers, &code, &HashSet::new());
+        let result = processor.step();
+
+        if index_register as usize + length > MEMORY_SIZE {
+            let is_out_of_bounds = matches!(
+                result,
+                Err(EmulatorError::Execution {
+                    source: ExecutionError::MemoryAccessOutOfBounds(address),
+                    ..
+                }) if address == MEMORY_SIZE
+            );
+            prop_assert!(is_out_of_bounds, "unexpected result {:?}", result);
+        } else {
+            prop_assert!(result.is_ok(), "unexpected result {:?}", result);
+        }
+    }
+}
+
+#[test]
+fn oversized_roms_are_rejected() {
+    let rom = vec![0; MEMORY_SIZE - ROM_START_ADDR as usize + 1];
+    assert!(Processor::new().load_program(rom).is_err());
+}